@@ -0,0 +1,4 @@
+// Re-embed the SQLite migrations whenever they change.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,6 @@
+-- Matches the schema SqliteUserRepo used to create inline, so existing databases adopt it as-is.
+CREATE TABLE IF NOT EXISTS users (
+    id       TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    email    TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Optional password credentials: an Argon2id PHC string, NULL for users without a password.
+ALTER TABLE users ADD COLUMN password_hash TEXT;
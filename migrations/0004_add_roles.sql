@@ -0,0 +1,2 @@
+-- Roles as a sorted JSON array of role names, e.g. '["admin"]'; ordinary users have none.
+ALTER TABLE users ADD COLUMN roles TEXT NOT NULL DEFAULT '[]';
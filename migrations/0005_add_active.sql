@@ -0,0 +1,2 @@
+-- Deactivated users (active = 0) keep their data but can no longer log in.
+ALTER TABLE users ADD COLUMN active INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,8 @@
+-- User events recorded in the same transaction as the change they describe, until the relay
+-- has delivered them. seq keeps them in recording order; payload is the event as JSON.
+CREATE TABLE outbox (
+    seq         INTEGER PRIMARY KEY AUTOINCREMENT,
+    id          TEXT NOT NULL UNIQUE,
+    recorded_at INTEGER NOT NULL,
+    payload     TEXT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Requests made with an Idempotency-Key, and the responses their retries get. response is the
+-- stored response as JSON, NULL while the first request is still being handled; expired rows
+-- are removed as new keys are claimed.
+CREATE TABLE idempotency_keys (
+    key         TEXT PRIMARY KEY,
+    fingerprint TEXT NOT NULL,
+    response    TEXT,
+    expires_at  INTEGER NOT NULL
+);
+
+CREATE INDEX idempotency_keys_expires_at ON idempotency_keys (expires_at);
@@ -0,0 +1,22 @@
+-- Creation order for the "created" sort and its cursors. The implicit rowid of a table with a
+-- TEXT primary key can be reused after a delete and renumbered by VACUUM; an AUTOINCREMENT key
+-- is neither. SQLite cannot add such a key in place, so the table is rebuilt, keeping the
+-- existing users in rowid order.
+CREATE TABLE users_new (
+    seq           INTEGER PRIMARY KEY AUTOINCREMENT,
+    id            TEXT NOT NULL UNIQUE,
+    username      TEXT NOT NULL,
+    email         TEXT NOT NULL,
+    password_hash TEXT,
+    roles         TEXT NOT NULL DEFAULT '[]',
+    active        INTEGER NOT NULL DEFAULT 1
+);
+
+INSERT INTO users_new (seq, id, username, email, password_hash, roles, active)
+SELECT rowid, id, username, email, password_hash, roles, active FROM users ORDER BY rowid;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE UNIQUE INDEX users_username_key ON users (username);
+CREATE UNIQUE INDEX users_email_key ON users (email);
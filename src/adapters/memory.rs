@@ -1,35 +1,160 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::users::{User, UserRepo, UserRepoError};
+use crate::events::RecordedEvent;
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::users::{ConflictField, Cursor, RecordEvents, Role, User, UserPage, UserQuery, UserRepo, UserRepoError, UserSort};
+use crate::webhooks::{Delivery, DeliveryStatus, Webhook};
 
 pub struct MemoryUserRepo {
-    users: RwLock<HashMap<Uuid, User>>,
+    state: RwLock<MemoryState>,
+}
+
+/// Users keyed by id, plus secondary indexes mirroring the unique constraints of the other adapters.
+/// The outbox lives under the same lock, which makes recording events atomic with the change.
+#[derive(Default)]
+struct MemoryState {
+    users: HashMap<Uuid, StoredUser>,
+    by_username: HashMap<String, Uuid>,
+    by_email: HashMap<String, Uuid>,
+    next_seq: u64,
+    outbox: VecDeque<RecordedEvent>,
+    /// In creation order, as are their deliveries.
+    webhooks: Vec<Webhook>,
+    deliveries: Vec<Delivery>,
+    idempotency: HashMap<String, IdempotencyRecord>,
+}
+
+/// A user plus its insertion sequence number, which backs [`UserSort::Created`], and its
+/// password hash.
+struct StoredUser {
+    seq: u64,
+    user: User,
+    password_hash: Option<String>,
+}
+
+impl StoredUser {
+    /// Orders `self` relative to the position described by `cursor` under `sort`.
+    fn cmp_cursor(&self, sort: UserSort, cursor: &Cursor) -> Result<Ordering, UserRepoError> {
+        Ok(match sort {
+            UserSort::Created => {
+                let seq: u64 = cursor.key.parse().map_err(|_| UserRepoError::InvalidCursor)?;
+                self.seq.cmp(&seq)
+            }
+            UserSort::Username => (self.user.username.as_str(), self.user.id).cmp(&(cursor.key.as_str(), cursor.id)),
+            UserSort::Email => (self.user.email.as_str(), self.user.id).cmp(&(cursor.key.as_str(), cursor.id)),
+        })
+    }
+
+    fn cursor(&self, sort: UserSort) -> Cursor {
+        let key = match sort {
+            UserSort::Created => self.seq.to_string(),
+            UserSort::Username => self.user.username.clone(),
+            UserSort::Email => self.user.email.clone(),
+        };
+
+        Cursor { sort, id: self.user.id, key }
+    }
+}
+
+impl MemoryState {
+    /// Fails if `username` or `email` already belongs to a user other than `id`.
+    fn check_unique(&self, id: Uuid, username: &str, email: &str) -> Result<(), UserRepoError> {
+        if self.by_username.get(username).is_some_and(|owner| *owner != id) {
+            return Err(UserRepoError::Conflict {
+                field: ConflictField::Username,
+                value: username.to_owned(),
+            });
+        }
+
+        if self.by_email.get(email).is_some_and(|owner| *owner != id) {
+            return Err(UserRepoError::Conflict {
+                field: ConflictField::Email,
+                value: email.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, user: User, password_hash: Option<&str>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.insert_stored(StoredUser {
+            seq,
+            user,
+            password_hash: password_hash.map(str::to_owned),
+        });
+    }
+
+    fn insert_stored(&mut self, stored: StoredUser) {
+        self.by_username.insert(stored.user.username.clone(), stored.user.id);
+        self.by_email.insert(stored.user.email.clone(), stored.user.id);
+        self.users.insert(stored.user.id, stored);
+    }
+
+    fn remove(&mut self, id: Uuid) -> Option<StoredUser> {
+        let stored = self.users.remove(&id)?;
+        self.by_username.remove(&stored.user.username);
+        self.by_email.remove(&stored.user.email);
+        Some(stored)
+    }
+
+    fn record(&mut self, events: RecordEvents<'_>, before: Option<&User>, after: Option<&User>) {
+        self.outbox.extend(events(before, after).into_iter().map(RecordedEvent::new));
+    }
+
+    /// Applies `change` to user `id` and records its events.
+    fn change(&mut self, id: Uuid, events: RecordEvents<'_>, change: impl FnOnce(&mut StoredUser)) -> Option<User> {
+        let stored = self.users.get_mut(&id)?;
+        let before = stored.user.clone();
+        change(stored);
+        let after = stored.user.clone();
+        self.record(events, Some(&before), Some(&after));
+        Some(after)
+    }
 }
 
 impl MemoryUserRepo {
     pub fn new() -> Self {
         Self {
-            users: RwLock::new(HashMap::new()),
+            state: RwLock::new(MemoryState::default()),
         }
     }
 }
 
+impl Default for MemoryUserRepo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait::async_trait]
 impl UserRepo for MemoryUserRepo {
-    async fn add_user(&self, username: &str, email: &str) -> Result<User, UserRepoError> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
         log::debug!(target: "Users", "Adding user: {username}");
 
         let user = User {
             id: Uuid::new_v4(),
             username: username.to_owned(),
             email: email.to_owned(),
+            roles: Vec::new(),
+            active: true,
         };
 
         // No need for Entry/Vacant: UUID collision is not a thing you handle here.
-        let mut users = self.users.write().await;
-        users.insert(user.id, user.clone());
+        let mut state = self.state.write().await;
+        state.check_unique(user.id, username, email)?;
+        state.insert(user.clone(), password_hash);
+        state.record(events, None, Some(&user));
 
         Ok(user)
     }
@@ -37,21 +162,275 @@ impl UserRepo for MemoryUserRepo {
     async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
         log::debug!(target: "Users", "Getting user: {id}");
 
-        let users = self.users.read().await;
-        Ok(users.get(&id).cloned())
+        let state = self.state.read().await;
+        Ok(state.users.get(&id).map(|stored| stored.user.clone()))
+    }
+
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Updating user: {id}");
+
+        let mut state = self.state.write().await;
+        if !state.users.contains_key(&id) {
+            return Ok(None);
+        }
+        state.check_unique(id, username, email)?;
+
+        // Keep the sequence number so the user's position in creation order is stable.
+        let mut stored = state.remove(id).expect("user exists");
+        let before = stored.user.clone();
+        stored.user.username = username.to_owned();
+        stored.user.email = email.to_owned();
+        let user = stored.user.clone();
+        state.insert_stored(stored);
+        state.record(events, Some(&before), Some(&user));
+
+        Ok(Some(user))
     }
 
-    async fn remove_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
         log::debug!(target: "Users", "Removing user: {id}");
 
-        let mut users = self.users.write().await;
-        Ok(users.remove(&id))
+        let mut state = self.state.write().await;
+        let removed = state.remove(id).map(|stored| stored.user);
+        if let Some(user) = &removed {
+            state.record(events, Some(user), None);
+        }
+        Ok(removed)
     }
 
     async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
         log::debug!(target: "Users", "Listing users");
 
-        let users = self.users.read().await;
-        Ok(users.values().cloned().collect())
+        let state = self.state.read().await;
+        Ok(state.users.values().map(|stored| stored.user.clone()).collect())
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        log::debug!(target: "Users", "Querying users: {query:?}");
+
+        let cursor = query.cursor()?;
+        let state = self.state.read().await;
+
+        let mut matches = Vec::new();
+        for stored in state.users.values().filter(|stored| query.matches(&stored.user)) {
+            if let Some(cursor) = cursor
+                && stored.cmp_cursor(query.sort, cursor)? != Ordering::Greater
+            {
+                continue;
+            }
+            matches.push(stored);
+        }
+
+        match query.sort {
+            UserSort::Created => matches.sort_by_key(|stored| stored.seq),
+            UserSort::Username => matches.sort_by(|a, b| (&a.user.username, a.user.id).cmp(&(&b.user.username, b.user.id))),
+            UserSort::Email => matches.sort_by(|a, b| (&a.user.email, a.user.id).cmp(&(&b.user.email, b.user.id))),
+        }
+
+        let page_size = query.page_size();
+        let next_cursor = (matches.len() > page_size).then(|| matches[page_size - 1].cursor(query.sort));
+        matches.truncate(page_size);
+
+        Ok(UserPage {
+            users: matches.into_iter().map(|stored| stored.user.clone()).collect(),
+            next_cursor,
+        })
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        log::debug!(target: "Users", "Counting users: {query:?}");
+
+        let state = self.state.read().await;
+        Ok(state.users.values().filter(|stored| query.matches(&stored.user)).count() as u64)
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Finding user by login: {login}");
+
+        let state = self.state.read().await;
+        let id = state.by_username.get(login).or_else(|| state.by_email.get(login));
+        Ok(id.and_then(|id| state.users.get(id)).map(|stored| stored.user.clone()))
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        log::debug!(target: "Users", "Getting password hash: {id}");
+
+        let state = self.state.read().await;
+        Ok(state.users.get(&id).and_then(|stored| stored.password_hash.clone()))
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        log::debug!(target: "Users", "Setting password hash: {id}");
+
+        let mut state = self.state.write().await;
+        let changed = state.change(id, events, |stored| stored.password_hash = hash.map(str::to_owned));
+        Ok(changed.is_some())
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Granting role {role} to user: {id}");
+
+        let mut state = self.state.write().await;
+        Ok(state.change(id, events, |stored| {
+            if let Err(at) = stored.user.roles.binary_search(&role) {
+                stored.user.roles.insert(at, role);
+            }
+        }))
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Revoking role {role} from user: {id}");
+
+        let mut state = self.state.write().await;
+        Ok(state.change(id, events, |stored| stored.user.roles.retain(|r| *r != role)))
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Setting user {id} active: {active}");
+
+        let mut state = self.state.write().await;
+        Ok(state.change(id, events, |stored| stored.user.active = active))
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        let state = self.state.read().await;
+        Ok(state.outbox.iter().take(limit).cloned().collect())
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        let mut state = self.state.write().await;
+        state.outbox.retain(|recorded| !ids.contains(&recorded.id));
+        Ok(())
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        log::debug!(target: "Users", "Adding webhook: {}", webhook.id);
+
+        let mut state = self.state.write().await;
+        state.webhooks.push(webhook.clone());
+        Ok(())
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        let state = self.state.read().await;
+        Ok(state.webhooks.iter().find(|webhook| webhook.id == id).cloned())
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        let state = self.state.read().await;
+        Ok(state.webhooks.clone())
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        log::debug!(target: "Users", "Removing webhook: {id}");
+
+        let mut state = self.state.write().await;
+        let Some(at) = state.webhooks.iter().position(|webhook| webhook.id == id) else {
+            return Ok(None);
+        };
+        state.deliveries.retain(|delivery| delivery.webhook_id != id);
+        Ok(Some(state.webhooks.remove(at)))
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        let mut state = self.state.write().await;
+        for delivery in deliveries {
+            let known_webhook = state.webhooks.iter().any(|webhook| webhook.id == delivery.webhook_id);
+            let duplicate = state
+                .deliveries
+                .iter()
+                .any(|d| d.webhook_id == delivery.webhook_id && d.event_id == delivery.event_id);
+            if known_webhook && !duplicate {
+                state.deliveries.push(delivery.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        let state = self.state.read().await;
+        Ok(state.deliveries.iter().find(|delivery| delivery.id == id).cloned())
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        let mut state = self.state.write().await;
+        match state.deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            Some(stored) => {
+                *stored = delivery.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let state = self.state.read().await;
+        let mut due: Vec<_> = state
+            .deliveries
+            .iter()
+            .filter(|d| d.status == DeliveryStatus::Pending && d.next_attempt_at.is_some_and(|at| at <= now))
+            .cloned()
+            .collect();
+        // Stable, so deliveries due at the same time keep their creation order.
+        due.sort_by_key(|d| d.next_attempt_at);
+        due.truncate(limit);
+        Ok(due)
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let state = self.state.read().await;
+        Ok(state
+            .deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| delivery.webhook_id == webhook_id)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        let mut state = self.state.write().await;
+        state.idempotency.retain(|_, existing| existing.expires_at > now);
+        match state.idempotency.get(&record.key) {
+            Some(existing) => Ok(Some(existing.clone())),
+            None => {
+                state.idempotency.insert(record.key.clone(), record.clone());
+                Ok(None)
+            }
+        }
+    }
+
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        let mut state = self.state.write().await;
+        if let Some(record) = state.idempotency.get_mut(key) {
+            record.response = Some(response.clone());
+            record.expires_at = expires_at;
+        }
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        self.state.write().await.idempotency.remove(key);
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), UserRepoError> {
+        Ok(())
     }
 }
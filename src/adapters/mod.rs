@@ -1,3 +1,45 @@
 pub mod mongo;
 pub mod sqlite;
-pub mod memory;
\ No newline at end of file
+pub mod memory;
+
+use crate::config::{BackendKind, BackendSettings};
+use crate::users::{DynUserRepo, UserRepoError};
+use memory::MemoryUserRepo;
+use mongo::MongoUserRepo;
+use sqlite::SqliteUserRepo;
+use std::sync::Arc;
+
+/// Connects to the backend selected in `settings` and returns its repository.
+pub async fn connect(settings: &BackendSettings) -> Result<DynUserRepo, UserRepoError> {
+    log::info!("Using {:?} backend", settings.kind);
+
+    Ok(match settings.kind {
+        BackendKind::Sqlite => Arc::new(SqliteUserRepo::open(&settings.sqlite.path).await?),
+        BackendKind::Mongo => {
+            Arc::new(MongoUserRepo::connect(&settings.mongo).await?)
+        }
+        BackendKind::Memory => Arc::new(MemoryUserRepo::new()),
+    })
+}
+
+/// Brings the selected backend's schema up to date without serving requests.
+///
+/// SQLite applies its pending migrations; MongoDB ensures its indexes exist; the in-memory
+/// backend has nothing to do.
+pub async fn migrate(settings: &BackendSettings) -> Result<(), UserRepoError> {
+    match settings.kind {
+        BackendKind::Sqlite => {
+            let pool = sqlite::connect(&settings.sqlite.path).await?;
+            let report = sqlite::migrate(&pool).await?;
+            log::info!("SQLite schema at version {} (was {:?})", report.to, report.from);
+            pool.close().await;
+        }
+        BackendKind::Mongo => {
+            MongoUserRepo::connect(&settings.mongo).await?;
+            log::info!("MongoDB indexes are in place");
+        }
+        BackendKind::Memory => log::info!("In-memory backend has no schema to migrate"),
+    }
+
+    Ok(())
+}
@@ -1,12 +1,20 @@
-use crate::users::{User, UserRepo, UserRepoError};
+use crate::config::MongoSettings;
+use crate::events::RecordedEvent;
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::users::{ConflictField, Cursor, RecordEvents, Role, User, UserPage, UserQuery, UserRepo, UserRepoError, UserSort};
+use crate::webhooks::{Delivery, DeliveryStatus, Webhook};
 use async_trait::async_trait;
 use futures_util::TryStreamExt;
 use log::info;
 use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::ReturnDocument;
 use mongodb::{
-    bson::{doc, Document}, Collection,
+    bson::{doc, Document}, ClientSession, Collection,
     Database,
 };
+use thiserror::Error;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -16,33 +24,313 @@ struct MongoUserDoc {
     uuid: String,
     username: String,
     email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    /// Role names; missing in documents written before roles existed.
+    #[serde(default)]
+    roles: Vec<String>,
+    /// Missing in documents written before deactivation existed, which are all active.
+    #[serde(default = "active_by_default")]
+    active: bool,
+}
+
+fn active_by_default() -> bool {
+    true
 }
 
 impl MongoUserDoc {
-    fn from_user(user: &User) -> Self {
+    fn from_user(user: &User, password_hash: Option<&str>) -> Self {
         Self {
             id: ObjectId::new(),
             uuid: user.id.to_string(),
             username: user.username.clone(),
             email: user.email.clone(),
+            password_hash: password_hash.map(str::to_owned),
+            roles: user.roles.iter().map(|role| role.as_str().to_owned()).collect(),
+            active: user.active,
         }
     }
 
     fn try_into_user(self) -> Result<User, UserRepoError> {
+        let roles = Role::parse_all(&self.roles).map_err(UserRepoError::unexpected)?;
+
         Ok(User {
             id: Uuid::parse_str(&self.uuid).map_err(|e| UserRepoError::Unexpected(Box::new(e)))?,
             username: self.username,
             email: self.email,
+            roles,
+            active: self.active,
+        })
+    }
+
+    fn cursor(&self, sort: UserSort) -> Result<Cursor, UserRepoError> {
+        let key = match sort {
+            UserSort::Created => self.id.to_hex(),
+            UserSort::Username => self.username.clone(),
+            UserSort::Email => self.email.clone(),
+        };
+
+        Ok(Cursor {
+            sort,
+            id: Uuid::parse_str(&self.uuid).map_err(|e| UserRepoError::Unexpected(Box::new(e)))?,
+            key,
         })
     }
 }
 
+/// An outbox entry; `_id` increases as events are recorded, which keeps them in order.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MongoOutboxDoc {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    uuid: String,
+    recorded_at: i64,
+    /// The [`UserEvent`](crate::events::UserEvent) as JSON, like in the SQLite outbox.
+    payload: String,
+}
+
+impl MongoOutboxDoc {
+    fn from_event(recorded: &RecordedEvent) -> Result<Self, UserRepoError> {
+        Ok(Self {
+            id: ObjectId::new(),
+            uuid: recorded.id.to_string(),
+            recorded_at: recorded.recorded_at as i64,
+            payload: serde_json::to_string(&recorded.event).map_err(UserRepoError::unexpected)?,
+        })
+    }
+
+    fn try_into_event(self) -> Result<RecordedEvent, UserRepoError> {
+        Ok(RecordedEvent {
+            id: Uuid::parse_str(&self.uuid).map_err(UserRepoError::unexpected)?,
+            recorded_at: self.recorded_at as u64,
+            event: serde_json::from_str(&self.payload).map_err(UserRepoError::unexpected)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MongoWebhookDoc {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    uuid: String,
+    url: String,
+    secret: String,
+    event_types: Vec<String>,
+    created_at: i64,
+    /// Deliveries created for the webhook; written by `add_deliveries` so that it conflicts
+    /// with a concurrent removal. Missing in documents written before it existed.
+    #[serde(default)]
+    deliveries: i64,
+}
+
+impl MongoWebhookDoc {
+    fn from_webhook(webhook: &Webhook) -> Self {
+        Self {
+            id: ObjectId::new(),
+            uuid: webhook.id.to_string(),
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            event_types: webhook.event_types.clone(),
+            created_at: webhook.created_at as i64,
+            deliveries: 0,
+        }
+    }
+
+    fn try_into_webhook(self) -> Result<Webhook, UserRepoError> {
+        Ok(Webhook {
+            id: Uuid::parse_str(&self.uuid).map_err(UserRepoError::unexpected)?,
+            url: self.url,
+            secret: self.secret,
+            event_types: self.event_types,
+            created_at: self.created_at as u64,
+        })
+    }
+}
+
+/// A webhook delivery; `_id` increases as deliveries are created, which orders the log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MongoDeliveryDoc {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    uuid: String,
+    webhook_id: String,
+    event_id: String,
+    event_type: String,
+    payload: String,
+    status: String,
+    attempts: i64,
+    next_attempt_at: Option<i64>,
+    last_attempt_at: Option<i64>,
+    last_status_code: Option<i32>,
+    last_error: Option<String>,
+    created_at: i64,
+}
+
+impl MongoDeliveryDoc {
+    fn from_delivery(delivery: &Delivery) -> Self {
+        Self {
+            id: ObjectId::new(),
+            uuid: delivery.id.to_string(),
+            webhook_id: delivery.webhook_id.to_string(),
+            event_id: delivery.event_id.to_string(),
+            event_type: delivery.event_type.clone(),
+            payload: delivery.payload.clone(),
+            status: delivery.status.as_str().to_owned(),
+            attempts: delivery.attempts.into(),
+            next_attempt_at: delivery.next_attempt_at.map(|at| at as i64),
+            last_attempt_at: delivery.last_attempt_at.map(|at| at as i64),
+            last_status_code: delivery.last_status_code.map(i32::from),
+            last_error: delivery.last_error.clone(),
+            created_at: delivery.created_at as i64,
+        }
+    }
+
+    fn try_into_delivery(self) -> Result<Delivery, UserRepoError> {
+        Ok(Delivery {
+            id: Uuid::parse_str(&self.uuid).map_err(UserRepoError::unexpected)?,
+            webhook_id: Uuid::parse_str(&self.webhook_id).map_err(UserRepoError::unexpected)?,
+            event_id: Uuid::parse_str(&self.event_id).map_err(UserRepoError::unexpected)?,
+            event_type: self.event_type,
+            payload: self.payload,
+            status: self.status.parse().map_err(UserRepoError::unexpected)?,
+            attempts: self.attempts as u32,
+            next_attempt_at: self.next_attempt_at.map(|at| at as u64),
+            last_attempt_at: self.last_attempt_at.map(|at| at as u64),
+            last_status_code: self.last_status_code.map(|code| code as u16),
+            last_error: self.last_error,
+            created_at: self.created_at as u64,
+        })
+    }
+}
+
+/// A claimed idempotency key; the key itself is `_id`, so a second claim fails as a duplicate.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MongoIdempotencyDoc {
+    #[serde(rename = "_id")]
+    key: String,
+    fingerprint: String,
+    /// The [`StoredResponse`] as JSON; missing while the request is in flight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    expires_at: i64,
+    /// `expires_at` as a BSON date, for the TTL index that removes expired keys.
+    expire_after: DateTime,
+}
+
+impl MongoIdempotencyDoc {
+    fn from_record(record: &IdempotencyRecord) -> Result<Self, UserRepoError> {
+        Ok(Self {
+            key: record.key.clone(),
+            fingerprint: record.fingerprint.clone(),
+            response: record
+                .response
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()
+                .map_err(UserRepoError::unexpected)?,
+            expires_at: record.expires_at as i64,
+            expire_after: DateTime::from_millis(record.expires_at as i64),
+        })
+    }
+
+    fn try_into_record(self) -> Result<IdempotencyRecord, UserRepoError> {
+        Ok(IdempotencyRecord {
+            key: self.key,
+            fingerprint: self.fingerprint,
+            response: self
+                .response
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .map_err(UserRepoError::unexpected)?,
+            expires_at: self.expires_at as u64,
+        })
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(we)) if we.code == DUPLICATE_KEY)
+}
+
+/// Escapes regex metacharacters so user input matches literally.
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn query_filter(query: &UserQuery) -> Result<Document, UserRepoError> {
+    let mut clauses = Vec::new();
+
+    if let Some(prefix) = &query.username_prefix {
+        clauses.push(doc! { "username": { "$regex": format!("^{}", regex_escape(prefix)) } });
+    }
+
+    if let Some(domain) = &query.email_domain {
+        clauses.push(doc! { "email": { "$regex": format!("@{}$", regex_escape(domain)), "$options": "i" } });
+    }
+
+    if let Some(cursor) = query.cursor()? {
+        clauses.push(match query.sort {
+            UserSort::Created => {
+                let oid = ObjectId::parse_str(&cursor.key).map_err(|_| UserRepoError::InvalidCursor)?;
+                doc! { "_id": { "$gt": oid } }
+            }
+            UserSort::Username | UserSort::Email => {
+                let field = query.sort.as_str();
+                doc! {
+                    "$or": [
+                        { field: { "$gt": &cursor.key } },
+                        { field: &cursor.key, "uuid": { "$gt": cursor.id.to_string() } },
+                    ]
+                }
+            }
+        });
+    }
+
+    Ok(if clauses.is_empty() { Document::new() } else { doc! { "$and": clauses } })
+}
+
+const USERNAME_INDEX: &str = "users_username_key";
+const EMAIL_INDEX: &str = "users_email_key";
+
+/// Server error code for a unique index violation.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Why [`MongoUserRepo::new`] refuses a standalone server unless told to accept one.
+#[derive(Debug, Error)]
+#[error(
+    "MongoDB is a standalone server without transactions, so a crash between a user change and \
+     its outbox events would lose the events; use a replica set, or set \
+     `backend.mongo.allow_standalone` to accept that"
+)]
+pub struct StandaloneServer;
+
 pub struct MongoUserRepo {
+    db: Database,
     users: Collection<MongoUserDoc>,
+    outbox: Collection<MongoOutboxDoc>,
+    webhooks: Collection<MongoWebhookDoc>,
+    deliveries: Collection<MongoDeliveryDoc>,
+    idempotency_keys: Collection<MongoIdempotencyDoc>,
+    /// Whether the deployment (a replica set or sharded cluster) supports transactions.
+    transactions: bool,
 }
 
 impl MongoUserRepo {
-    pub async fn new(db: Database) -> Self {
+    pub async fn connect(settings: &MongoSettings) -> Result<Self, UserRepoError> {
+        let client = mongodb::Client::with_uri_str(&settings.uri).await.map_err(map_mongo_err)?;
+        Self::new(client.database(&settings.database), settings.allow_standalone).await
+    }
+
+    /// Ensures the indexes exist. Fails with [`StandaloneServer`] on a deployment without
+    /// transactions, unless `allow_standalone` is set; changes are then written without them.
+    pub async fn new(db: Database, allow_standalone: bool) -> Result<Self, UserRepoError> {
         let users = db.collection::<MongoUserDoc>("users");
 
         users
@@ -57,9 +345,159 @@ impl MongoUserRepo {
                     .build(),
             )
             .await
-            .expect("Failed to create index on 'uuid' field");
+            .map_err(map_mongo_err)?;
+
+        // Named explicitly so duplicate key errors can be traced back to the field.
+        for (field, name) in [("username", USERNAME_INDEX), ("email", EMAIL_INDEX)] {
+            users
+                .create_index(
+                    mongodb::IndexModel::builder()
+                        .keys(doc! { field: 1 })
+                        .options(
+                            mongodb::options::IndexOptions::builder()
+                                .name(name.to_owned())
+                                .unique(true)
+                                .build(),
+                        )
+                        .build(),
+                )
+                .await
+                .map_err(map_mongo_err)?;
+        }
+
+        let webhooks = db.collection::<MongoWebhookDoc>("webhooks");
+        let deliveries = db.collection::<MongoDeliveryDoc>("webhook_deliveries");
+        let indexes = [
+            (&webhooks.clone_with_type::<Document>(), doc! { "uuid": 1 }, true),
+            (&deliveries.clone_with_type::<Document>(), doc! { "uuid": 1 }, true),
+            // At most one delivery per webhook and event, however often the relay hands it over.
+            (&deliveries.clone_with_type::<Document>(), doc! { "webhook_id": 1, "event_id": 1 }, true),
+            (&deliveries.clone_with_type::<Document>(), doc! { "status": 1, "next_attempt_at": 1 }, false),
+        ];
+        for (collection, keys, unique) in indexes {
+            collection
+                .create_index(
+                    mongodb::IndexModel::builder()
+                        .keys(keys)
+                        .options(mongodb::options::IndexOptions::builder().unique(unique).build())
+                        .build(),
+                )
+                .await
+                .map_err(map_mongo_err)?;
+        }
+
+        // The TTL monitor only runs about once a minute, so claims still check `expires_at`.
+        let idempotency_keys = db.collection::<MongoIdempotencyDoc>("idempotency_keys");
+        idempotency_keys
+            .create_index(
+                mongodb::IndexModel::builder()
+                    .keys(doc! { "expire_after": 1 })
+                    .options(
+                        mongodb::options::IndexOptions::builder()
+                            .expire_after(std::time::Duration::ZERO)
+                            .build(),
+                    )
+                    .build(),
+            )
+            .await
+            .map_err(map_mongo_err)?;
+
+        // Standalone servers have no transactions; replica set members report their set name.
+        let hello = db.run_command(doc! { "hello": 1 }).await.map_err(map_mongo_err)?;
+        let transactions = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+        if !transactions && !allow_standalone {
+            return Err(UserRepoError::unexpected(StandaloneServer));
+        }
+        if !transactions {
+            log::warn!(
+                "MongoDB is a standalone server without transactions: user changes and their outbox events \
+                 are written one after the other, so a crash in between loses the events"
+            );
+        }
+
+        Ok(Self {
+            outbox: db.collection("outbox"),
+            webhooks,
+            deliveries,
+            idempotency_keys,
+            db,
+            users,
+            transactions,
+        })
+    }
+
+    /// A session for one change, in a transaction if the deployment supports them.
+    async fn start(&self) -> Result<ClientSession, UserRepoError> {
+        let mut session = self.db.client().start_session().await.map_err(map_mongo_err)?;
+        if self.transactions {
+            session.start_transaction().await.map_err(map_mongo_err)?;
+        }
+        Ok(session)
+    }
+
+    /// Commits the change made in `session`. Sessions dropped before this abort their transaction.
+    async fn commit(&self, session: &mut ClientSession) -> Result<(), UserRepoError> {
+        if self.transactions {
+            session.commit_transaction().await.map_err(map_mongo_err)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the events `events` derives for a change to the outbox, in the change's session.
+    async fn record(
+        &self,
+        session: &mut ClientSession,
+        events: RecordEvents<'_>,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> Result<(), UserRepoError> {
+        let docs = events(before, after)
+            .into_iter()
+            .map(|event| MongoOutboxDoc::from_event(&RecordedEvent::new(event)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !docs.is_empty() {
+            self.outbox.insert_many(docs).session(&mut *session).await.map_err(map_mongo_err)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `update` to user `id` and records the events for the change.
+    async fn change(
+        &self,
+        id: Uuid,
+        events: RecordEvents<'_>,
+        update: Document,
+        map_err: impl FnOnce(mongodb::error::Error) -> UserRepoError,
+    ) -> Result<Option<User>, UserRepoError> {
+        let mut session = self.start().await?;
+        let filter = doc! { "uuid": id.to_string() };
+
+        let Some(before) = self
+            .users
+            .find_one(filter.clone())
+            .session(&mut session)
+            .await
+            .map_err(map_mongo_err)?
+        else {
+            return Ok(None);
+        };
+        let Some(after) = self
+            .users
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .session(&mut session)
+            .await
+            .map_err(map_err)?
+        else {
+            return Ok(None);
+        };
+
+        let (before, after) = (before.try_into_user()?, after.try_into_user()?);
+        self.record(&mut session, events, Some(&before), Some(&after)).await?;
+        self.commit(&mut session).await?;
 
-        Self { users }
+        Ok(Some(after))
     }
 }
 
@@ -81,21 +519,61 @@ fn map_mongo_err(e: mongodb::error::Error) -> UserRepoError {
     UserRepoError::Unexpected(Box::new(e))
 }
 
+/// Like [`map_mongo_err`], but turns duplicate key errors (E11000) into [`UserRepoError::Conflict`].
+fn map_write_err(e: mongodb::error::Error, username: &str, email: &str) -> UserRepoError {
+    // insert_one reports a write error, find_one_and_update a command error.
+    let duplicate_message = match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(we)) if we.code == DUPLICATE_KEY => Some(&we.message),
+        ErrorKind::Command(ce) if ce.code == DUPLICATE_KEY => Some(&ce.message),
+        _ => None,
+    };
+
+    if let Some(message) = duplicate_message {
+        // e.g. "E11000 duplicate key error collection: db.users index: users_email_key dup key: { ... }"
+        if message.contains(USERNAME_INDEX) {
+            return UserRepoError::Conflict {
+                field: ConflictField::Username,
+                value: username.to_owned(),
+            };
+        }
+        if message.contains(EMAIL_INDEX) {
+            return UserRepoError::Conflict {
+                field: ConflictField::Email,
+                value: email.to_owned(),
+            };
+        }
+    }
+
+    map_mongo_err(e)
+}
+
 #[async_trait]
 impl UserRepo for MongoUserRepo {
-    async fn add_user(&self, username: &str, email: &str) -> Result<User, UserRepoError> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
         info!(target: "Users", "Adding user: {}", username);
 
         let user = User {
             id: Uuid::new_v4(),
             username: username.to_owned(),
             email: email.to_owned(),
+            roles: Vec::new(),
+            active: true,
         };
 
+        let mut session = self.start().await?;
         self.users
-            .insert_one(MongoUserDoc::from_user(&user))
+            .insert_one(MongoUserDoc::from_user(&user, password_hash))
+            .session(&mut session)
             .await
-            .map_err(map_mongo_err)?;
+            .map_err(|e| map_write_err(e, username, email))?;
+        self.record(&mut session, events, None, Some(&user)).await?;
+        self.commit(&mut session).await?;
 
         Ok(user)
     }
@@ -112,16 +590,38 @@ impl UserRepo for MongoUserRepo {
         doc_opt.map(MongoUserDoc::try_into_user).transpose()
     }
 
-    async fn remove_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        info!(target: "Users", "Updating user: {}", id);
+
+        let update = doc! { "$set": { "username": username, "email": email } };
+        self.change(id, events, update, |e| map_write_err(e, username, email)).await
+    }
+
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
         info!(target: "Users", "Removing user: {}", id);
 
-        let doc_opt = self
+        let mut session = self.start().await?;
+        let Some(doc) = self
             .users
             .find_one_and_delete(doc! { "uuid": id.to_string() })
+            .session(&mut session)
             .await
-            .map_err(map_mongo_err)?;
+            .map_err(map_mongo_err)?
+        else {
+            return Ok(None);
+        };
 
-        doc_opt.map(MongoUserDoc::try_into_user).transpose()
+        let user = doc.try_into_user()?;
+        self.record(&mut session, events, Some(&user), None).await?;
+        self.commit(&mut session).await?;
+
+        Ok(Some(user))
     }
 
     async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
@@ -137,4 +637,345 @@ impl UserRepo for MongoUserRepo {
 
         docs.into_iter().map(MongoUserDoc::try_into_user).collect()
     }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        info!(target: "Users", "Querying users: {:?}", query);
+
+        let sort = match query.sort {
+            UserSort::Created => doc! { "_id": 1 },
+            UserSort::Username => doc! { "username": 1, "uuid": 1 },
+            UserSort::Email => doc! { "email": 1, "uuid": 1 },
+        };
+
+        // Fetch one extra document to learn whether there is a next page.
+        let page_size = query.page_size();
+        let cursor = self
+            .users
+            .find(query_filter(query)?)
+            .sort(sort)
+            .limit(page_size as i64 + 1)
+            .await
+            .map_err(map_mongo_err)?;
+
+        let mut docs: Vec<MongoUserDoc> = cursor.try_collect().await.map_err(map_mongo_err)?;
+
+        let next_cursor = if docs.len() > page_size {
+            docs.truncate(page_size);
+            docs.last().map(|doc| doc.cursor(query.sort)).transpose()?
+        } else {
+            None
+        };
+
+        let users = docs
+            .into_iter()
+            .map(MongoUserDoc::try_into_user)
+            .collect::<Result<_, _>>()?;
+
+        Ok(UserPage { users, next_cursor })
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        info!(target: "Users", "Counting users: {:?}", query);
+
+        let filter = query_filter(&UserQuery { cursor: None, ..query.clone() })?;
+        self.users.count_documents(filter).await.map_err(map_mongo_err)
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        info!(target: "Users", "Finding user by login: {}", login);
+
+        let doc_opt = self
+            .users
+            .find_one(doc! { "$or": [{ "username": login }, { "email": login }] })
+            .await
+            .map_err(map_mongo_err)?;
+
+        doc_opt.map(MongoUserDoc::try_into_user).transpose()
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        info!(target: "Users", "Getting password hash: {}", id);
+
+        let doc_opt = self
+            .users
+            .find_one(doc! { "uuid": id.to_string() })
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(doc_opt.and_then(|doc| doc.password_hash))
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        info!(target: "Users", "Setting password hash: {}", id);
+
+        let update = match hash {
+            Some(hash) => doc! { "$set": { "password_hash": hash } },
+            None => doc! { "$unset": { "password_hash": "" } },
+        };
+
+        Ok(self.change(id, events, update, map_mongo_err).await?.is_some())
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        info!(target: "Users", "Granting role {} to user: {}", role, id);
+
+        let update = doc! { "$addToSet": { "roles": role.as_str() } };
+        self.change(id, events, update, map_mongo_err).await
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        info!(target: "Users", "Revoking role {} from user: {}", role, id);
+
+        let update = doc! { "$pull": { "roles": role.as_str() } };
+        self.change(id, events, update, map_mongo_err).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        info!(target: "Users", "Setting user {} active: {}", id, active);
+
+        let update = doc! { "$set": { "active": active } };
+        self.change(id, events, update, map_mongo_err).await
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        let cursor = self
+            .outbox
+            .find(Document::new())
+            .sort(doc! { "_id": 1 })
+            .limit(limit as i64)
+            .await
+            .map_err(map_mongo_err)?;
+
+        let docs: Vec<MongoOutboxDoc> = cursor.try_collect().await.map_err(map_mongo_err)?;
+
+        docs.into_iter().map(MongoOutboxDoc::try_into_event).collect()
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        let ids: Vec<String> = ids.iter().map(Uuid::to_string).collect();
+        self.outbox
+            .delete_many(doc! { "uuid": { "$in": ids } })
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(())
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        info!(target: "Users", "Adding webhook: {}", webhook.id);
+
+        self.webhooks
+            .insert_one(MongoWebhookDoc::from_webhook(webhook))
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(())
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        let doc_opt = self
+            .webhooks
+            .find_one(doc! { "uuid": id.to_string() })
+            .await
+            .map_err(map_mongo_err)?;
+
+        doc_opt.map(MongoWebhookDoc::try_into_webhook).transpose()
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        let cursor = self
+            .webhooks
+            .find(Document::new())
+            .sort(doc! { "_id": 1 })
+            .await
+            .map_err(map_mongo_err)?;
+
+        let docs: Vec<MongoWebhookDoc> = cursor.try_collect().await.map_err(map_mongo_err)?;
+
+        docs.into_iter().map(MongoWebhookDoc::try_into_webhook).collect()
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        info!(target: "Users", "Removing webhook: {}", id);
+
+        let mut session = self.start().await?;
+        let Some(removed) = self
+            .webhooks
+            .find_one_and_delete(doc! { "uuid": id.to_string() })
+            .session(&mut session)
+            .await
+            .map_err(map_mongo_err)?
+        else {
+            return Ok(None);
+        };
+        self.deliveries
+            .delete_many(doc! { "webhook_id": id.to_string() })
+            .session(&mut session)
+            .await
+            .map_err(map_mongo_err)?;
+        self.commit(&mut session).await?;
+
+        removed.try_into_webhook().map(Some)
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        // A session per delivery, since a duplicate aborts the transaction it happens in.
+        for delivery in deliveries {
+            let mut session = self.start().await?;
+
+            // Without foreign keys, check for the webhook in the same transaction. Bumping a
+            // counter on it, rather than only reading it, makes a concurrent `remove_webhook`
+            // conflict with this transaction instead of leaving an orphan delivery behind.
+            if self
+                .webhooks
+                .find_one_and_update(doc! { "uuid": delivery.webhook_id.to_string() }, doc! { "$inc": { "deliveries": 1 } })
+                .session(&mut session)
+                .await
+                .map_err(map_mongo_err)?
+                .is_none()
+            {
+                continue;
+            }
+
+            match self.deliveries.insert_one(MongoDeliveryDoc::from_delivery(delivery)).session(&mut session).await {
+                Ok(_) => self.commit(&mut session).await?,
+                Err(e) if is_duplicate_key(&e) => {}
+                Err(e) => return Err(map_mongo_err(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        let doc_opt = self
+            .deliveries
+            .find_one(doc! { "uuid": id.to_string() })
+            .await
+            .map_err(map_mongo_err)?;
+
+        doc_opt.map(MongoDeliveryDoc::try_into_delivery).transpose()
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        let stored = MongoDeliveryDoc::from_delivery(delivery);
+        let result = self
+            .deliveries
+            .update_one(
+                doc! { "uuid": &stored.uuid },
+                doc! { "$set": {
+                    "status": stored.status,
+                    "attempts": stored.attempts,
+                    "next_attempt_at": stored.next_attempt_at,
+                    "last_attempt_at": stored.last_attempt_at,
+                    "last_status_code": stored.last_status_code,
+                    "last_error": stored.last_error,
+                } },
+            )
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let cursor = self
+            .deliveries
+            .find(doc! { "status": DeliveryStatus::Pending.as_str(), "next_attempt_at": { "$lte": now as i64 } })
+            .sort(doc! { "next_attempt_at": 1, "_id": 1 })
+            .limit(limit as i64)
+            .await
+            .map_err(map_mongo_err)?;
+
+        let docs: Vec<MongoDeliveryDoc> = cursor.try_collect().await.map_err(map_mongo_err)?;
+
+        docs.into_iter().map(MongoDeliveryDoc::try_into_delivery).collect()
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let cursor = self
+            .deliveries
+            .find(doc! { "webhook_id": webhook_id.to_string() })
+            .sort(doc! { "_id": -1 })
+            .limit(limit as i64)
+            .await
+            .map_err(map_mongo_err)?;
+
+        let docs: Vec<MongoDeliveryDoc> = cursor.try_collect().await.map_err(map_mongo_err)?;
+
+        docs.into_iter().map(MongoDeliveryDoc::try_into_delivery).collect()
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        let stored = MongoIdempotencyDoc::from_record(record)?;
+        loop {
+            self.idempotency_keys
+                .delete_one(doc! { "_id": &stored.key, "expires_at": { "$lte": now as i64 } })
+                .await
+                .map_err(map_mongo_err)?;
+
+            match self.idempotency_keys.insert_one(&stored).await {
+                Ok(_) => return Ok(None),
+                Err(e) if is_duplicate_key(&e) => {}
+                Err(e) => return Err(map_mongo_err(e)),
+            }
+
+            // Released or expired since the insert failed: try claiming it again.
+            if let Some(existing) = self
+                .idempotency_keys
+                .find_one(doc! { "_id": &stored.key, "expires_at": { "$gt": now as i64 } })
+                .await
+                .map_err(map_mongo_err)?
+            {
+                return existing.try_into_record().map(Some);
+            }
+        }
+    }
+
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        let response = serde_json::to_string(response).map_err(UserRepoError::unexpected)?;
+        self.idempotency_keys
+            .update_one(
+                doc! { "_id": key },
+                doc! { "$set": {
+                    "response": response,
+                    "expires_at": expires_at as i64,
+                    "expire_after": DateTime::from_millis(expires_at as i64),
+                } },
+            )
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        self.idempotency_keys
+            .delete_one(doc! { "_id": key })
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        self.db
+            .run_command(doc! { "ping": 1 })
+            .await
+            .map_err(map_mongo_err)?;
+
+        Ok(())
+    }
+
+    /// Waits for cursors and sessions in use to be dropped, then closes the client's
+    /// connections.
+    async fn close(&self) -> Result<(), UserRepoError> {
+        self.db.client().clone().shutdown().await;
+        Ok(())
+    }
 }
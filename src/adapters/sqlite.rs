@@ -1,6 +1,14 @@
-use sqlx::{FromRow, SqlitePool};
+use sqlx::migrate::{MigrateDatabase, MigrateError, Migrator};
+use sqlx::query::QueryAs;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteConnection};
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool, Transaction};
+use std::path::Path;
+use thiserror::Error;
 use uuid::Uuid;
-use crate::users::{User, UserRepo, UserRepoError};
+use crate::events::RecordedEvent;
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::users::{ConflictField, Cursor, RecordEvents, Role, User, UserPage, UserQuery, UserRepo, UserRepoError, UserSort};
+use crate::webhooks::{Delivery, DeliveryStatus, Webhook};
 
 pub struct SqliteUserRepo {
     pool: SqlitePool,
@@ -11,58 +19,345 @@ struct SqlxUserRow {
     id: String,
     username: String,
     email: String,
+    /// JSON array of role names.
+    roles: String,
+    active: bool,
 }
 
 impl SqlxUserRow {
     fn try_into_user(self) -> Result<User, UserRepoError> {
         let id = Uuid::parse_str(&self.id).map_err(|e| UserRepoError::Unexpected(Box::new(e)))?;
+        let names: Vec<String> = serde_json::from_str(&self.roles).map_err(UserRepoError::unexpected)?;
+        let roles = Role::parse_all(&names).map_err(UserRepoError::unexpected)?;
 
         Ok(User {
             id,
             username: self.username,
             email: self.email,
+            roles,
+            active: self.active,
+        })
+    }
+}
+
+#[derive(FromRow, Debug)]
+struct SqlxOutboxRow {
+    id: String,
+    recorded_at: i64,
+    /// The [`UserEvent`](crate::events::UserEvent) as JSON.
+    payload: String,
+}
+
+impl SqlxOutboxRow {
+    fn try_into_event(self) -> Result<RecordedEvent, UserRepoError> {
+        Ok(RecordedEvent {
+            id: Uuid::parse_str(&self.id).map_err(UserRepoError::unexpected)?,
+            recorded_at: self.recorded_at as u64,
+            event: serde_json::from_str(&self.payload).map_err(UserRepoError::unexpected)?,
+        })
+    }
+}
+
+#[derive(FromRow, Debug)]
+struct SqlxWebhookRow {
+    id: String,
+    url: String,
+    secret: String,
+    /// JSON array of event types.
+    event_types: String,
+    created_at: i64,
+}
+
+impl SqlxWebhookRow {
+    fn try_into_webhook(self) -> Result<Webhook, UserRepoError> {
+        Ok(Webhook {
+            id: Uuid::parse_str(&self.id).map_err(UserRepoError::unexpected)?,
+            url: self.url,
+            secret: self.secret,
+            event_types: serde_json::from_str(&self.event_types).map_err(UserRepoError::unexpected)?,
+            created_at: self.created_at as u64,
+        })
+    }
+}
+
+#[derive(FromRow, Debug)]
+struct SqlxDeliveryRow {
+    id: String,
+    webhook_id: String,
+    event_id: String,
+    event_type: String,
+    payload: String,
+    status: String,
+    attempts: i64,
+    next_attempt_at: Option<i64>,
+    last_attempt_at: Option<i64>,
+    last_status_code: Option<i64>,
+    last_error: Option<String>,
+    created_at: i64,
+}
+
+impl SqlxDeliveryRow {
+    fn try_into_delivery(self) -> Result<Delivery, UserRepoError> {
+        Ok(Delivery {
+            id: Uuid::parse_str(&self.id).map_err(UserRepoError::unexpected)?,
+            webhook_id: Uuid::parse_str(&self.webhook_id).map_err(UserRepoError::unexpected)?,
+            event_id: Uuid::parse_str(&self.event_id).map_err(UserRepoError::unexpected)?,
+            event_type: self.event_type,
+            payload: self.payload,
+            status: self.status.parse().map_err(UserRepoError::unexpected)?,
+            attempts: self.attempts as u32,
+            next_attempt_at: self.next_attempt_at.map(|at| at as u64),
+            last_attempt_at: self.last_attempt_at.map(|at| at as u64),
+            last_status_code: self.last_status_code.map(|code| code as u16),
+            last_error: self.last_error,
+            created_at: self.created_at as u64,
+        })
+    }
+}
+
+const DELIVERY_COLUMNS: &str = "id, webhook_id, event_id, event_type, payload, status, attempts, next_attempt_at, \
+    last_attempt_at, last_status_code, last_error, created_at";
+
+#[derive(FromRow, Debug)]
+struct SqlxIdempotencyRow {
+    key: String,
+    fingerprint: String,
+    response: Option<String>,
+    expires_at: i64,
+}
+
+impl SqlxIdempotencyRow {
+    fn try_into_record(self) -> Result<IdempotencyRecord, UserRepoError> {
+        Ok(IdempotencyRecord {
+            key: self.key,
+            fingerprint: self.fingerprint,
+            response: self
+                .response
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .map_err(UserRepoError::unexpected)?,
+            expires_at: self.expires_at as u64,
+        })
+    }
+}
+
+/// A user row plus its `seq`, which backs [`UserSort::Created`].
+#[derive(FromRow, Debug)]
+struct SqlxUserPageRow {
+    seq: i64,
+    #[sqlx(flatten)]
+    user: SqlxUserRow,
+}
+
+impl SqlxUserPageRow {
+    fn cursor(&self, sort: UserSort) -> Result<Cursor, UserRepoError> {
+        let key = match sort {
+            UserSort::Created => self.seq.to_string(),
+            UserSort::Username => self.user.username.clone(),
+            UserSort::Email => self.user.email.clone(),
+        };
+
+        Ok(Cursor {
+            sort,
+            id: Uuid::parse_str(&self.user.id).map_err(|e| UserRepoError::Unexpected(Box::new(e)))?,
+            key,
         })
     }
 }
 
 impl SqliteUserRepo {
+    /// Opens (creating if needed) the database file at `path` and migrates it.
+    pub async fn open(path: &Path) -> Result<Self, UserRepoError> {
+        Self::new(connect(path).await?).await
+    }
+
+    /// Wraps `pool`, first bringing its schema up to date with [`migrate`].
     pub async fn new(pool: SqlitePool) -> Result<Self, UserRepoError> {
-        // Minimal schema init. Consider moving to migrations.
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS users (
-                id       TEXT PRIMARY KEY,
-                username TEXT NOT NULL,
-                email    TEXT NOT NULL
-            )
-            "#,
-        )
-        .execute(&pool)
-        .await
-        .map_err(map_sqlx_err)?;
+        migrate(&pool).await?;
 
         Ok(Self { pool })
     }
 }
 
+/// Schema migrations from `migrations/`, embedded at compile time and recorded in
+/// the `_sqlx_migrations` table.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("database schema version {found} is newer than the latest known version {known}; refusing to use it")]
+    TooNew { found: i64, known: i64 },
+
+    #[error(transparent)]
+    Migrate(#[from] MigrateError),
+}
+
+/// Schema versions before and after [`migrate`]; `None` for a database that was never migrated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from: Option<i64>,
+    pub to: i64,
+}
+
+/// Opens (creating if needed) the database file at `path` without touching its schema.
+pub async fn connect(path: &Path) -> Result<SqlitePool, UserRepoError> {
+    let url = format!("sqlite:{}", path.display());
+
+    Sqlite::create_database(&url).await.map_err(map_sqlx_err)?;
+
+    let options = SqliteConnectOptions::new().filename(path);
+    SqlitePool::connect_with(options).await.map_err(map_sqlx_err)
+}
+
+/// The newest schema version this build knows how to produce.
+pub fn latest_schema_version() -> i64 {
+    MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+/// The newest migration recorded as applied, if any.
+pub async fn schema_version(pool: &SqlitePool) -> Result<Option<i64>, UserRepoError> {
+    let tracked: Option<String> = sqlx::query_scalar(
+        r#"SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'"#,
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(map_sqlx_err)?;
+
+    if tracked.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query_scalar(r#"SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1"#)
+        .fetch_one(pool)
+        .await
+        .map_err(map_sqlx_err)
+}
+
+/// Applies pending migrations. Fails without changing anything if the database was
+/// migrated by a newer build, so an old binary cannot corrupt a newer schema.
+pub async fn migrate(pool: &SqlitePool) -> Result<MigrationReport, UserRepoError> {
+    let from = schema_version(pool).await?;
+    let known = latest_schema_version();
+
+    if let Some(found) = from.filter(|found| *found > known) {
+        return Err(UserRepoError::unexpected(SchemaError::TooNew { found, known }));
+    }
+
+    MIGRATOR
+        .run(pool)
+        .await
+        .map_err(|e| UserRepoError::unexpected(SchemaError::from(e)))?;
+
+    if from != Some(known) {
+        log::info!(target: "Users", "Migrated SQLite schema from version {from:?} to {known}");
+    }
+
+    Ok(MigrationReport { from, to: known })
+}
+
+impl SqliteUserRepo {
+    /// Starts a transaction holding the write lock from the start, so reading a user before
+    /// changing it cannot fail halfway with `SQLITE_BUSY`.
+    async fn begin(&self) -> Result<Transaction<'static, Sqlite>, UserRepoError> {
+        self.pool.begin_with("BEGIN IMMEDIATE").await.map_err(map_sqlx_err)
+    }
+
+    /// Runs `update`, a statement changing user `id` and returning the changed row, in a
+    /// transaction that also records the events for the change.
+    async fn change<'q>(
+        &self,
+        id: Uuid,
+        events: RecordEvents<'_>,
+        update: QueryAs<'q, Sqlite, SqlxUserRow, SqliteArguments<'q>>,
+        map_err: impl FnOnce(sqlx::Error) -> UserRepoError,
+    ) -> Result<Option<User>, UserRepoError> {
+        let mut tx = self.begin().await?;
+
+        let Some(before) = fetch_user(&mut tx, id).await? else {
+            tx.rollback().await.map_err(map_sqlx_err)?;
+            return Ok(None);
+        };
+        let after = update
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(map_err)?
+            .try_into_user()?;
+
+        record(&mut tx, events, Some(&before), Some(&after)).await?;
+        tx.commit().await.map_err(map_sqlx_err)?;
+
+        Ok(Some(after))
+    }
+}
+
+async fn fetch_user(conn: &mut SqliteConnection, id: Uuid) -> Result<Option<User>, UserRepoError> {
+    let row = sqlx::query_as::<_, SqlxUserRow>(
+        r#"SELECT id, username, email, roles, active FROM users WHERE id = ?"#,
+    )
+    .bind(id.to_string())
+    .fetch_optional(conn)
+    .await
+    .map_err(map_sqlx_err)?;
+
+    row.map(SqlxUserRow::try_into_user).transpose()
+}
+
+/// Adds the events `events` derives for a change to the outbox, on the change's connection.
+async fn record(
+    conn: &mut SqliteConnection,
+    events: RecordEvents<'_>,
+    before: Option<&User>,
+    after: Option<&User>,
+) -> Result<(), UserRepoError> {
+    for event in events(before, after) {
+        let recorded = RecordedEvent::new(event);
+        let payload = serde_json::to_string(&recorded.event).map_err(UserRepoError::unexpected)?;
+
+        sqlx::query(r#"INSERT INTO outbox (id, recorded_at, payload) VALUES (?, ?, ?)"#)
+            .bind(recorded.id.to_string())
+            .bind(recorded.recorded_at as i64)
+            .bind(payload)
+            .execute(&mut *conn)
+            .await
+            .map_err(map_sqlx_err)?;
+    }
+
+    Ok(())
+}
+
 #[async_trait::async_trait]
 impl UserRepo for SqliteUserRepo {
-    async fn add_user(&self, username: &str, email: &str) -> Result<User, UserRepoError> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
         log::debug!(target: "Users", "Adding user: {username}");
 
         let user = User {
             id: Uuid::new_v4(),
             username: username.to_owned(),
             email: email.to_owned(),
+            roles: Vec::new(),
+            active: true,
         };
 
-        sqlx::query(r#"INSERT INTO users (id, username, email) VALUES (?, ?, ?)"#)
+        let mut tx = self.begin().await?;
+
+        sqlx::query(r#"INSERT INTO users (id, username, email, password_hash) VALUES (?, ?, ?, ?)"#)
             .bind(user.id.to_string())
             .bind(&user.username)
             .bind(&user.email)
-            .execute(&self.pool)
+            .bind(password_hash)
+            .execute(&mut *tx)
             .await
-            .map_err(map_sqlx_err)?;
+            .map_err(|e| map_write_err(e, username, email))?;
+
+        record(&mut tx, events, None, Some(&user)).await?;
+        tx.commit().await.map_err(map_sqlx_err)?;
 
         Ok(user)
     }
@@ -70,10 +365,140 @@ impl UserRepo for SqliteUserRepo {
     async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
         log::debug!(target: "Users", "Getting user: {id}");
 
+        let mut conn = self.pool.acquire().await.map_err(map_sqlx_err)?;
+        fetch_user(&mut conn, id).await
+    }
+
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Updating user: {id}");
+
+        let update = sqlx::query_as(
+            r#"UPDATE users SET username = ?, email = ? WHERE id = ? RETURNING id, username, email, roles, active"#,
+        )
+        .bind(username)
+        .bind(email)
+        .bind(id.to_string());
+
+        self.change(id, events, update, |e| map_write_err(e, username, email)).await
+    }
+
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Removing user: {id}");
+
+        let mut tx = self.begin().await?;
+
+        let Some(user) = fetch_user(&mut tx, id).await? else {
+            tx.rollback().await.map_err(map_sqlx_err)?;
+            return Ok(None);
+        };
+
+        sqlx::query(r#"DELETE FROM users WHERE id = ?"#)
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        record(&mut tx, events, Some(&user), None).await?;
+        tx.commit().await.map_err(map_sqlx_err)?;
+
+        Ok(Some(user))
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
+        log::debug!(target: "Users", "Listing users");
+
+        let rows = sqlx::query_as::<_, SqlxUserRow>(r#"SELECT id, username, email, roles, active FROM users"#)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        rows.into_iter().map(SqlxUserRow::try_into_user).collect()
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        log::debug!(target: "Users", "Querying users: {query:?}");
+
+        let mut sql = QueryBuilder::<Sqlite>::new("SELECT seq, id, username, email, roles, active FROM users WHERE 1 = 1");
+        push_filters(&mut sql, query);
+
+        let sort_column = match query.sort {
+            UserSort::Created => "seq",
+            UserSort::Username => "username",
+            UserSort::Email => "email",
+        };
+
+        if let Some(cursor) = query.cursor()? {
+            match query.sort {
+                UserSort::Created => {
+                    let seq: i64 = cursor.key.parse().map_err(|_| UserRepoError::InvalidCursor)?;
+                    sql.push(" AND seq > ").push_bind(seq);
+                }
+                UserSort::Username | UserSort::Email => {
+                    sql.push(format_args!(" AND ({sort_column} > "))
+                        .push_bind(&cursor.key)
+                        .push(format_args!(" OR ({sort_column} = "))
+                        .push_bind(&cursor.key)
+                        .push(" AND id > ")
+                        .push_bind(cursor.id.to_string())
+                        .push("))");
+                }
+            }
+        }
+
+        // Fetch one extra row to learn whether there is a next page.
+        let page_size = query.page_size();
+        sql.push(format_args!(" ORDER BY {sort_column}, id LIMIT "))
+            .push_bind(page_size as i64 + 1);
+
+        let mut rows = sql
+            .build_query_as::<SqlxUserPageRow>()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        let next_cursor = if rows.len() > page_size {
+            rows.truncate(page_size);
+            rows.last().map(|row| row.cursor(query.sort)).transpose()?
+        } else {
+            None
+        };
+
+        let users = rows
+            .into_iter()
+            .map(|row| row.user.try_into_user())
+            .collect::<Result<_, _>>()?;
+
+        Ok(UserPage { users, next_cursor })
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        log::debug!(target: "Users", "Counting users: {query:?}");
+
+        let mut sql = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM users WHERE 1 = 1");
+        push_filters(&mut sql, query);
+
+        let count: i64 = sql
+            .build_query_scalar()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+        Ok(count as u64)
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Finding user by login: {login}");
+
         let row = sqlx::query_as::<_, SqlxUserRow>(
-            r#"SELECT id, username, email FROM users WHERE id = ?"#,
+            r#"SELECT id, username, email, roles, active FROM users WHERE username = ? OR email = ?"#,
         )
-        .bind(id.to_string())
+        .bind(login)
+        .bind(login)
         .fetch_optional(&self.pool)
         .await
         .map_err(map_sqlx_err)?;
@@ -81,47 +506,350 @@ impl UserRepo for SqliteUserRepo {
         row.map(SqlxUserRow::try_into_user).transpose()
     }
 
-    async fn remove_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
-        log::debug!(target: "Users", "Removing user: {id}");
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        log::debug!(target: "Users", "Getting password hash: {id}");
 
-        let mut tx = self.pool.begin().await.map_err(map_sqlx_err)?;
+        let hash: Option<Option<String>> = sqlx::query_scalar(r#"SELECT password_hash FROM users WHERE id = ?"#)
+            .bind(id.to_string())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
 
-        let row = sqlx::query_as::<_, SqlxUserRow>(
-            r#"SELECT id, username, email FROM users WHERE id = ?"#,
+        Ok(hash.flatten())
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        log::debug!(target: "Users", "Setting password hash: {id}");
+
+        let update = sqlx::query_as(
+            r#"UPDATE users SET password_hash = ? WHERE id = ? RETURNING id, username, email, roles, active"#,
+        )
+        .bind(hash)
+        .bind(id.to_string());
+
+        Ok(self.change(id, events, update, map_sqlx_err).await?.is_some())
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Granting role {role} to user: {id}");
+
+        // A single statement, so concurrent grants and revokes cannot lose each other's changes.
+        let update = sqlx::query_as(
+            r#"UPDATE users SET roles = (
+                   SELECT json_group_array(role) FROM (
+                       SELECT value AS role FROM json_each(users.roles) UNION SELECT ? ORDER BY role
+                   )
+               )
+               WHERE id = ?
+               RETURNING id, username, email, roles, active"#,
+        )
+        .bind(role.as_str())
+        .bind(id.to_string());
+
+        self.change(id, events, update, map_sqlx_err).await
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Revoking role {role} from user: {id}");
+
+        let update = sqlx::query_as(
+            r#"UPDATE users SET roles = (
+                   SELECT json_group_array(value) FROM json_each(users.roles) WHERE value <> ?
+               )
+               WHERE id = ?
+               RETURNING id, username, email, roles, active"#,
+        )
+        .bind(role.as_str())
+        .bind(id.to_string());
+
+        self.change(id, events, update, map_sqlx_err).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        log::debug!(target: "Users", "Setting user {id} active: {active}");
+
+        let update = sqlx::query_as(
+            r#"UPDATE users SET active = ? WHERE id = ? RETURNING id, username, email, roles, active"#,
+        )
+        .bind(active)
+        .bind(id.to_string());
+
+        self.change(id, events, update, map_sqlx_err).await
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        let rows = sqlx::query_as::<_, SqlxOutboxRow>(
+            r#"SELECT id, recorded_at, payload FROM outbox ORDER BY seq LIMIT ?"#,
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        rows.into_iter().map(SqlxOutboxRow::try_into_event).collect()
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut sql = QueryBuilder::<Sqlite>::new("DELETE FROM outbox WHERE id IN (");
+        let mut list = sql.separated(", ");
+        for id in ids {
+            list.push_bind(id.to_string());
+        }
+        sql.push(")").build().execute(&self.pool).await.map_err(map_sqlx_err)?;
+
+        Ok(())
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        log::debug!(target: "Users", "Adding webhook: {}", webhook.id);
+
+        let event_types = serde_json::to_string(&webhook.event_types).map_err(UserRepoError::unexpected)?;
+        sqlx::query(r#"INSERT INTO webhooks (id, url, secret, event_types, created_at) VALUES (?, ?, ?, ?, ?)"#)
+            .bind(webhook.id.to_string())
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(event_types)
+            .bind(webhook.created_at as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        Ok(())
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        let row = sqlx::query_as::<_, SqlxWebhookRow>(
+            r#"SELECT id, url, secret, event_types, created_at FROM webhooks WHERE id = ?"#,
         )
         .bind(id.to_string())
-        .fetch_optional(&mut *tx)
+        .fetch_optional(&self.pool)
         .await
         .map_err(map_sqlx_err)?;
 
-        let user = match row {
-            None => {
-                tx.rollback().await.map_err(map_sqlx_err)?;
-                return Ok(None);
-            }
-            Some(row) => row.try_into_user()?,
-        };
+        row.map(SqlxWebhookRow::try_into_webhook).transpose()
+    }
 
-        sqlx::query(r#"DELETE FROM users WHERE id = ?"#)
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        let rows = sqlx::query_as::<_, SqlxWebhookRow>(
+            r#"SELECT id, url, secret, event_types, created_at FROM webhooks ORDER BY seq"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        rows.into_iter().map(SqlxWebhookRow::try_into_webhook).collect()
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        log::debug!(target: "Users", "Removing webhook: {id}");
+
+        // Its deliveries go with it, by ON DELETE CASCADE.
+        let row = sqlx::query_as::<_, SqlxWebhookRow>(
+            r#"DELETE FROM webhooks WHERE id = ? RETURNING id, url, secret, event_types, created_at"#,
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        row.map(SqlxWebhookRow::try_into_webhook).transpose()
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        // Skips deliveries to webhooks removed meanwhile, where the foreign key would fail the
+        // whole batch; OR IGNORE only covers the unique constraints.
+        let insert = format!(
+            "INSERT OR IGNORE INTO webhook_deliveries ({DELIVERY_COLUMNS})
+             SELECT ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ? WHERE EXISTS (SELECT 1 FROM webhooks WHERE id = ?)"
+        );
+
+        let mut tx = self.begin().await?;
+        for delivery in deliveries {
+            sqlx::query(&insert)
+                .bind(delivery.id.to_string())
+                .bind(delivery.webhook_id.to_string())
+                .bind(delivery.event_id.to_string())
+                .bind(&delivery.event_type)
+                .bind(&delivery.payload)
+                .bind(delivery.status.as_str())
+                .bind(delivery.attempts as i64)
+                .bind(delivery.next_attempt_at.map(|at| at as i64))
+                .bind(delivery.last_attempt_at.map(|at| at as i64))
+                .bind(delivery.last_status_code.map(i64::from))
+                .bind(&delivery.last_error)
+                .bind(delivery.created_at as i64)
+                .bind(delivery.webhook_id.to_string())
+                .execute(&mut *tx)
+                .await
+                .map_err(map_sqlx_err)?;
+        }
+        tx.commit().await.map_err(map_sqlx_err)?;
+
+        Ok(())
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        let row = sqlx::query_as::<_, SqlxDeliveryRow>(&format!("SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE id = ?"))
             .bind(id.to_string())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        row.map(SqlxDeliveryRow::try_into_delivery).transpose()
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        let result = sqlx::query(
+            r#"UPDATE webhook_deliveries
+               SET status = ?, attempts = ?, next_attempt_at = ?, last_attempt_at = ?, last_status_code = ?, last_error = ?
+               WHERE id = ?"#,
+        )
+        .bind(delivery.status.as_str())
+        .bind(delivery.attempts as i64)
+        .bind(delivery.next_attempt_at.map(|at| at as i64))
+        .bind(delivery.last_attempt_at.map(|at| at as i64))
+        .bind(delivery.last_status_code.map(i64::from))
+        .bind(&delivery.last_error)
+        .bind(delivery.id.to_string())
+        .execute(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let rows = sqlx::query_as::<_, SqlxDeliveryRow>(&format!(
+            "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries
+             WHERE status = ? AND next_attempt_at <= ?
+             ORDER BY next_attempt_at, seq LIMIT ?"
+        ))
+        .bind(DeliveryStatus::Pending.as_str())
+        .bind(now as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        rows.into_iter().map(SqlxDeliveryRow::try_into_delivery).collect()
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        let rows = sqlx::query_as::<_, SqlxDeliveryRow>(&format!(
+            "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE webhook_id = ? ORDER BY seq DESC LIMIT ?"
+        ))
+        .bind(webhook_id.to_string())
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(map_sqlx_err)?;
+
+        rows.into_iter().map(SqlxDeliveryRow::try_into_delivery).collect()
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        let mut tx = self.begin().await?;
+        sqlx::query("DELETE FROM idempotency_keys WHERE expires_at <= ?")
+            .bind(now as i64)
             .execute(&mut *tx)
             .await
             .map_err(map_sqlx_err)?;
 
+        let claimed = sqlx::query("INSERT OR IGNORE INTO idempotency_keys (key, fingerprint, response, expires_at) VALUES (?, ?, NULL, ?)")
+            .bind(&record.key)
+            .bind(&record.fingerprint)
+            .bind(record.expires_at as i64)
+            .execute(&mut *tx)
+            .await
+            .map_err(map_sqlx_err)?
+            .rows_affected()
+            == 1;
+
+        let existing = match claimed {
+            true => None,
+            false => Some(
+                sqlx::query_as::<_, SqlxIdempotencyRow>("SELECT key, fingerprint, response, expires_at FROM idempotency_keys WHERE key = ?")
+                    .bind(&record.key)
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(map_sqlx_err)?
+                    .try_into_record()?,
+            ),
+        };
         tx.commit().await.map_err(map_sqlx_err)?;
+        Ok(existing)
+    }
 
-        Ok(Some(user))
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        let response = serde_json::to_string(response).map_err(UserRepoError::unexpected)?;
+        sqlx::query("UPDATE idempotency_keys SET response = ?, expires_at = ? WHERE key = ?")
+            .bind(response)
+            .bind(expires_at as i64)
+            .bind(key)
+            .execute(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
+
+        Ok(())
     }
 
-    async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
-        log::debug!(target: "Users", "Listing users");
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .await
+            .map_err(map_sqlx_err)?;
 
-        let rows = sqlx::query_as::<_, SqlxUserRow>(r#"SELECT id, username, email FROM users"#)
-            .fetch_all(&self.pool)
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
             .await
             .map_err(map_sqlx_err)?;
 
-        rows.into_iter().map(SqlxUserRow::try_into_user).collect()
+        Ok(())
+    }
+
+    /// Moves everything in the write-ahead log into the database file and truncates the log,
+    /// so the file is complete on its own, then closes the pool.
+    async fn close(&self) -> Result<(), UserRepoError> {
+        let checkpoint = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&self.pool).await;
+        self.pool.close().await;
+        checkpoint.map_err(map_sqlx_err)?;
+
+        Ok(())
+    }
+}
+
+/// Appends `query`'s filters to a statement that already has a `WHERE` clause.
+fn push_filters<'q>(sql: &mut QueryBuilder<'q, Sqlite>, query: &'q UserQuery) {
+    if let Some(prefix) = &query.username_prefix {
+        // substr keeps the match case-sensitive, unlike LIKE.
+        sql.push(" AND substr(username, 1, length(")
+            .push_bind(prefix)
+            .push(")) = ")
+            .push_bind(prefix);
+    }
+
+    if let Some(domain) = &query.email_domain {
+        sql.push(" AND lower(substr(email, instr(email, '@') + 1)) = lower(")
+            .push_bind(domain)
+            .push(")");
     }
 }
 
@@ -134,3 +862,28 @@ fn map_sqlx_err(e: sqlx::Error) -> UserRepoError {
         other => UserRepoError::Unexpected(Box::new(other)),
     }
 }
+
+/// Like [`map_sqlx_err`], but turns unique constraint violations
+/// (`SQLITE_CONSTRAINT_UNIQUE`) into [`UserRepoError::Conflict`].
+fn map_write_err(e: sqlx::Error, username: &str, email: &str) -> UserRepoError {
+    if let sqlx::Error::Database(db_err) = &e
+        && db_err.is_unique_violation()
+    {
+        // SQLite reports the offending columns, e.g. "UNIQUE constraint failed: users.email".
+        let message = db_err.message();
+        if message.contains("users.username") {
+            return UserRepoError::Conflict {
+                field: ConflictField::Username,
+                value: username.to_owned(),
+            };
+        }
+        if message.contains("users.email") {
+            return UserRepoError::Conflict {
+                field: ConflictField::Email,
+                value: email.to_owned(),
+            };
+        }
+    }
+
+    map_sqlx_err(e)
+}
@@ -0,0 +1,150 @@
+use super::dto::{LoginDto, RefreshDto, TokenDto, UserDto};
+use super::error::{ApiError, ProblemDetails};
+use crate::app::Application;
+use crate::events::now_millis;
+use crate::policy::Caller;
+use crate::tokens::{TokenError, TokenKind, Tokens};
+use crate::users::{User, UserRepo};
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::{Data, Json};
+use actix_web::{FromRequest, HttpRequest};
+use log::info;
+use std::future::{ready, Ready};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The user a request's `Authorization: Bearer` access token was issued to.
+///
+/// Declaring it as a handler argument makes the route require authentication; requests
+/// without a valid token get a 401 problem response. Needs `Data<Tokens>` in app data.
+#[derive(Debug, Clone, Copy)]
+pub struct Authenticated {
+    pub user_id: Uuid,
+    /// When the access token expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl Authenticated {
+    /// The caller with their current roles, for the application's access policy. Fails with
+    /// 401 if the user was deleted or deactivated after the token was issued.
+    pub async fn caller<R: UserRepo>(&self, app: &Application<R>) -> Result<Caller, ApiError> {
+        Ok(app.caller(self.user_id).await?)
+    }
+
+    /// How long the access token stays valid; responses streamed with it end by then.
+    pub fn expires_in(&self) -> Duration {
+        Duration::from_millis((self.expires_at * 1000).saturating_sub(now_millis()))
+    }
+}
+
+impl FromRequest for Authenticated {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+pub(super) fn authenticate(req: &HttpRequest) -> Result<Authenticated, ApiError> {
+    let Some(tokens) = req.app_data::<Data<Tokens>>() else {
+        log::error!("Data<Tokens> is not registered; cannot authenticate requests");
+        return Err(ApiError::Internal);
+    };
+
+    // Auth schemes are case-insensitive (RFC 7235), so `bearer` is as good as `Bearer`.
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token)
+        .ok_or_else(|| ApiError::Unauthorized("missing bearer token".to_owned()))?;
+
+    let claims = tokens.validate(token.trim(), TokenKind::Access)?;
+    Ok(Authenticated {
+        user_id: claims.sub,
+        expires_at: claims.exp,
+    })
+}
+
+impl From<TokenError> for ApiError {
+    fn from(e: TokenError) -> Self {
+        ApiError::Unauthorized(e.to_string())
+    }
+}
+
+fn token_pair(tokens: &Tokens, user: User) -> TokenDto {
+    let access = tokens.issue(user.id, TokenKind::Access);
+    let refresh = tokens.issue(user.id, TokenKind::Refresh);
+
+    TokenDto {
+        access_token: access.token,
+        token_type: "Bearer",
+        expires_in: access.expires_in,
+        refresh_token: refresh.token,
+        refresh_expires_in: refresh.expires_in,
+        user: UserDto::from(user),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    request_body = LoginDto,
+    responses(
+        (status = 200, description = "Credentials are valid; here are tokens for the user", body = TokenDto),
+        (status = 400, description = "Malformed body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Unknown login, wrong password or deactivated account", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"),
+                ("RateLimit-Limit" = u32, description = "Requests allowed in a burst"),
+                ("RateLimit-Remaining" = u32, description = "Requests left"),
+                ("RateLimit-Reset" = u64, description = "Seconds until the quota is fully restored"))),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn login<R: UserRepo>(
+    data: Data<Application<R>>,
+    tokens: Data<Tokens>,
+    dto: Json<LoginDto>,
+) -> Result<Json<TokenDto>, ApiError> {
+    info!("Login attempt: {}", dto.login.trim());
+
+    let user = data.authenticate(&dto.login, &dto.password).await?;
+    Ok(Json(token_pair(&tokens, user)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/refresh",
+    tag = "auth",
+    request_body = RefreshDto,
+    responses(
+        (status = 200, description = "A new token pair", body = TokenDto),
+        (status = 400, description = "Malformed body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Refresh token invalid or expired, or its user is gone or deactivated", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn refresh<R: UserRepo>(
+    data: Data<Application<R>>,
+    tokens: Data<Tokens>,
+    dto: Json<RefreshDto>,
+) -> Result<Json<TokenDto>, ApiError> {
+    let claims = tokens.validate(&dto.refresh_token, TokenKind::Refresh)?;
+    info!("Refreshing tokens of user: {}", claims.sub);
+
+    // Only the user's current state is checked: nothing records revoked tokens, so refresh
+    // tokens issued before a password change keep working until they expire.
+    let user = data.active_user(claims.sub).await?;
+
+    Ok(Json(token_pair(&tokens, user)))
+}
@@ -0,0 +1,459 @@
+use super::error::ApiError;
+use crate::events::RecordedEvent;
+use crate::users::{Cursor, Role, User, UserPage, UserQuery, UserSort};
+use crate::validation::FieldError;
+use crate::webhooks::{Delivery, DeliveryStatus, Webhook};
+use serde_json::Value;
+
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct UserDto {
+    /// Unique identifier for the user
+    /// example = "550e8400-e29b-41d4-a716-446655440000"
+    /// format = "uuid"
+    pub id: String,
+
+    /// Username of the user
+    /// example = "johndoe"
+    pub username: String,
+
+    /// Email address of the user
+    /// example = "johndoe@example.com"
+    pub email: String,
+
+    /// Roles beyond an ordinary user's; empty for most users
+    pub roles: Vec<RoleDto>,
+
+    /// `false` once deactivated: the user can no longer log in
+    pub active: bool,
+}
+
+/// `admin` may do anything to any user; `auditor` may list and read any user.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RoleDto {
+    Admin,
+    Auditor,
+}
+
+impl From<Role> for RoleDto {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Admin => RoleDto::Admin,
+            Role::Auditor => RoleDto::Auditor,
+        }
+    }
+}
+
+impl From<RoleDto> for Role {
+    fn from(role: RoleDto) -> Self {
+        match role {
+            RoleDto::Admin => Role::Admin,
+            RoleDto::Auditor => Role::Auditor,
+        }
+    }
+}
+
+/// One page of users.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct UserPageDto {
+    pub items: Vec<UserDto>,
+
+    /// Pass as `cursor` to fetch the next page; absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl From<UserPage> for UserPageDto {
+    fn from(page: UserPage) -> Self {
+        Self {
+            items: page.users.into_iter().map(UserDto::from).collect(),
+            next_cursor: page.next_cursor.as_ref().map(Cursor::encode),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDto {
+    #[default]
+    Created,
+    Username,
+    Email,
+}
+
+impl From<SortDto> for UserSort {
+    fn from(sort: SortDto) -> Self {
+        match sort {
+            SortDto::Created => UserSort::Created,
+            SortDto::Username => UserSort::Username,
+            SortDto::Email => UserSort::Email,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersParams {
+    /// Page size (1-500, default 50)
+    pub limit: Option<usize>,
+
+    /// Opaque cursor from a previous page's `next_cursor`
+    pub cursor: Option<String>,
+
+    /// Sort order; a cursor is only valid with the sort it was issued for
+    pub sort: Option<SortDto>,
+
+    /// Only users whose username starts with this prefix (case-sensitive)
+    pub username_prefix: Option<String>,
+
+    /// Only users with an email in this domain, e.g. `example.com`
+    pub email_domain: Option<String>,
+}
+
+impl TryFrom<ListUsersParams> for UserQuery {
+    type Error = ApiError;
+
+    fn try_from(params: ListUsersParams) -> Result<Self, Self::Error> {
+        Ok(UserQuery {
+            limit: params.limit.unwrap_or(UserQuery::DEFAULT_LIMIT),
+            cursor: params.cursor.as_deref().map(Cursor::decode).transpose()?,
+            sort: params.sort.unwrap_or_default().into(),
+            username_prefix: params.username_prefix,
+            email_domain: params.email_domain,
+        })
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct CreateUserDto {
+    /// 3-32 letters, digits, `_`, `.` or `-`; surrounding whitespace is trimmed
+    #[schema(min_length = 3, max_length = 32, pattern = "^[A-Za-z0-9_.-]+$", example = "johndoe")]
+    pub username: String,
+
+    /// Surrounding whitespace is trimmed and the address is lower-cased
+    #[schema(format = Email, max_length = 254, example = "johndoe@example.com")]
+    pub email: String,
+
+    /// Optional password for logging in; must meet the password policy
+    /// (12-128 characters by default, not containing the username or email)
+    #[schema(write_only, format = Password)]
+    pub password: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct UpdateUserDto {
+    /// 3-32 letters, digits, `_`, `.` or `-`; surrounding whitespace is trimmed
+    #[schema(min_length = 3, max_length = 32, pattern = "^[A-Za-z0-9_.-]+$", example = "johndoe")]
+    pub username: String,
+
+    /// Surrounding whitespace is trimmed and the address is lower-cased
+    #[schema(format = Email, max_length = 254, example = "johndoe@example.com")]
+    pub email: String,
+}
+
+/// JSON Merge Patch (RFC 7386) document for a user.
+/// Omitted fields are left untouched; `null` is rejected because every field is required.
+#[derive(utoipa::ToSchema)]
+pub struct PatchUserDto {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct ChangePasswordDto {
+    /// Required if the user already has a password
+    #[schema(format = Password)]
+    pub current_password: Option<String>,
+
+    /// Must meet the password policy
+    #[schema(format = Password)]
+    pub new_password: String,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginDto {
+    /// Username or email address
+    #[schema(example = "johndoe")]
+    pub login: String,
+
+    #[schema(format = Password)]
+    pub password: String,
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct RefreshDto {
+    /// `refresh_token` from a previous login or refresh
+    pub refresh_token: String,
+}
+
+/// Tokens for the authenticated `user`. Send the access token as `Authorization: Bearer`
+/// and exchange the refresh token at `/auth/refresh` before it expires.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct TokenDto {
+    pub access_token: String,
+
+    /// Always `Bearer`
+    pub token_type: &'static str,
+
+    /// Seconds until the access token expires
+    pub expires_in: u64,
+
+    pub refresh_token: String,
+
+    /// Seconds until the refresh token expires
+    pub refresh_expires_in: u64,
+
+    pub user: UserDto,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct FieldErrorDto {
+    /// example = "username"
+    pub field: &'static str,
+
+    /// Stable reason such as `required`, `too_short`, `too_long`, `invalid_chars`, `invalid_format`
+    /// or `contains_identity`
+    pub code: &'static str,
+
+    /// Human-readable explanation
+    pub message: String,
+}
+
+impl From<&FieldError> for FieldErrorDto {
+    fn from(error: &FieldError) -> Self {
+        Self {
+            field: error.field,
+            code: error.code,
+            message: error.message.clone(),
+        }
+    }
+}
+
+impl From<User> for UserDto {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id.to_string(),
+            username: user.username,
+            email: user.email,
+            roles: user.roles.into_iter().map(RoleDto::from).collect(),
+            active: user.active,
+        }
+    }
+}
+
+
+/// A kind of user event a webhook can subscribe to; the `type` of the delivered event.
+#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTypeDto {
+    Registered,
+    Renamed,
+    EmailChanged,
+    PasswordChanged,
+    RolesChanged,
+    Deactivated,
+    Reactivated,
+    Deleted,
+}
+
+impl EventTypeDto {
+    const ALL: [EventTypeDto; 8] = [
+        EventTypeDto::Registered,
+        EventTypeDto::Renamed,
+        EventTypeDto::EmailChanged,
+        EventTypeDto::PasswordChanged,
+        EventTypeDto::RolesChanged,
+        EventTypeDto::Deactivated,
+        EventTypeDto::Reactivated,
+        EventTypeDto::Deleted,
+    ];
+
+    /// The [`UserEvent::kind`](crate::events::UserEvent::kind) it stands for.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventTypeDto::Registered => "registered",
+            EventTypeDto::Renamed => "renamed",
+            EventTypeDto::EmailChanged => "email_changed",
+            EventTypeDto::PasswordChanged => "password_changed",
+            EventTypeDto::RolesChanged => "roles_changed",
+            EventTypeDto::Deactivated => "deactivated",
+            EventTypeDto::Reactivated => "reactivated",
+            EventTypeDto::Deleted => "deleted",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event_type| event_type.as_str() == kind)
+    }
+}
+
+/// One change on the `/users/events` stream: the `data` of an SSE event named `created`,
+/// `updated` or `deleted`, whose `id` is the id here.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct UserChangeDto {
+    /// Id of the underlying event; send the last one seen as `Last-Event-ID` to resume
+    /// format = "uuid"
+    pub id: String,
+
+    /// What exactly changed
+    #[serde(rename = "type")]
+    pub event_type: EventTypeDto,
+
+    /// format = "uuid"
+    pub user_id: String,
+
+    /// The user afterwards, or just before deletion; absent for password changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<UserDto>,
+
+    /// When the change was made, in milliseconds since the Unix epoch
+    pub recorded_at: u64,
+}
+
+impl From<&RecordedEvent> for UserChangeDto {
+    fn from(recorded: &RecordedEvent) -> Self {
+        Self {
+            id: recorded.id.to_string(),
+            event_type: EventTypeDto::parse(recorded.event.kind()).expect("every event kind has a DTO"),
+            user_id: recorded.event.user_id().to_string(),
+            user: recorded.event.user().cloned().map(UserDto::from),
+            recorded_at: recorded.recorded_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct CreateWebhookDto {
+    /// Where to POST events; an absolute `http` or `https` URL
+    #[schema(format = "uri", max_length = 2048, example = "https://example.com/hooks/users")]
+    pub url: String,
+
+    /// Key for the `Webhook-Signature` HMAC; never returned
+    #[schema(write_only, min_length = 16, max_length = 256)]
+    pub secret: String,
+
+    /// Events to deliver; at least one
+    pub event_types: Vec<EventTypeDto>,
+}
+
+/// A subscription to user events. Each event is POSTed as JSON with the headers
+/// `Webhook-Id` (the delivery id), `Webhook-Event` (its type) and
+/// `Webhook-Signature: t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>" keyed with the secret>`.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct WebhookDto {
+    /// format = "uuid"
+    pub id: String,
+
+    pub url: String,
+
+    pub event_types: Vec<EventTypeDto>,
+
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+}
+
+impl From<Webhook> for WebhookDto {
+    fn from(webhook: Webhook) -> Self {
+        Self {
+            id: webhook.id.to_string(),
+            url: webhook.url,
+            // Stored types come from this enum, so none are dropped.
+            event_types: webhook.event_types.iter().filter_map(|kind| EventTypeDto::parse(kind)).collect(),
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+/// `pending` until the receiver accepts it with a 2xx status (`succeeded`) or every attempt
+/// has failed (`failed`).
+#[derive(serde::Serialize, utoipa::ToSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatusDto {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl From<DeliveryStatus> for DeliveryStatusDto {
+    fn from(status: DeliveryStatus) -> Self {
+        match status {
+            DeliveryStatus::Pending => DeliveryStatusDto::Pending,
+            DeliveryStatus::Succeeded => DeliveryStatusDto::Succeeded,
+            DeliveryStatus::Failed => DeliveryStatusDto::Failed,
+        }
+    }
+}
+
+/// One event sent, or being sent, to a webhook.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct DeliveryDto {
+    /// Sent as `Webhook-Id`, the same on every attempt
+    /// format = "uuid"
+    pub id: String,
+
+    /// format = "uuid"
+    pub webhook_id: String,
+
+    /// The event's `id`, for dropping duplicates
+    /// format = "uuid"
+    pub event_id: String,
+
+    pub event_type: EventTypeDto,
+
+    pub status: DeliveryStatusDto,
+
+    pub attempts: u32,
+
+    /// Milliseconds since the Unix epoch; only while pending
+    pub next_attempt_at: Option<u64>,
+
+    /// Milliseconds since the Unix epoch
+    pub last_attempt_at: Option<u64>,
+
+    /// HTTP status of the last response; absent if the receiver could not be reached
+    pub last_status_code: Option<u16>,
+
+    /// Why the last attempt failed
+    pub last_error: Option<String>,
+
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+
+    /// The request body: the event
+    #[schema(value_type = Object)]
+    pub payload: Value,
+}
+
+impl From<Delivery> for DeliveryDto {
+    fn from(delivery: Delivery) -> Self {
+        Self {
+            id: delivery.id.to_string(),
+            webhook_id: delivery.webhook_id.to_string(),
+            event_id: delivery.event_id.to_string(),
+            event_type: EventTypeDto::parse(&delivery.event_type).expect("deliveries are of known event types"),
+            status: delivery.status.into(),
+            attempts: delivery.attempts,
+            next_attempt_at: delivery.next_attempt_at,
+            last_attempt_at: delivery.last_attempt_at,
+            last_status_code: delivery.last_status_code,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at,
+            payload: serde_json::from_str(&delivery.payload).unwrap_or(Value::String(delivery.payload)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListDeliveriesParams {
+    /// Most deliveries returned, newest first (1-500, default 50)
+    pub limit: Option<usize>,
+}
+
+impl ListDeliveriesParams {
+    pub const DEFAULT_LIMIT: usize = 50;
+    pub const MAX_LIMIT: usize = 500;
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, Self::MAX_LIMIT)
+    }
+}
@@ -0,0 +1,326 @@
+use super::dto::FieldErrorDto;
+use super::rate_limit::{rate_limit_headers, seconds};
+use crate::app::AppError;
+use crate::passwords::PasswordError;
+use crate::policy::Denied;
+use crate::rate_limit::Decision;
+use crate::users::{ConflictField, UserRepoError};
+use crate::validation::ValidationErrors;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use thiserror::Error;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Seconds clients are asked to wait before retrying a 503.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Seconds clients are asked to wait before retrying a request whose first attempt is still
+/// being handled.
+pub const IN_FLIGHT_RETRY_AFTER_SECS: u64 = 1;
+
+/// RFC 7807 problem details, served as `application/problem+json` for every error.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct ProblemDetails {
+    /// URI reference identifying the problem type
+    /// example = "/problems/conflict"
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+
+    /// Short summary of the problem type
+    /// example = "Conflict"
+    pub title: &'static str,
+
+    /// HTTP status code
+    /// example = 409
+    pub status: u16,
+
+    /// Explanation specific to this occurrence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+
+    /// Path of the request that caused the problem
+    /// example = "/api/users"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+
+    /// Conflicts only: `username` or `email`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'static str>,
+
+    /// Conflicts only: the value that is already in use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+
+    /// Validation failures only: every failed constraint, possibly several per field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldErrorDto>>,
+}
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("conflict on field {field}")]
+    Conflict { field: ConflictField, value: String },
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    /// The body is not JSON, going by its `Content-Type`.
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    /// The body is larger than the JSON extractor accepts.
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error(transparent)]
+    Validation(#[from] ValidationErrors),
+
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("internal server error")]
+    Internal,
+
+    #[error("service unavailable")]
+    Unavailable,
+
+    #[error("not found")]
+    NotFound,
+
+    /// The `Idempotency-Key` was already used for a different request.
+    #[error("idempotency key reused for a different request")]
+    IdempotencyMismatch,
+
+    /// A request with the same `Idempotency-Key` is still being handled.
+    #[error("idempotency key in use")]
+    IdempotencyInFlight,
+
+    /// The client used up its quota for the route.
+    #[error("too many requests")]
+    TooManyRequests(Decision),
+}
+
+impl From<UserRepoError> for ApiError {
+    fn from(e: UserRepoError) -> Self {
+        match e {
+            UserRepoError::Conflict { field, value } => ApiError::Conflict { field, value },
+            UserRepoError::InvalidCursor => ApiError::BadRequest(e.to_string()),
+            UserRepoError::Unavailable => ApiError::Unavailable,
+            UserRepoError::Unexpected(_) => ApiError::Internal,
+        }
+    }
+}
+
+impl From<Denied> for ApiError {
+    fn from(denied: Denied) -> Self {
+        ApiError::Forbidden(denied.to_string())
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::NotFound | AppError::WebhookNotFound | AppError::DeliveryNotFound => ApiError::NotFound,
+            AppError::Validation(errors) => ApiError::Validation(errors),
+            AppError::Denied(denied) => denied.into(),
+            AppError::Unauthenticated(reason) => ApiError::Unauthorized(reason.to_owned()),
+            AppError::Password(e) => e.into(),
+            AppError::Repo(e) => e.into(),
+        }
+    }
+}
+
+impl From<PasswordError> for ApiError {
+    fn from(e: PasswordError) -> Self {
+        log::error!("Password hashing failed: {e}");
+        ApiError::Internal
+    }
+}
+
+impl ApiError {
+    fn problem_type(&self) -> &'static str {
+        match self {
+            ApiError::Conflict { .. } => "/problems/conflict",
+            ApiError::BadRequest(_) => "/problems/bad-request",
+            ApiError::UnsupportedMediaType(_) => "/problems/unsupported-media-type",
+            ApiError::PayloadTooLarge(_) => "/problems/payload-too-large",
+            ApiError::Validation(_) => "/problems/validation",
+            ApiError::Unauthorized(_) => "/problems/unauthorized",
+            ApiError::Forbidden(_) => "/problems/forbidden",
+            ApiError::Internal => "/problems/internal",
+            ApiError::Unavailable => "/problems/unavailable",
+            ApiError::NotFound => "/problems/not-found",
+            ApiError::IdempotencyMismatch => "/problems/idempotency-key-reused",
+            ApiError::IdempotencyInFlight => "/problems/idempotency-key-in-use",
+            ApiError::TooManyRequests(_) => "/problems/too-many-requests",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::Conflict { .. } => "Conflict",
+            ApiError::BadRequest(_) => "Bad request",
+            ApiError::UnsupportedMediaType(_) => "Unsupported media type",
+            ApiError::PayloadTooLarge(_) => "Payload too large",
+            ApiError::Validation(_) => "Validation failed",
+            ApiError::Unauthorized(_) => "Unauthorized",
+            ApiError::Forbidden(_) => "Forbidden",
+            ApiError::Internal => "Internal server error",
+            ApiError::Unavailable => "Service unavailable",
+            ApiError::NotFound => "Not found",
+            ApiError::IdempotencyMismatch => "Idempotency key reused",
+            ApiError::IdempotencyInFlight => "Idempotency key in use",
+            ApiError::TooManyRequests(_) => "Too many requests",
+        }
+    }
+
+    fn problem(&self, instance: Option<String>) -> ProblemDetails {
+        let mut problem = ProblemDetails {
+            problem_type: self.problem_type(),
+            title: self.title(),
+            status: self.status_code().as_u16(),
+            detail: None,
+            instance,
+            field: None,
+            value: None,
+            errors: None,
+        };
+
+        match self {
+            ApiError::Conflict { field, value } => {
+                problem.detail = Some(format!("{field} is already taken"));
+                problem.field = Some(field.as_str());
+                problem.value = Some(value.clone());
+            }
+            ApiError::BadRequest(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::Unauthorized(detail)
+            | ApiError::Forbidden(detail) => problem.detail = Some(detail.clone()),
+            ApiError::Validation(errors) => {
+                problem.detail = Some(errors.to_string());
+                problem.errors = Some(errors.errors().iter().map(FieldErrorDto::from).collect());
+            }
+            ApiError::Unavailable => {
+                problem.detail = Some("the user store is temporarily unreachable; retry later".to_owned());
+            }
+            ApiError::IdempotencyMismatch => {
+                problem.detail = Some("this Idempotency-Key was already used for a different request".to_owned());
+            }
+            ApiError::IdempotencyInFlight => {
+                problem.detail = Some("a request with this Idempotency-Key is still being handled; retry later".to_owned());
+            }
+            ApiError::TooManyRequests(decision) => {
+                problem.detail = Some(format!("rate limit exceeded; retry in {} seconds", seconds(decision.retry_after)));
+            }
+            // Never leak internals to callers.
+            ApiError::Internal | ApiError::NotFound => {}
+        }
+
+        problem
+    }
+
+    pub(super) fn problem_response(&self, instance: Option<String>) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        res.content_type(PROBLEM_JSON);
+
+        match self {
+            ApiError::Unavailable => {
+                res.insert_header((RETRY_AFTER, RETRY_AFTER_SECS));
+            }
+            ApiError::IdempotencyInFlight => {
+                res.insert_header((RETRY_AFTER, IN_FLIGHT_RETRY_AFTER_SECS));
+            }
+            ApiError::TooManyRequests(decision) => {
+                res.insert_header((RETRY_AFTER, seconds(decision.retry_after)));
+                for header in rate_limit_headers(decision) {
+                    res.insert_header(header);
+                }
+            }
+            // RFC 6750: tell the client which scheme to authenticate with.
+            ApiError::Unauthorized(_) => {
+                res.insert_header((WWW_AUTHENTICATE, "Bearer"));
+            }
+            _ => {}
+        }
+
+        res.json(self.problem(instance))
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Conflict { .. } | ApiError::IdempotencyInFlight => StatusCode::CONFLICT,
+            ApiError::BadRequest(_) | ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::IdempotencyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.problem_response(None)
+    }
+}
+
+/// Re-renders [`ApiError`] responses with the request path as the problem `instance`,
+/// which `ResponseError::error_response` has no access to.
+pub async fn problem_instance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let path = req.path().to_owned();
+    let res = next.call(req).await?;
+
+    let problem = res
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<ApiError>())
+        .map(|e| e.problem_response(Some(path)));
+
+    Ok(match problem {
+        Some(problem) => res.into_response(problem).map_into_right_body(),
+        None => res.map_into_left_body(),
+    })
+}
+
+pub(crate) fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::ContentType => ApiError::UnsupportedMediaType(err.to_string()),
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            ApiError::PayloadTooLarge(err.to_string())
+        }
+        _ => ApiError::BadRequest(err.to_string()),
+    }
+    .into()
+}
+
+pub(crate) fn path_error_handler(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
+
+pub(crate) fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
+
+/// Default service answering unmatched routes with a 404 problem.
+pub async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound)
+}
+
@@ -0,0 +1,158 @@
+use super::auth::Authenticated;
+use super::dto::UserChangeDto;
+use super::error::{ApiError, ProblemDetails};
+use crate::app::{Application, UserChanges};
+use crate::config::StreamSettings;
+use crate::events::{RecordedEvent, UserEvent};
+use crate::users::UserRepo;
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::web::{Bytes, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::stream::{self, Stream};
+use log::info;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use uuid::Uuid;
+
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+const HEARTBEAT: &[u8] = b": heartbeat\n\n";
+
+/// Tells the client it may have missed changes, so it should reload what it shows.
+const RESET: &[u8] = b"event: reset\ndata: {}\n\n";
+
+#[utoipa::path(
+    get,
+    path = "/users/events",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(
+        ("Last-Event-ID" = Option<String>, Header,
+            description = "Id of the last event seen, to first receive the changes made since; \
+                if it is too old to replay, the stream starts with a `reset` event instead")
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events: a `created`, `updated` or `deleted` event per change to any user, \
+            with the change as `data` and its id as `id`; `reset` when changes may have been missed; \
+            and a `: heartbeat` comment while idle", body = UserChangeDto, content_type = "text/event-stream"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is neither an admin nor an auditor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn user_events<R: UserRepo + 'static>(
+    req: HttpRequest,
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    settings: Option<Data<StreamSettings>>,
+) -> Result<HttpResponse, ApiError> {
+    let last_event_id = req.headers().get(LAST_EVENT_ID).and_then(|value| value.to_str().ok());
+    info!("Streaming user events after: {}", last_event_id.unwrap_or("now"));
+    let caller = auth.caller(&data).await?;
+
+    // An id that is not even a UUID cannot be replayed from, just like one too old.
+    let after = last_event_id.map(|id| id.trim().parse().unwrap_or_else(|_| Uuid::nil()));
+    let changes = data.watch_users(&caller, after)?;
+
+    let heartbeat = settings.map_or_else(StreamSettings::default, |settings| settings.get_ref().clone()).heartbeat_interval_ms;
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // Stops nginx from buffering the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(frames(data, auth, changes, Duration::from_millis(heartbeat.max(1)))))
+}
+
+struct State<R: UserRepo> {
+    app: Data<Application<R>>,
+    auth: Authenticated,
+    /// Frames to send before any live event.
+    pending: VecDeque<Bytes>,
+    changes: UserChanges,
+    heartbeat: Interval,
+    expires: Instant,
+}
+
+impl<R: UserRepo> State<R> {
+    /// Whether the subscriber may still see every user's changes.
+    async fn allowed(&self) -> bool {
+        match self.app.authorize_watch(self.auth.user_id).await {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Ending the user event stream of user {}: {e}", self.auth.user_id);
+                false
+            }
+        }
+    }
+}
+
+/// The SSE frames for `changes`: the missed ones (or a reset), then live ones as they are
+/// relayed, with a heartbeat every `interval` in between.
+///
+/// The stream ends when `auth`'s token expires, or when a heartbeat or a change to the
+/// subscriber finds they may no longer list users.
+fn frames<R: UserRepo>(
+    app: Data<Application<R>>,
+    auth: Authenticated,
+    mut changes: UserChanges,
+    interval: Duration,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let pending = match changes.missed.take() {
+        Some(missed) => missed.iter().map(frame).collect(),
+        None => VecDeque::from([Bytes::from_static(RESET)]),
+    };
+    let mut heartbeat = time::interval_at(Instant::now() + interval, interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let expires = Instant::now() + auth.expires_in();
+
+    let state = State { app, auth, pending, changes, heartbeat, expires };
+    stream::unfold(state, |mut state| async move {
+        if let Some(frame) = state.pending.pop_front() {
+            return Some((Ok(frame), state));
+        }
+
+        let (frame, recheck) = tokio::select! {
+            received = state.changes.live.recv() => match received {
+                // Changes to the subscriber themselves may take their access away.
+                Ok(event) => (frame(&event), event.event.user_id() == state.auth.user_id),
+                // Fell behind the relay; the skipped events are gone, and may have been such changes.
+                Err(RecvError::Lagged(_)) => (Bytes::from_static(RESET), true),
+                Err(RecvError::Closed) => return None,
+            },
+            _ = state.heartbeat.tick() => (Bytes::from_static(HEARTBEAT), true),
+            _ = time::sleep_until(state.expires) => {
+                info!("Ending the user event stream of user {}: access token expired", state.auth.user_id);
+                return None;
+            }
+        };
+        if recheck && !state.allowed().await {
+            return None;
+        }
+        Some((Ok(frame), state))
+    })
+}
+
+fn frame(recorded: &RecordedEvent) -> Bytes {
+    let data = serde_json::to_string(&UserChangeDto::from(recorded)).expect("changes serialize to JSON");
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {data}\n\n", recorded.id, change(&recorded.event)))
+}
+
+/// The coarse kind of change a UI cares about.
+fn change(event: &UserEvent) -> &'static str {
+    match event {
+        UserEvent::Registered { .. } => "created",
+        UserEvent::Deleted { .. } => "deleted",
+        UserEvent::Renamed { .. }
+        | UserEvent::EmailChanged { .. }
+        | UserEvent::PasswordChanged { .. }
+        | UserEvent::RolesChanged { .. }
+        | UserEvent::Deactivated { .. }
+        | UserEvent::Reactivated { .. } => "updated",
+    }
+}
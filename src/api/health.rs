@@ -0,0 +1,127 @@
+//! Liveness and readiness probes, mounted next to (not inside) the user API.
+
+use crate::app::Application;
+use crate::users::UserRepo;
+use actix_web::dev::HttpServiceFactory;
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(live, ready),
+    components(schemas(HealthDto, CheckDto, HealthStatus)),
+    tags((name = "health", description = "Liveness and readiness probes"))
+)]
+struct HealthDoc;
+
+#[derive(serde::Serialize, utoipa::ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct HealthDto {
+    /// `up` only if every check is up
+    pub status: HealthStatus,
+
+    /// Result per dependency, keyed by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, CheckDto>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct CheckDto {
+    pub status: HealthStatus,
+
+    /// Time the check took, or the timeout if it did not finish
+    pub latency_ms: u64,
+
+    /// Why the check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How long readiness waits for the repository before reporting it down.
+#[derive(Clone, Copy)]
+struct ReadinessTimeout(Duration);
+
+#[utoipa::path(
+    get,
+    path = "/live",
+    tag = "health",
+    responses(
+        (status = 200, description = "The process is running", body = HealthDto)
+    )
+)]
+async fn live() -> Json<HealthDto> {
+    Json(HealthDto {
+        status: HealthStatus::Up,
+        checks: BTreeMap::new(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "health",
+    responses(
+        (status = 200, description = "Every dependency is reachable", body = HealthDto),
+        (status = 503, description = "At least one dependency is down", body = HealthDto)
+    )
+)]
+async fn ready<R: UserRepo>(data: Data<Application<R>>, timeout: Data<ReadinessTimeout>) -> HttpResponse {
+    let started = Instant::now();
+    let outcome = tokio::time::timeout(timeout.0, data.health_check()).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    let repository = match outcome {
+        Ok(Ok(())) => CheckDto {
+            status: HealthStatus::Up,
+            latency_ms,
+            error: None,
+        },
+        Ok(Err(e)) => CheckDto {
+            status: HealthStatus::Down,
+            latency_ms,
+            error: Some(e.to_string()),
+        },
+        Err(_) => CheckDto {
+            status: HealthStatus::Down,
+            latency_ms,
+            error: Some(format!("timed out after {} ms", timeout.0.as_millis())),
+        },
+    };
+
+    let checks = BTreeMap::from([("repository", repository)]);
+    let status = if checks.values().all(|check| check.status == HealthStatus::Up) {
+        HealthStatus::Up
+    } else {
+        HealthStatus::Down
+    };
+
+    let mut res = match status {
+        HealthStatus::Up => HttpResponse::Ok(),
+        HealthStatus::Down => HttpResponse::ServiceUnavailable(),
+    };
+    res.json(HealthDto { status, checks })
+}
+
+/// `{path}/live` and `{path}/ready`; readiness gives the repository `timeout` to answer.
+///
+/// Expects `Data<Application<R>>` in app data, like [`super::configure`].
+pub fn scope<R: UserRepo + 'static>(path: &str, timeout: Duration) -> impl HttpServiceFactory {
+    web::scope(path)
+        .app_data(Data::new(ReadinessTimeout(timeout)))
+        .route("/live", web::get().to(live))
+        .route("/ready", web::get().to(ready::<R>))
+}
+
+/// OpenAPI document for the probes as mounted under `path` (e.g. `/health`).
+pub fn openapi(path: &str) -> utoipa::openapi::OpenApi {
+    super::nested(path, HealthDoc::openapi())
+}
@@ -0,0 +1,134 @@
+use super::error::ApiError;
+use crate::app::Application;
+use crate::idempotency::{Claim, StoredResponse};
+use crate::users::UserRepo;
+use actix_web::body::{self, BoxBody};
+use actix_web::http::header::{CONTENT_TYPE, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use log::{error, info};
+use std::future::Future;
+
+pub(super) const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Set on responses replayed for a retry rather than produced by handling it.
+pub(super) const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// The only headers worth replaying; the rest are the server's own and get set afresh.
+const STORED_HEADERS: [actix_web::http::header::HeaderName; 2] = [CONTENT_TYPE, LOCATION];
+
+/// Answers `req` with `handle`, at most once per `Idempotency-Key`.
+///
+/// Without the header, `handle` simply runs. With it, the first request claims the key and its
+/// response is stored; retries with the same key and `fingerprint` get that response back while
+/// it lasts, and one with another fingerprint gets a 422. A retry arriving while the first
+/// request is still being handled gets a 409. Server errors are not stored, so that a retry
+/// tries again.
+pub(super) async fn idempotent<R: UserRepo>(
+    req: &HttpRequest,
+    data: &Application<R>,
+    fingerprint: &str,
+    handle: impl Future<Output = Result<HttpResponse, ApiError>>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(key) = idempotency_key(req)? else {
+        return handle.await;
+    };
+
+    match data.claim_idempotency_key(key, fingerprint).await? {
+        Claim::New => {}
+        Claim::Replay(stored) => {
+            info!("Replaying response for idempotency key: {key}");
+            return Ok(replay(stored));
+        }
+        Claim::InFlight => return Err(ApiError::IdempotencyInFlight),
+        Claim::Mismatch => return Err(ApiError::IdempotencyMismatch),
+    }
+
+    let res = match handle.await {
+        Err(e) if e.status_code().is_server_error() => {
+            if let Err(release) = data.release_idempotency_key(key).await {
+                error!("Failed to release idempotency key {key}: {release}");
+            }
+            return Err(e);
+        }
+        // Stored as the problem_instance middleware is about to render it.
+        Err(e) => {
+            let (stored, _) = store(e.problem_response(Some(req.path().to_owned()))).await;
+            if let Some(stored) = stored {
+                complete(data, key, &stored).await;
+            }
+            return Err(e);
+        }
+        Ok(res) => res,
+    };
+
+    let (stored, res) = store(res).await;
+    match stored {
+        Some(stored) => complete(data, key, &stored).await,
+        None => {
+            if let Err(release) = data.release_idempotency_key(key).await {
+                error!("Failed to release idempotency key {key}: {release}");
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// The request's `Idempotency-Key`, if it has one: 1 to 255 visible ASCII characters.
+fn idempotency_key(req: &HttpRequest) -> Result<Option<&str>, ApiError> {
+    let Some(value) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+
+    match value.to_str() {
+        Ok(key) if (1..=MAX_KEY_LENGTH).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic()) => Ok(Some(key)),
+        _ => Err(ApiError::BadRequest(format!(
+            "{IDEMPOTENCY_KEY} must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+        ))),
+    }
+}
+
+/// Reads the body of `res` to store it, and puts it back. Gives nothing to store if the body
+/// cannot be read or is not text.
+async fn store(res: HttpResponse) -> (Option<StoredResponse>, HttpResponse) {
+    let (res, body) = res.into_parts();
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to read response body to store: {e}");
+            return (None, res.set_body(BoxBody::new(())));
+        }
+    };
+
+    let stored = String::from_utf8(bytes.to_vec()).ok().map(|body| StoredResponse {
+        status: res.status().as_u16(),
+        headers: STORED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = res.headers().get(name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_owned()))
+            })
+            .collect(),
+        body,
+    });
+    (stored, res.set_body(BoxBody::new(bytes)))
+}
+
+/// Keeps `stored` for retries. The response goes out even if that fails; a retry then waits
+/// out the lock timeout.
+async fn complete<R: UserRepo>(data: &Application<R>, key: &str, stored: &StoredResponse) {
+    if let Err(e) = data.complete_idempotency_key(key, stored).await {
+        error!("Failed to store response for idempotency key {key}: {e}");
+    }
+}
+
+fn replay(stored: StoredResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut res = HttpResponse::build(status);
+    for header in stored.headers {
+        res.insert_header(header);
+    }
+    res.insert_header((IDEMPOTENT_REPLAYED, "true")).body(stored.body)
+}
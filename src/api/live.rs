@@ -0,0 +1,372 @@
+//! `/users/live`: WebSocket subscriptions to filtered sets of users.
+//!
+//! The client sends `subscribe` (with a subscription name of its choosing and a filter) and
+//! `unsubscribe` messages; the server answers a subscription with a `snapshot` of every
+//! matching user, then `created`, `updated` and `deleted` messages as users enter, change within
+//! and leave the set. A `snapshot` replaces whatever the client knew for that subscription; one
+//! is sent again whenever the connection fell too far behind to tell what it missed.
+//!
+//! The connection is closed with a policy violation (1008) when the access token it was opened
+//! with expires, or once the caller may no longer list users.
+
+use super::auth::Authenticated;
+use super::dto::UserDto;
+use super::error::{ApiError, ProblemDetails};
+use crate::app::{AppError, Application};
+use crate::config::WebSocketSettings;
+use crate::events::{RecordedEvent, UserEvent};
+use crate::live::{Change, LiveView};
+use crate::users::{User, UserQuery, UserRepo};
+use actix_web::web::{Data, Payload};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Session};
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use uuid::Uuid;
+
+/// Longest subscription name, in bytes.
+const MAX_SUBSCRIPTION_LEN: usize = 64;
+
+/// Client messages are small; anything bigger is a protocol error.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        subscription: String,
+        #[serde(default)]
+        filter: FilterDto,
+    },
+    Unsubscribe {
+        subscription: String,
+    },
+}
+
+/// The filters of `GET /users`; every user matches an empty one.
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FilterDto {
+    username_prefix: Option<String>,
+    email_domain: Option<String>,
+}
+
+impl From<FilterDto> for UserQuery {
+    fn from(filter: FilterDto) -> Self {
+        UserQuery {
+            username_prefix: filter.username_prefix,
+            email_domain: filter.email_domain,
+            ..UserQuery::default()
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Snapshot { subscription: &'a str, users: Vec<UserDto> },
+    Created { subscription: &'a str, user: UserDto },
+    Updated { subscription: &'a str, user: UserDto },
+    Deleted { subscription: &'a str, user: UserDto },
+    Unsubscribed { subscription: &'a str },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subscription: Option<&'a str>,
+        message: String,
+    },
+}
+
+impl<'a> ServerMessage<'a> {
+    fn change(subscription: &'a str, change: Change) -> Self {
+        match change {
+            Change::Created(user) => Self::Created { subscription, user: user.into() },
+            Change::Updated(user) => Self::Updated { subscription, user: user.into() },
+            Change::Deleted(user) => Self::Deleted { subscription, user: user.into() },
+        }
+    }
+
+    fn error(subscription: Option<&'a str>, message: impl Into<String>) -> Self {
+        Self::Error { subscription, message: message.into() }
+    }
+
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("server messages serialize to JSON")
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/live",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 101, description = "WebSocket of JSON messages. Send `{\"type\": \"subscribe\", \"subscription\": \"<name>\", \
+            \"filter\": {\"email_domain\": \"example.com\"}}` (the filters of `GET /users`, all optional) to get a `snapshot` \
+            of the matching users, then `created`, `updated` and `deleted` messages as users enter, change within and leave \
+            the set; `{\"type\": \"unsubscribe\", \"subscription\": \"<name>\"}` stops them. A later `snapshot` replaces \
+            the earlier one; problems with a message are answered with an `error`"),
+        (status = 400, description = "Not a WebSocket handshake", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is neither an admin nor an auditor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn live_users<R: UserRepo + 'static>(
+    req: HttpRequest,
+    body: Payload,
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    settings: Option<Data<WebSocketSettings>>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Opening live user subscriptions for user: {}", auth.user_id);
+    let caller = auth.caller(&data).await?;
+    // Listening before the first snapshot is taken, so no change falls in between.
+    let live = data.watch_users(&caller, None)?.live;
+
+    let (response, session, messages) = actix_ws::handle(&req, body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let messages = messages
+        .max_frame_size(MAX_MESSAGE_SIZE)
+        .aggregate_continuations()
+        .max_continuation_size(MAX_MESSAGE_SIZE);
+
+    let connection = Connection {
+        app: data,
+        user_id: auth.user_id,
+        expires: Instant::now() + auth.expires_in(),
+        session,
+        views: BTreeMap::new(),
+        settings: settings.map_or_else(WebSocketSettings::default, |settings| settings.get_ref().clone()),
+    };
+    actix_web::rt::spawn(connection.run(messages, live));
+    Ok(response)
+}
+
+/// Why a connection ends.
+enum Stop {
+    /// Close it properly, e.g. after the client asked to.
+    Close(Option<CloseReason>),
+    /// Just drop it: the client is gone, or too slow to even take a close frame.
+    Drop(&'static str),
+}
+
+struct Connection<R: UserRepo> {
+    app: Data<Application<R>>,
+    user_id: Uuid,
+    /// When the access token the connection was opened with expires.
+    expires: Instant,
+    session: Session,
+    /// By subscription name.
+    views: BTreeMap<String, LiveView>,
+    settings: WebSocketSettings,
+}
+
+impl<R: UserRepo> Connection<R> {
+    async fn run(mut self, mut messages: AggregatedMessageStream, mut live: broadcast::Receiver<RecordedEvent>) {
+        let ping_interval = Duration::from_millis(self.settings.ping_interval_ms.max(1));
+        let mut ping = time::interval_at(Instant::now() + ping_interval, ping_interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_heard = Instant::now();
+
+        let stop = loop {
+            let handled = tokio::select! {
+                message = messages.recv() => {
+                    last_heard = Instant::now();
+                    match message {
+                        Some(Ok(AggregatedMessage::Text(text))) => self.handle(&text).await,
+                        Some(Ok(AggregatedMessage::Binary(_))) => self.send(&ServerMessage::error(None, "messages must be JSON text")).await,
+                        Some(Ok(AggregatedMessage::Ping(bytes))) => self.deliver(|session| session.pong(&bytes)).await,
+                        Some(Ok(AggregatedMessage::Pong(_))) => Ok(()),
+                        Some(Ok(AggregatedMessage::Close(_))) => Err(Stop::Close(None)),
+                        None => Err(Stop::Drop("connection lost")),
+                        Some(Err(e)) => Err(Stop::Close(Some(CloseReason {
+                            code: CloseCode::Protocol,
+                            description: Some(e.to_string()),
+                        }))),
+                    }
+                }
+                received = live.recv() => match received {
+                    Ok(recorded) => self.publish(&recorded.event).await,
+                    // The skipped events are gone; start every subscription over.
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Live subscriptions of user {} fell {skipped} events behind", self.user_id);
+                        match self.authorize().await {
+                            Ok(()) => self.refresh().await,
+                            Err(stop) => Err(stop),
+                        }
+                    }
+                    Err(RecvError::Closed) => Err(Stop::Close(Some(CloseCode::Away.into()))),
+                },
+                _ = ping.tick() => match last_heard.elapsed() > 2 * ping_interval {
+                    true => Err(Stop::Drop("client stopped answering pings")),
+                    false => match self.authorize().await {
+                        Ok(()) => self.deliver(|session| session.ping(b"")).await,
+                        Err(stop) => Err(stop),
+                    },
+                },
+                _ = time::sleep_until(self.expires) => Err(Stop::Close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("access token expired".to_owned()),
+                }))),
+            };
+            if let Err(stop) = handled {
+                break stop;
+            }
+        };
+
+        match stop {
+            Stop::Close(reason) => {
+                debug!("Closing live subscriptions of user {}", self.user_id);
+                let timeout = self.send_timeout();
+                let _ = time::timeout(timeout, self.session.close(reason)).await;
+            }
+            Stop::Drop(why) => info!("Dropping live subscriptions of user {}: {why}", self.user_id),
+        }
+    }
+
+    async fn handle(&mut self, text: &str) -> Result<(), Stop> {
+        match serde_json::from_str(text) {
+            Ok(ClientMessage::Subscribe { subscription, filter }) => self.subscribe(subscription, filter.into()).await,
+            Ok(ClientMessage::Unsubscribe { subscription }) => match self.views.remove(&subscription) {
+                Some(_) => self.send(&ServerMessage::Unsubscribed { subscription: &subscription }).await,
+                None => self.send(&ServerMessage::error(Some(&subscription), "no such subscription")).await,
+            },
+            Err(e) => self.send(&ServerMessage::error(None, format!("invalid message: {e}"))).await,
+        }
+    }
+
+    async fn subscribe(&mut self, subscription: String, filter: UserQuery) -> Result<(), Stop> {
+        let refused = if subscription.is_empty() || subscription.len() > MAX_SUBSCRIPTION_LEN {
+            Some(format!("subscription names must be 1-{MAX_SUBSCRIPTION_LEN} bytes long"))
+        } else if self.views.contains_key(&subscription) {
+            Some("already subscribed under this name".to_owned())
+        } else if self.views.len() >= self.settings.max_subscriptions {
+            Some(format!("at most {} subscriptions per connection", self.settings.max_subscriptions))
+        } else {
+            None
+        };
+        if let Some(message) = refused {
+            return self.send(&ServerMessage::error(Some(&subscription), message)).await;
+        }
+
+        let mut view = LiveView::new(filter);
+        let Some(users) = self.snapshot(&subscription, view.filter()).await? else {
+            return Ok(());
+        };
+        view.reset(&users);
+        self.views.insert(subscription.clone(), view);
+        self.send_snapshot(&subscription, users).await
+    }
+
+    /// Every user matching `filter`, checked against the caller's current roles. `None` if
+    /// that failed in a way the client was told about.
+    async fn snapshot(&mut self, subscription: &str, filter: &UserQuery) -> Result<Option<Vec<User>>, Stop> {
+        let users = match self.app.caller(self.user_id).await {
+            Ok(caller) => self.app.all_users(&caller, filter).await,
+            Err(e) => Err(e),
+        };
+
+        let message = match users {
+            Ok(users) => return Ok(Some(users)),
+            Err(AppError::Unauthenticated(why)) => {
+                return Err(Stop::Close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(why.to_owned()),
+                })));
+            }
+            Err(AppError::Denied(denied)) => denied.to_string(),
+            Err(e) => {
+                error!("Failed to load users for live subscription {subscription}: {e}");
+                "failed to load users; try again later".to_owned()
+            }
+        };
+        self.send(&ServerMessage::error(Some(subscription), message)).await?;
+        Ok(None)
+    }
+
+    /// Retakes the snapshot of every subscription, dropping those that failed.
+    async fn refresh(&mut self) -> Result<(), Stop> {
+        let subscriptions: Vec<String> = self.views.keys().cloned().collect();
+        for subscription in subscriptions {
+            let filter = self.views[&subscription].filter().clone();
+            match self.snapshot(&subscription, &filter).await? {
+                Some(users) => {
+                    if let Some(view) = self.views.get_mut(&subscription) {
+                        view.reset(&users);
+                    }
+                    self.send_snapshot(&subscription, users).await?;
+                }
+                None => {
+                    self.views.remove(&subscription);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the caller may still watch users, with their current roles: they may have lost
+    /// the role, or been deactivated or deleted, since the connection was opened.
+    async fn authorize(&self) -> Result<(), Stop> {
+        let (code, description) = match self.app.authorize_watch(self.user_id).await {
+            Ok(()) => return Ok(()),
+            Err(AppError::Unauthenticated(why)) => (CloseCode::Policy, why.to_owned()),
+            Err(AppError::Denied(denied)) => (CloseCode::Policy, denied.to_string()),
+            Err(e) => {
+                error!("Failed to check the access of user {} to live subscriptions: {e}", self.user_id);
+                (CloseCode::Again, "failed to check access; try again later".to_owned())
+            }
+        };
+        Err(Stop::Close(Some(CloseReason { code, description: Some(description) })))
+    }
+
+    async fn publish(&mut self, event: &UserEvent) -> Result<(), Stop> {
+        // Changes to the caller themselves may take their access away.
+        if event.user_id() == self.user_id {
+            self.authorize().await?;
+        }
+        let messages: Vec<String> = self
+            .views
+            .iter_mut()
+            .filter_map(|(subscription, view)| Some(ServerMessage::change(subscription, view.apply(event)?).to_text()))
+            .collect();
+        for text in messages {
+            self.deliver(|session| session.text(text)).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_snapshot(&mut self, subscription: &str, users: Vec<User>) -> Result<(), Stop> {
+        let users = users.into_iter().map(UserDto::from).collect();
+        self.send(&ServerMessage::Snapshot { subscription, users }).await
+    }
+
+    async fn send(&mut self, message: &ServerMessage<'_>) -> Result<(), Stop> {
+        let text = message.to_text();
+        self.deliver(|session| session.text(text)).await
+    }
+
+    /// Runs `send`, giving up on clients that do not read fast enough: once the outgoing buffer
+    /// is full, every send waits for the client.
+    async fn deliver<'s, F, Fut>(&'s mut self, send: F) -> Result<(), Stop>
+    where
+        F: FnOnce(&'s mut Session) -> Fut,
+        Fut: Future<Output = Result<(), actix_ws::Closed>>,
+    {
+        let timeout = self.send_timeout();
+        match time::timeout(timeout, send(&mut self.session)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(actix_ws::Closed)) => Err(Stop::Drop("connection closed")),
+            Err(_) => Err(Stop::Drop("client too slow to keep up")),
+        }
+    }
+
+    fn send_timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.send_timeout_ms)
+    }
+}
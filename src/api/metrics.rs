@@ -0,0 +1,41 @@
+//! Per-route request metrics and the `/metrics` scrape endpoint.
+
+use crate::metrics::Metrics;
+use actix_web::body::BoxBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::web::{self, Data};
+use actix_web::{Error, HttpResponse, Resource, Route};
+use std::time::Instant;
+
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Records every request handled by `route` under the `name` label, if `Data<Metrics>` is
+/// registered; otherwise the route is left as is.
+pub(super) fn instrument(route: Route, name: &'static str) -> Route {
+    route.wrap(from_fn(move |req: ServiceRequest, next: Next<BoxBody>| observe(name, req, next)))
+}
+
+async fn observe(name: &'static str, req: ServiceRequest, next: Next<BoxBody>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(metrics) = req.app_data::<Data<Metrics>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let method = req.method().clone();
+    let started = Instant::now();
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    metrics.observe_http(name, method.as_str(), status.as_u16(), started.elapsed());
+    result
+}
+
+/// Serves the `Data<Metrics>` registered in app data at `path`, in the Prometheus text format.
+pub fn metrics_route(path: &str) -> Resource {
+    web::resource(path).route(web::get().to(|metrics: Data<Metrics>| async move {
+        HttpResponse::Ok().content_type(TEXT_FORMAT).body(metrics.render())
+    }))
+}
@@ -0,0 +1,203 @@
+//! The user management HTTP API as a reusable actix service.
+//!
+//! Mount it with [`scope`] (or [`configure`] inside a scope of your own) and register a
+//! `Data<Application<R>>` for the same `R`, plus the [`Tokens`](crate::tokens::Tokens) that
+//! sign and check access tokens:
+//!
+//! ```no_run
+//! use actix_web::{web::Data, App};
+//! use rust_webapp::adapters::memory::MemoryUserRepo;
+//! use rust_webapp::app::Application;
+//! use rust_webapp::config::JwtSettings;
+//! use rust_webapp::tokens::Tokens;
+//!
+//! let application = Data::new(Application::new(MemoryUserRepo::new()));
+//! let tokens = Data::new(Tokens::new(&JwtSettings::default()).unwrap());
+//! let app = App::new()
+//!     .app_data(application)
+//!     .app_data(tokens)
+//!     .service(rust_webapp::api::scope::<MemoryUserRepo>("/api"));
+//! ```
+
+pub mod auth;
+pub mod dto;
+pub mod error;
+mod events;
+pub mod health;
+mod idempotency;
+mod live;
+pub mod metrics;
+mod rate_limit;
+pub mod request_log;
+pub mod tracing;
+mod users;
+mod webhooks;
+
+use crate::users::UserRepo;
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header::ContentType;
+use actix_web::middleware::from_fn;
+use actix_web::web::{self, JsonConfig, PathConfig, QueryConfig, ServiceConfig};
+use actix_web::{HttpResponse, Resource, Route};
+use dto::{
+    ChangePasswordDto, CreateUserDto, CreateWebhookDto, DeliveryDto, DeliveryStatusDto, EventTypeDto, FieldErrorDto, LoginDto,
+    PatchUserDto, RefreshDto, RoleDto, SortDto, TokenDto, UpdateUserDto, UserChangeDto, UserDto, UserPageDto, WebhookDto,
+};
+use error::{problem_instance, ProblemDetails};
+use metrics::instrument;
+use rate_limit::limit;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        users::get_users,
+        users::create_user,
+        events::user_events,
+        live::live_users,
+        users::get_user,
+        users::replace_user,
+        users::patch_user,
+        users::change_password,
+        users::delete_user,
+        users::deactivate_user,
+        users::reactivate_user,
+        users::grant_role,
+        users::revoke_role,
+        webhooks::get_webhooks,
+        webhooks::create_webhook,
+        webhooks::get_webhook,
+        webhooks::delete_webhook,
+        webhooks::get_deliveries,
+        webhooks::redeliver,
+        auth::login,
+        auth::refresh
+    ),
+    components(
+        schemas(
+            UserDto, RoleDto, UserPageDto, SortDto, CreateUserDto, UpdateUserDto, PatchUserDto, ChangePasswordDto, LoginDto,
+            RefreshDto, TokenDto, ProblemDetails, FieldErrorDto, EventTypeDto, CreateWebhookDto, WebhookDto, DeliveryStatusDto,
+            DeliveryDto, UserChangeDto
+        )
+    ),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "users", description = "User management"),
+        (name = "webhooks", description = "Signed HTTP callbacks for user events; admins only"),
+        (name = "auth", description = "Logging in and refreshing tokens")
+    )
+)]
+struct ApiDoc;
+
+/// Declares the `bearer_auth` scheme that protected paths refer to.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+/// Registers the user, webhook and auth routes (see [`openapi`] for the full list), and the
+/// extractor configs that turn malformed input into problem responses. Everything but signing
+/// up and the auth routes needs an access token, and the application's access policy decides
+/// what its user may do.
+///
+/// Expects `Data<Application<R>>` and `Data<`[`Tokens`](crate::tokens::Tokens)`>` in app data.
+/// When registered, routes also report to `Data<`[`Metrics`](crate::metrics::Metrics)`>`, are
+/// limited by `Data<`[`RateLimiter`](crate::rate_limit::RateLimiter)`>`, and the event stream
+/// and the WebSocket take their settings from
+/// `Data<`[`StreamSettings`](crate::config::StreamSettings)`>` and
+/// `Data<`[`WebSocketSettings`](crate::config::WebSocketSettings)`>`.
+///
+/// Wrap the surrounding scope with [`error::problem_instance`] to get the request path in
+/// problem responses, or use [`scope`].
+pub fn configure<R: UserRepo + 'static>(cfg: &mut ServiceConfig) {
+    cfg.app_data(JsonConfig::default().error_handler(error::json_error_handler))
+        .app_data(PathConfig::default().error_handler(error::path_error_handler))
+        .app_data(QueryConfig::default().error_handler(error::query_error_handler))
+        .service(
+            web::resource("/users")
+                .route(named(web::get().to(users::get_users::<R>), "get_users"))
+                .route(named(web::post().to(users::create_user::<R>), "create_user")),
+        )
+        // Before `/users/{id}`, which would take `events` and `live` for ids.
+        .service(web::resource("/users/events").route(named(web::get().to(events::user_events::<R>), "user_events")))
+        .service(web::resource("/users/live").route(named(web::get().to(live::live_users::<R>), "live_users")))
+        .service(
+            web::resource("/users/{id}")
+                .route(named(web::get().to(users::get_user::<R>), "get_user"))
+                .route(named(web::put().to(users::replace_user::<R>), "replace_user"))
+                .route(named(web::patch().to(users::patch_user::<R>), "patch_user"))
+                .route(named(web::delete().to(users::delete_user::<R>), "delete_user")),
+        )
+        .service(
+            web::resource("/users/{id}/password")
+                .route(named(web::put().to(users::change_password::<R>), "change_password")),
+        )
+        .service(web::resource("/users/{id}/deactivate").route(named(web::post().to(users::deactivate_user::<R>), "deactivate_user")))
+        .service(web::resource("/users/{id}/reactivate").route(named(web::post().to(users::reactivate_user::<R>), "reactivate_user")))
+        .service(
+            web::resource("/users/{id}/roles/{role}")
+                .route(named(web::put().to(users::grant_role::<R>), "grant_role"))
+                .route(named(web::delete().to(users::revoke_role::<R>), "revoke_role")),
+        )
+        .service(
+            web::resource("/webhooks")
+                .route(named(web::get().to(webhooks::get_webhooks::<R>), "get_webhooks"))
+                .route(named(web::post().to(webhooks::create_webhook::<R>), "create_webhook")),
+        )
+        .service(
+            web::resource("/webhooks/{id}")
+                .route(named(web::get().to(webhooks::get_webhook::<R>), "get_webhook"))
+                .route(named(web::delete().to(webhooks::delete_webhook::<R>), "delete_webhook")),
+        )
+        .service(web::resource("/webhooks/{id}/deliveries").route(named(web::get().to(webhooks::get_deliveries::<R>), "get_deliveries")))
+        .service(
+            web::resource("/webhooks/{id}/deliveries/{delivery_id}/redeliver")
+                .route(named(web::post().to(webhooks::redeliver::<R>), "redeliver")),
+        )
+        .service(web::resource("/auth/login").route(named(web::post().to(auth::login::<R>), "login")))
+        .service(web::resource("/auth/refresh").route(named(web::post().to(auth::refresh::<R>), "refresh")));
+}
+
+/// `route` reporting to the metrics and limited to its quota under `name`. Requests turned away
+/// by the limiter are still counted.
+fn named(route: Route, name: &'static str) -> Route {
+    instrument(limit(route, name), name)
+}
+
+/// The whole API mounted under `path`, with problem responses for unmatched routes below it.
+pub fn scope<R: UserRepo + 'static>(path: &str) -> impl HttpServiceFactory {
+    web::scope(path)
+        .wrap(from_fn(problem_instance))
+        .configure(configure::<R>)
+        .default_service(web::to(error::not_found))
+}
+
+/// Serves `doc` (e.g. [`openapi`]`(prefix)`) as JSON at `path`.
+pub fn openapi_route(path: &str, doc: &utoipa::openapi::OpenApi) -> Resource {
+    let body = serde_json::to_string(doc).expect("OpenAPI document serializes");
+
+    web::resource(path).route(web::get().to(move || {
+        let body = body.clone();
+        async move { HttpResponse::Ok().content_type(ContentType::json()).body(body) }
+    }))
+}
+
+/// OpenAPI document for the API as mounted under `prefix` (e.g. `/api`).
+pub fn openapi(prefix: &str) -> utoipa::openapi::OpenApi {
+    nested(prefix, ApiDoc::openapi())
+}
+
+/// `doc` with every path prefixed by `prefix`, keeping its info and components.
+fn nested(prefix: &str, doc: utoipa::openapi::OpenApi) -> utoipa::openapi::OpenApi {
+    let mut base = doc.clone();
+    base.paths = utoipa::openapi::Paths::new();
+    base.nest(prefix, doc)
+}
@@ -0,0 +1,76 @@
+//! Per-route enforcement of the [`RateLimiter`]'s quotas.
+
+use super::auth::authenticate;
+use super::error::ApiError;
+use crate::rate_limit::{Decision, RateLimiter};
+use actix_web::body::BoxBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::web::Data;
+use actix_web::{Error, Route};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Limits every request to `route` by the quota named `name`, if `Data<RateLimiter>` is
+/// registered; otherwise the route is left as is.
+pub(super) fn limit(route: Route, name: &'static str) -> Route {
+    route.wrap(from_fn(move |req: ServiceRequest, next: Next<BoxBody>| check(name, req, next)))
+}
+
+async fn check(name: &'static str, req: ServiceRequest, next: Next<BoxBody>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limiter) = req.app_data::<Data<RateLimiter>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let client = client(&req, &limiter);
+    let decision = match limiter.acquire(name, &client).await {
+        Ok(decision) => decision,
+        // Better to serve everyone than no one while the store is down.
+        Err(e) => {
+            warn!("Rate limit store failed, letting the request through: {e}");
+            return next.call(req).await;
+        }
+    };
+
+    if !decision.allowed {
+        info!("Rate limited {client} on {name}");
+        return Ok(req.error_response(ApiError::TooManyRequests(decision)));
+    }
+
+    let mut res = next.call(req).await?;
+    for (name, value) in rate_limit_headers(&decision) {
+        res.headers_mut().insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+    Ok(res)
+}
+
+/// Who the request counts against: the user of a valid access token, so that a user's quota
+/// follows them across addresses, or else the client's IP address.
+fn client(req: &ServiceRequest, limiter: &RateLimiter) -> String {
+    if let Ok(authenticated) = authenticate(req.request()) {
+        return format!("user:{}", authenticated.user_id);
+    }
+
+    let info = req.connection_info();
+    let address = match limiter.trusts_forwarded() {
+        true => info.realip_remote_addr(),
+        false => info.peer_addr(),
+    };
+    format!("ip:{}", address.unwrap_or("unknown"))
+}
+
+/// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (in seconds), as in the IETF
+/// rate limit headers draft.
+pub(super) fn rate_limit_headers(decision: &Decision) -> [(&'static str, u64); 3] {
+    [
+        ("ratelimit-limit", decision.limit.into()),
+        ("ratelimit-remaining", decision.remaining.into()),
+        ("ratelimit-reset", seconds(decision.reset_after)),
+    ]
+}
+
+/// `duration` in whole seconds, rounded up so that clients never retry too early.
+pub(super) fn seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
+}
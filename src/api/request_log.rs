@@ -0,0 +1,106 @@
+//! Request ids and the access log.
+//!
+//! [`request_log`] gives every request an id and puts it in the log MDC under `request_id`
+//! while the request is handled, so every record logged on its behalf, down to the repository
+//! adapters, carries it. log4rs shows it with `{X(request_id)}` in a pattern, and the JSON
+//! encoder includes it under `mdc`.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, USER_AGENT};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use log::info;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use uuid::Uuid;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Key of the request id in the log MDC.
+pub const MDC_KEY: &str = "request_id";
+
+/// Longest `X-Request-Id` taken from a client; longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+/// Takes the request's `X-Request-Id`, or makes up one, logs everything done for the request
+/// with it and echoes it in the response. Then writes one line to the `Access` log target with
+/// the `method`, `path`, `status`, `latency_ms` and `user_agent` as key-values, which log4rs'
+/// JSON encoder renders as `attributes`.
+pub async fn request_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned);
+    let method = req.method().clone();
+    let path = req.path().to_owned();
+    let user_agent = req.headers().get(USER_AGENT).and_then(|value| value.to_str().ok()).unwrap_or_default().to_owned();
+
+    let started = Instant::now();
+    let result = WithRequestId::new(&id, next.call(req)).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    with_request_id(&id, || {
+        info!(
+            target: "Access",
+            method = method.as_str(),
+            path = path.as_str(),
+            status = status.as_u16(),
+            latency_ms = latency_ms,
+            user_agent = user_agent.as_str();
+            "{method} {path} {} {latency_ms:.1}ms", status.as_u16()
+        )
+    });
+
+    let mut res = result?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID, value);
+    }
+    Ok(res)
+}
+
+/// Ids are echoed into headers and logs, so only visible ASCII is taken.
+fn is_valid(id: &str) -> bool {
+    (1..=MAX_REQUEST_ID_LENGTH).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Runs `f` with `id` in the MDC, restoring whatever was there before.
+fn with_request_id<T>(id: &str, f: impl FnOnce() -> T) -> T {
+    let _guard = log_mdc::insert_scoped(MDC_KEY, id);
+    f()
+}
+
+/// A future that has its request id in the MDC whenever it runs.
+///
+/// The MDC is per thread, and a worker thread takes turns polling many requests, so the id
+/// is put in place for each poll rather than once per request.
+struct WithRequestId<F> {
+    id: String,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> WithRequestId<F> {
+    fn new(id: &str, inner: F) -> Self {
+        Self { id: id.to_owned(), inner: Box::pin(inner) }
+    }
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        with_request_id(&this.id, || this.inner.as_mut().poll(cx))
+    }
+}
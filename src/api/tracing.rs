@@ -0,0 +1,78 @@
+//! A server span per request, continuing the client's trace if it sent a W3C `traceparent`.
+
+use crate::telemetry::Tracing;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, USER_AGENT};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+use opentelemetry::context::FutureExt;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+/// Handles the request inside a span named after its method and route pattern (e.g.
+/// `GET /api/users/{id}`), if `Data<Tracing>` is registered; otherwise the request is handled
+/// as is. Repository calls made meanwhile become the span's children.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(tracing) = req.app_data::<Data<Tracing>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let parent = TraceContextPropagator::new().extract(&Headers(req.headers()));
+    let method = req.method().to_string();
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.path", req.path().to_owned()),
+        KeyValue::new("url.scheme", req.connection_info().scheme().to_owned()),
+    ];
+    if let Some(user_agent) = req.headers().get(USER_AGENT).and_then(|value| value.to_str().ok()) {
+        attributes.push(KeyValue::new("user_agent.original", user_agent.to_owned()));
+    }
+
+    let tracer = tracing.tracer();
+    let span = tracer
+        .span_builder(method.clone())
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(tracer, &parent);
+    let cx = parent.with_span(span);
+    let result = next.call(req).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    let status = match &result {
+        Ok(res) => {
+            if let Some(route) = res.request().match_pattern() {
+                span.update_name(format!("{method} {route}"));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            res.status()
+        }
+        Err(e) => e.as_response_error().status_code(),
+    };
+    span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+    // Client errors are the client's; only server errors fail the span.
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    span.end();
+    result
+}
+
+/// Request headers as the propagator reads them.
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
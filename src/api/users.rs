@@ -0,0 +1,417 @@
+use super::auth::Authenticated;
+use super::dto::{ChangePasswordDto, CreateUserDto, ListUsersParams, PatchUserDto, RoleDto, UpdateUserDto, UserDto, UserPageDto};
+use super::error::{ApiError, ProblemDetails};
+use super::idempotency::idempotent;
+use crate::app::Application;
+use crate::idempotency::fingerprint;
+use crate::users::{UserQuery, UserRepo};
+use crate::validation::ValidationErrors;
+use actix_web::http::header::LOCATION;
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use log::info;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(ListUsersParams),
+    responses(
+        (status = 200, description = "One page of users", body = UserPageDto),
+        (status = 400, description = "Invalid cursor or query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is neither an admin nor an auditor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn get_users<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    params: Query<ListUsersParams>,
+) -> Result<Json<UserPageDto>, ApiError> {
+    info!("Fetching users");
+    let caller = auth.caller(&data).await?;
+    let query = UserQuery::try_from(params.into_inner())?;
+    let page = data.list_users(&caller, &query).await?;
+    Ok(Json(UserPageDto::from(page)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    request_body = CreateUserDto,
+    params(
+        ("Idempotency-Key" = Option<String>, Header,
+            description = "Up to 255 visible ASCII characters, unique per user to create. A retry with the same key and \
+                username and email gets the first response back, with `Idempotent-Replayed: true`, instead of creating \
+                the user again; keys are kept for 24 hours by default")
+    ),
+    responses(
+        (status = 201, description = "User created successfully", body = UserDto,
+            headers(("Location" = String, description = "URL of the new user"))),
+        (status = 400, description = "Malformed body or `Idempotency-Key`, or invalid username, email or password", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 409, description = "Username or email already taken, or a request with the same `Idempotency-Key` is still being handled", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying; only while the key is in use"))),
+        (status = 422, description = "`Idempotency-Key` already used for a different username or email", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit exceeded; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying"),
+                ("RateLimit-Limit" = u32, description = "Requests allowed in a burst"),
+                ("RateLimit-Remaining" = u32, description = "Requests left"),
+                ("RateLimit-Reset" = u64, description = "Seconds until the quota is fully restored"))),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn create_user<R: UserRepo>(
+    req: HttpRequest,
+    data: Data<Application<R>>,
+    user_dto: Json<CreateUserDto>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Creating user: {}", user_dto.username);
+
+    // The password is left out, as it would otherwise be kept (hashed) with the key.
+    let request = fingerprint(&("POST", req.path(), &user_dto.username, &user_dto.email));
+    idempotent(&req, &data, &request, async {
+        let user = data
+            .register(&user_dto.username, &user_dto.email, user_dto.password.as_deref())
+            .await?;
+
+        Ok(HttpResponse::Created()
+            .insert_header((LOCATION, format!("{}/{}", req.path(), user.id)))
+            .json(UserDto::from(user)))
+    })
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Get user by ID", body = UserDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Not your own account, and caller is neither an admin nor an auditor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn get_user<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
+    info!("Fetching user: {}", id);
+    let caller = auth.caller(&data).await?;
+    let user = data.get_user(&caller, *id).await?;
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = UpdateUserDto,
+    responses(
+        (status = 200, description = "User replaced successfully", body = UserDto),
+        (status = 400, description = "Id is not a UUID, malformed body, or invalid username or email", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Not your own account, and caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 409, description = "Username or email already taken", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn replace_user<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    id: Path<Uuid>,
+    user_dto: Json<UpdateUserDto>,
+) -> Result<Json<UserDto>, ApiError> {
+    info!("Replacing user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data
+        .update_user(&caller, *id, &user_dto.username, &user_dto.email)
+        .await?;
+
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body(content = PatchUserDto, content_type = "application/merge-patch+json"),
+    responses(
+        (status = 200, description = "User patched successfully", body = UserDto),
+        (status = 400, description = "Id is not a UUID, malformed patch, or patched user is invalid", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Not your own account, and caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 409, description = "Username or email already taken", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn patch_user<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    id: Path<Uuid>,
+    patch: Json<Value>,
+) -> Result<Json<UserDto>, ApiError> {
+    info!("Patching user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let current = data.get_user(&caller, *id).await?;
+
+    let mut target = serde_json::json!({
+        "username": current.username,
+        "email": current.email,
+    });
+    merge_patch(&mut target, &patch);
+
+    let mut errors = ValidationErrors::default();
+    let username = patched_string(&target, "username", &mut errors);
+    let email = patched_string(&target, "email", &mut errors);
+    errors.into_result(())?;
+
+    let user = data.update_user(&caller, *id, &username, &email).await?;
+
+    Ok(Json(UserDto::from(user)))
+}
+
+/// Reads a required string field from a patched user document, recording an error if it was
+/// removed (patched to `null`) or replaced with a non-string.
+fn patched_string(target: &Value, field: &'static str, errors: &mut ValidationErrors) -> String {
+    match target.get(field) {
+        Some(Value::String(value)) => value.clone(),
+        Some(_) => {
+            errors.add(field, "invalid_type", "must be a string");
+            String::new()
+        }
+        None => {
+            errors.add(field, "required", "must not be null");
+            String::new()
+        }
+    }
+}
+
+/// Applies `patch` to `target` following RFC 7386 JSON Merge Patch.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    let target = target.as_object_mut().expect("target is an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}/password",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    request_body = ChangePasswordDto,
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 400, description = "Id is not a UUID, malformed body, or new password violates the policy", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token (with `WWW-Authenticate`), or current password missing or wrong", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`, when the access token is at fault"))),
+        (status = 403, description = "Not your own account", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn change_password<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    id: Path<Uuid>,
+    dto: Json<ChangePasswordDto>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Changing password of user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    data.change_password(&caller, *id, dto.current_password.as_deref(), &dto.new_password)
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "User deleted successfully", body = UserDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Not your own account, and caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn delete_user<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
+    info!("Deleting user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data.delete_user(&caller, *id).await?;
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{id}/deactivate",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user, now unable to log in or use their tokens; data is kept", body = UserDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Not your own account, and caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn deactivate_user<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
+    info!("Deactivating user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data.deactivate(&caller, *id).await?;
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{id}/reactivate",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user, active again", body = UserDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn reactivate_user<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
+    info!("Reactivating user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data.reactivate(&caller, *id).await?;
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}/roles/{role}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("role" = RoleDto, Path, description = "Role to grant")
+    ),
+    responses(
+        (status = 200, description = "The user, now with the role; granting a role twice is a no-op", body = UserDto),
+        (status = 400, description = "Id is not a UUID or role is unknown", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn grant_role<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    path: Path<(Uuid, RoleDto)>,
+) -> Result<Json<UserDto>, ApiError> {
+    let (id, role) = path.into_inner();
+    info!("Granting role to user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data.grant_role(&caller, id, role.into()).await?;
+    Ok(Json(UserDto::from(user)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/roles/{role}",
+    tag = "users",
+    security(("bearer_auth" = [])),
+    params(
+        ("id" = Uuid, Path, description = "User id"),
+        ("role" = RoleDto, Path, description = "Role to revoke")
+    ),
+    responses(
+        (status = 200, description = "The user, now without the role; revoking a missing role is a no-op", body = UserDto),
+        (status = 400, description = "Id is not a UUID or role is unknown", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin, or is revoking their own admin role", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn revoke_role<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    path: Path<(Uuid, RoleDto)>,
+) -> Result<Json<UserDto>, ApiError> {
+    let (id, role) = path.into_inner();
+    info!("Revoking role from user: {}", id);
+    let caller = auth.caller(&data).await?;
+
+    let user = data.revoke_role(&caller, id, role.into()).await?;
+    Ok(Json(UserDto::from(user)))
+}
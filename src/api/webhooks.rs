@@ -0,0 +1,181 @@
+use super::auth::Authenticated;
+use super::dto::{CreateWebhookDto, DeliveryDto, ListDeliveriesParams, WebhookDto};
+use super::error::{ApiError, ProblemDetails};
+use crate::app::Application;
+use crate::users::UserRepo;
+use actix_web::http::header::LOCATION;
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{HttpRequest, HttpResponse};
+use log::info;
+use uuid::Uuid;
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Every webhook, oldest first", body = [WebhookDto]),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn get_webhooks<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>) -> Result<Json<Vec<WebhookDto>>, ApiError> {
+    info!("Fetching webhooks");
+    let caller = auth.caller(&data).await?;
+    let webhooks = data.list_webhooks(&caller).await?;
+    Ok(Json(webhooks.into_iter().map(WebhookDto::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    request_body = CreateWebhookDto,
+    responses(
+        (status = 201, description = "Webhook created; matching events are delivered from now on", body = WebhookDto,
+            headers(("Location" = String, description = "URL of the new webhook"))),
+        (status = 400, description = "Malformed body, unknown event type, or invalid URL or secret", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn create_webhook<R: UserRepo>(
+    req: HttpRequest,
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    dto: Json<CreateWebhookDto>,
+) -> Result<HttpResponse, ApiError> {
+    info!("Creating webhook for {}", dto.url);
+    let caller = auth.caller(&data).await?;
+
+    let event_types: Vec<String> = dto.event_types.iter().map(|event_type| event_type.as_str().to_owned()).collect();
+    let webhook = data.create_webhook(&caller, &dto.url, &dto.secret, &event_types).await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, format!("{}/{}", req.path(), webhook.id)))
+        .json(WebhookDto::from(webhook)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "The webhook", body = WebhookDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Webhook not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn get_webhook<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<WebhookDto>, ApiError> {
+    info!("Fetching webhook: {}", id);
+    let caller = auth.caller(&data).await?;
+    let webhook = data.get_webhook(&caller, *id).await?;
+    Ok(Json(WebhookDto::from(webhook)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "Webhook deleted with its delivery log; pending deliveries are dropped", body = WebhookDto),
+        (status = 400, description = "Id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Webhook not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn delete_webhook<R: UserRepo>(auth: Authenticated, data: Data<Application<R>>, id: Path<Uuid>) -> Result<Json<WebhookDto>, ApiError> {
+    info!("Deleting webhook: {}", id);
+    let caller = auth.caller(&data).await?;
+    let webhook = data.delete_webhook(&caller, *id).await?;
+    Ok(Json(WebhookDto::from(webhook)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    params(("id" = Uuid, Path, description = "Webhook id"), ListDeliveriesParams),
+    responses(
+        (status = 200, description = "The webhook's delivery log, newest first", body = [DeliveryDto]),
+        (status = 400, description = "Id is not a UUID or invalid query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Webhook not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn get_deliveries<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    id: Path<Uuid>,
+    params: Query<ListDeliveriesParams>,
+) -> Result<Json<Vec<DeliveryDto>>, ApiError> {
+    info!("Fetching deliveries of webhook: {}", id);
+    let caller = auth.caller(&data).await?;
+    let deliveries = data.webhook_deliveries(&caller, *id, params.limit()).await?;
+    Ok(Json(deliveries.into_iter().map(DeliveryDto::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/webhooks/{id}/deliveries/{delivery_id}/redeliver",
+    tag = "webhooks",
+    security(("bearer_auth" = [])),
+    params(
+        ("id" = Uuid, Path, description = "Webhook id"),
+        ("delivery_id" = Uuid, Path, description = "Delivery id")
+    ),
+    responses(
+        (status = 202, description = "Delivery queued to be sent again right away, with a fresh set of attempts", body = DeliveryDto),
+        (status = 400, description = "An id is not a UUID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or expired access token", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("WWW-Authenticate" = String, description = "`Bearer`"))),
+        (status = 403, description = "Caller is not an admin", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "No such delivery to this webhook", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Unexpected server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "User store unavailable; see `Retry-After`", body = ProblemDetails, content_type = "application/problem+json",
+            headers(("Retry-After" = u64, description = "Seconds to wait before retrying")))
+    )
+)]
+pub(super) async fn redeliver<R: UserRepo>(
+    auth: Authenticated,
+    data: Data<Application<R>>,
+    path: Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, delivery_id) = path.into_inner();
+    info!("Redelivering {} to webhook: {}", delivery_id, id);
+    let caller = auth.caller(&data).await?;
+
+    let delivery = data.redeliver(&caller, id, delivery_id).await?;
+    Ok(HttpResponse::Accepted().json(DeliveryDto::from(delivery)))
+}
@@ -1,12 +1,780 @@
-use crate::users::UserRepo;
+use crate::config::{IdempotencySettings, RelaySettings, WebhookSettings};
+use crate::events::{now_millis, EventSink, RecordedEvent, SinkError, UserEvent};
+use crate::idempotency::{Claim, IdempotencyRecord, StoredResponse};
+use crate::passwords::{PasswordError, Passwords};
+use crate::policy::{self, Action, Caller, Denied};
+use crate::users::{Role, User, UserPage, UserQuery, UserRepo, UserRepoError};
+use crate::validation::{
+    normalize_login, validate_new_user, validate_password, validate_user_fields, validate_webhook, UserFields, ValidationErrors,
+};
+use crate::webhooks::{Delivery, DeliveryStatus, Webhook, WebhookSender};
+use futures_util::future::join_all;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, watch, Notify};
+use uuid::Uuid;
 
+/// Deliberately the same whether the user is unknown, has no password or gave the wrong one.
+pub const INVALID_CREDENTIALS: &str = "invalid username, email or password";
+
+const USER_GONE: &str = "user no longer exists";
+
+const DEACTIVATED: &str = "account is deactivated";
+
+/// How many events a slow subscriber may fall behind before it starts missing some, and the
+/// default number kept for [`Application::watch_users`] to replay.
+const EVENT_CAPACITY: usize = 1024;
+
+/// The user management use cases.
+///
+/// Every operation goes through here, whoever calls it, so validation, normalization, the
+/// access policy and [`UserEvent`]s apply the same way to the HTTP API, the command line and
+/// tests. The repository itself is not exposed.
+///
+/// Events are recorded in the repository's outbox with each change, and only reach
+/// [`EventSink`]s, subscribers and webhooks through the relay ([`run_relay`](Self::run_relay)).
+/// The relay queues a delivery per subscribed webhook, which the webhook dispatcher
+/// ([`run_webhooks`](Self::run_webhooks)) then sends, retrying until the receiver accepts it.
 pub struct Application<U: UserRepo> {
-    pub users: U,
+    users: U,
+    passwords: Passwords,
+    sinks: Vec<Arc<dyn EventSink>>,
+    /// Taken by [`close_subscriptions`](Self::close_subscriptions), which ends them all.
+    events: Mutex<Option<broadcast::Sender<RecordedEvent>>>,
+    /// The latest relayed events, oldest first, for subscribers catching up after a disconnect.
+    recent: Mutex<VecDeque<RecordedEvent>>,
+    replay_capacity: usize,
+    idempotency: IdempotencySettings,
+    /// Wakes the relay when a change may have recorded events.
+    recorded: Notify,
+    /// Wakes the webhook dispatcher when deliveries may have become due.
+    queued: Notify,
+    /// Set by [`stop`](Self::stop), for the relay and the webhook dispatcher to return.
+    stopping: watch::Sender<bool>,
+}
+
+/// Why an [`Application`] operation failed.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("no such user")]
+    NotFound,
+
+    #[error("no such webhook")]
+    WebhookNotFound,
+
+    #[error("no such webhook delivery")]
+    DeliveryNotFound,
+
+    #[error(transparent)]
+    Validation(#[from] ValidationErrors),
+
+    /// The caller is not allowed to do this.
+    #[error("denied: {0}")]
+    Denied(#[from] Denied),
+
+    /// Credentials were wrong, or their user can no longer act. Safe to show to the caller.
+    #[error("{0}")]
+    Unauthenticated(&'static str),
+
+    #[error(transparent)]
+    Password(#[from] PasswordError),
+
+    #[error(transparent)]
+    Repo(#[from] UserRepoError),
+}
+
+/// What [`Application::watch_users`] hands a subscriber.
+pub struct UserChanges {
+    /// Events relayed after the one the subscriber saw last, oldest first. `None` if that event
+    /// is no longer (or never was) in the replay buffer, so some changes may have been missed.
+    pub missed: Option<Vec<RecordedEvent>>,
+    /// Every event relayed from now on.
+    pub live: broadcast::Receiver<RecordedEvent>,
+}
+
+/// Why [`Application::relay_events`] stopped short.
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error(transparent)]
+    Repo(#[from] UserRepoError),
+
+    #[error("sink {sink} failed to take event {event}: {source}")]
+    Sink { sink: String, event: Uuid, source: SinkError },
 }
 
 impl<U: UserRepo> Application<U> {
+    /// An application hashing passwords with the default settings.
     pub fn new(users: U) -> Self {
-        Application { users }
+        Application {
+            users,
+            passwords: Passwords::default(),
+            sinks: Vec::new(),
+            events: Mutex::new(Some(broadcast::channel(EVENT_CAPACITY).0)),
+            recent: Mutex::new(VecDeque::new()),
+            replay_capacity: EVENT_CAPACITY,
+            idempotency: IdempotencySettings::default(),
+            recorded: Notify::new(),
+            queued: Notify::new(),
+            stopping: watch::Sender::new(false),
+        }
+    }
+
+    pub fn with_passwords(self, passwords: Passwords) -> Self {
+        Application { passwords, ..self }
+    }
+
+    /// Keeps the latest `capacity` relayed events for [`watch_users`](Self::watch_users) to
+    /// replay; 0 disables resuming.
+    pub fn with_replay_capacity(self, replay_capacity: usize) -> Self {
+        Application { replay_capacity, ..self }
+    }
+
+    /// How long idempotency keys are kept; see [`claim_idempotency_key`](Self::claim_idempotency_key).
+    pub fn with_idempotency(self, idempotency: IdempotencySettings) -> Self {
+        Application { idempotency, ..self }
+    }
+
+    /// Adds a destination for the relay to deliver every event to.
+    pub fn with_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// The same application on a wrapped repository, e.g. a
+    /// [`MeteredUserRepo`](crate::metrics::MeteredUserRepo).
+    pub fn map_users<V: UserRepo>(self, wrap: impl FnOnce(U) -> V) -> Application<V> {
+        Application {
+            users: wrap(self.users),
+            passwords: self.passwords,
+            sinks: self.sinks,
+            events: self.events,
+            recent: self.recent,
+            replay_capacity: self.replay_capacity,
+            idempotency: self.idempotency,
+            recorded: self.recorded,
+            queued: self.queued,
+            stopping: self.stopping,
+        }
+    }
+
+    /// Events from now on, as the relay delivers them. A receiver that falls more than 1024
+    /// events behind skips the oldest ones.
+    ///
+    /// After [`close_subscriptions`](Self::close_subscriptions) the receiver is closed at once.
+    pub fn subscribe(&self) -> broadcast::Receiver<RecordedEvent> {
+        match &*self.events.lock().expect("event sender lock poisoned") {
+            Some(events) => events.subscribe(),
+            // A channel whose sender is gone is closed from the start.
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Live events for `caller`, who must be allowed to list users, preceded by those relayed
+    /// after event `after` if it is still in the replay buffer. Nothing is replayed without
+    /// `after`.
+    pub fn watch_users(&self, caller: &Caller, after: Option<Uuid>) -> Result<UserChanges, AppError> {
+        self.authorize(caller, Action::ListUsers)?;
+
+        // Subscribing under the lock the relay publishes under means no event is both replayed
+        // and received live, and none falls between the two.
+        let recent = self.recent.lock().expect("replay buffer lock poisoned");
+        let missed = match after {
+            None => Some(Vec::new()),
+            Some(after) => recent
+                .iter()
+                .rposition(|event| event.id == after)
+                .map(|seen| recent.iter().skip(seen + 1).cloned().collect()),
+        };
+        Ok(UserChanges { missed, live: self.subscribe() })
+    }
+
+    /// Checks that user `id` may still [`watch_users`](Self::watch_users) with their current
+    /// roles. Subscriptions outlive the request that opened them, so they check again as they go.
+    pub async fn authorize_watch(&self, id: Uuid) -> Result<(), AppError> {
+        let caller = self.caller(id).await?;
+        Ok(self.authorize(&caller, Action::ListUsers)?)
+    }
+
+    /// Delivers up to `limit` events from the outbox, oldest first, to every sink and then to
+    /// subscribers, and removes them from the outbox. Returns how many were delivered.
+    ///
+    /// Before the sinks, each event is queued for every webhook subscribed to it. Stops at the
+    /// first event that fails to queue or that a sink fails to take, so that it and every later
+    /// event are retried next time, in order.
+    pub async fn relay_events(&self, limit: usize) -> Result<usize, RelayError> {
+        let pending = self.users.pending_events(limit).await?;
+        let webhooks = match pending.is_empty() {
+            true => Vec::new(),
+            false => self.users.list_webhooks().await?,
+        };
+
+        let mut delivered = Vec::with_capacity(pending.len());
+        let mut queued = false;
+        let mut failure = None;
+        'events: for recorded in &pending {
+            let deliveries: Vec<_> = webhooks
+                .iter()
+                .filter(|webhook| webhook.wants(recorded))
+                .map(|webhook| Delivery::new(webhook, recorded))
+                .collect();
+            // Queueing again after a failure further on is harmless: there is at most one
+            // delivery per webhook and event.
+            if let Err(e) = self.users.add_deliveries(&deliveries).await {
+                failure = Some(RelayError::Repo(e));
+                break 'events;
+            }
+            queued |= !deliveries.is_empty();
+
+            for sink in &self.sinks {
+                if let Err(source) = sink.deliver(recorded).await {
+                    failure = Some(RelayError::Sink {
+                        sink: sink.name().to_owned(),
+                        event: recorded.id,
+                        source,
+                    });
+                    break 'events;
+                }
+            }
+            self.publish(recorded);
+            delivered.push(recorded.id);
+        }
+
+        if queued {
+            self.queued.notify_one();
+        }
+
+        // If this fails the events are delivered again later, which at least once allows.
+        self.users.mark_delivered(&delivered).await?;
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(delivered.len()),
+        }
+    }
+
+    /// Relays events until [stopped](Self::stop): right after local changes, every poll
+    /// interval for events recorded by other processes, and with exponential backoff while
+    /// delivery fails. Once stopped, it empties the outbox before returning, unless delivery
+    /// fails; whatever is left is relayed after the next start.
+    pub async fn run_relay(&self, settings: &RelaySettings) {
+        let batch_size = settings.batch_size.max(1);
+        let poll_interval = Duration::from_millis(settings.poll_interval_ms);
+        let retry_initial = Duration::from_millis(settings.retry_initial_ms);
+        let retry_max = Duration::from_millis(settings.retry_max_ms);
+
+        let mut stopping = self.stopping.subscribe();
+        let mut backoff = retry_initial;
+        loop {
+            let stopped = *stopping.borrow_and_update();
+            match self.relay_events(batch_size).await {
+                // A full batch suggests more are waiting.
+                Ok(relayed) if relayed == batch_size => backoff = retry_initial,
+                Ok(_) if stopped => return,
+                Ok(_) => {
+                    backoff = retry_initial;
+                    tokio::select! {
+                        _ = self.recorded.notified() => {}
+                        _ = tokio::time::sleep(poll_interval) => {}
+                        _ = stopping.changed() => {}
+                    }
+                }
+                Err(e) if stopped => {
+                    log::warn!(target: "Events", "Relaying events failed, leaving the rest in the outbox: {e}");
+                    return;
+                }
+                Err(e) => {
+                    log::warn!(target: "Events", "Relaying events failed, retrying in {backoff:?}: {e}");
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = stopping.changed() => {}
+                    }
+                    backoff = (backoff * 2).min(retry_max);
+                }
+            }
+        }
+    }
+
+    /// Makes one attempt at up to `settings.batch_size` webhook deliveries that are due,
+    /// concurrently, and records the outcome of each. Returns how many were attempted.
+    pub async fn dispatch_webhooks(&self, sender: &WebhookSender, settings: &WebhookSettings) -> Result<usize, UserRepoError> {
+        let due = self.users.due_deliveries(now_millis(), settings.batch_size.max(1)).await?;
+
+        let mut webhooks = HashMap::new();
+        for delivery in &due {
+            if let Entry::Vacant(entry) = webhooks.entry(delivery.webhook_id) {
+                entry.insert(self.users.get_webhook(delivery.webhook_id).await?);
+            }
+        }
+
+        let attempted = due.len();
+        let attempts = due.into_iter().map(|mut delivery| {
+            let webhook = webhooks.get(&delivery.webhook_id).cloned().flatten();
+            async move {
+                match webhook {
+                    Some(webhook) => {
+                        let outcome = sender.send(&webhook, &delivery).await;
+                        delivery.record_attempt(now_millis(), outcome, settings);
+                    }
+                    // Removed while the delivery was being queued.
+                    None => {
+                        delivery.status = DeliveryStatus::Failed;
+                        delivery.next_attempt_at = None;
+                        delivery.last_error = Some("webhook no longer exists".to_owned());
+                    }
+                }
+
+                match &delivery.last_error {
+                    None => log::info!(target: "Webhooks", "Delivered {} to webhook {}", delivery.id, delivery.webhook_id),
+                    Some(e) => log::warn!(
+                        target: "Webhooks",
+                        "Delivery {} to webhook {} failed (attempt {}, now {}): {e}",
+                        delivery.id, delivery.webhook_id, delivery.attempts, delivery.status
+                    ),
+                }
+                self.users.update_delivery(&delivery).await
+            }
+        });
+
+        for result in join_all(attempts).await {
+            result?;
+        }
+        Ok(attempted)
+    }
+
+    /// Sends webhook deliveries until [stopped](Self::stop): right after the relay queues some,
+    /// and every poll interval for retries that have become due. Once stopped, it returns as
+    /// soon as the batch being sent is done; deliveries still due are sent after the next start.
+    pub async fn run_webhooks(&self, sender: &WebhookSender, settings: &WebhookSettings) {
+        let batch_size = settings.batch_size.max(1);
+        let poll_interval = Duration::from_millis(settings.poll_interval_ms);
+
+        let mut stopping = self.stopping.subscribe();
+        loop {
+            if *stopping.borrow_and_update() {
+                return;
+            }
+            match self.dispatch_webhooks(sender, settings).await {
+                // A full batch suggests more are due.
+                Ok(attempted) if attempted == batch_size => continue,
+                Ok(_) => {}
+                Err(e) => log::warn!(target: "Webhooks", "Dispatching webhook deliveries failed: {e}"),
+            }
+            tokio::select! {
+                _ = self.queued.notified() => {}
+                _ = tokio::time::sleep(poll_interval) => {}
+                _ = stopping.changed() => {}
+            }
+        }
+    }
+
+    /// Ends every subscription, so that event streams and WebSockets close instead of keeping
+    /// a stopping server waiting; later ones end at once.
+    pub fn close_subscriptions(&self) {
+        self.events.lock().expect("event sender lock poisoned").take();
+    }
+
+    /// Has [`run_relay`](Self::run_relay) and [`run_webhooks`](Self::run_webhooks) finish their
+    /// work and return, once no more requests are coming.
+    pub fn stop(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    /// Closes the repository's connections, once nothing uses the application any more.
+    pub async fn close(&self) -> Result<(), AppError> {
+        Ok(self.users.close().await?)
+    }
+
+    /// Hands `event` to subscribers and keeps it for later ones to replay.
+    fn publish(&self, event: &RecordedEvent) {
+        let mut recent = self.recent.lock().expect("replay buffer lock poisoned");
+        if self.replay_capacity > 0 {
+            if recent.len() >= self.replay_capacity {
+                recent.pop_front();
+            }
+            recent.push_back(event.clone());
+        }
+        // Nobody listening is fine.
+        if let Some(events) = &*self.events.lock().expect("event sender lock poisoned") {
+            let _ = events.send(event.clone());
+        }
+    }
+
+    /// Lets the relay know a change may have recorded events.
+    fn recorded(&self) {
+        self.recorded.notify_one();
+    }
+
+    /// Checks `action` against the access policy, logging refusals.
+    fn authorize(&self, caller: &Caller, action: Action) -> Result<(), Denied> {
+        policy::authorize(caller, action).inspect_err(|denied| {
+            log::warn!("Denied {action:?} to user {}: {denied}", caller.id);
+        })
+    }
+
+    /// Cheap round trip to the repository's backing store, for readiness probes.
+    pub async fn health_check(&self) -> Result<(), UserRepoError> {
+        self.users.health_check().await
+    }
+
+    /// Claims `key` for a request with this [`fingerprint`](crate::idempotency::fingerprint),
+    /// unless it was used before and has not expired yet.
+    ///
+    /// After a [`Claim::New`], [`complete_idempotency_key`](Self::complete_idempotency_key)
+    /// with the response, or [`release_idempotency_key`](Self::release_idempotency_key) if a
+    /// retry should be handled afresh. A claim never completed nor released lapses after the
+    /// lock timeout.
+    pub async fn claim_idempotency_key(&self, key: &str, fingerprint: &str) -> Result<Claim, AppError> {
+        let now = now_millis();
+        let record = IdempotencyRecord {
+            key: key.to_owned(),
+            fingerprint: fingerprint.to_owned(),
+            response: None,
+            expires_at: now + self.idempotency.lock_timeout_secs * 1000,
+        };
+
+        Ok(match self.users.claim_idempotency_key(&record, now).await? {
+            None => Claim::New,
+            Some(existing) if existing.fingerprint != fingerprint => Claim::Mismatch,
+            Some(IdempotencyRecord { response: Some(response), .. }) => Claim::Replay(response),
+            Some(_) => Claim::InFlight,
+        })
+    }
+
+    /// Stores the response to the request that claimed `key`, to replay to its retries until
+    /// the TTL runs out.
+    pub async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse) -> Result<(), AppError> {
+        let expires_at = now_millis() + self.idempotency.ttl_secs * 1000;
+        Ok(self.users.complete_idempotency_key(key, response, expires_at).await?)
+    }
+
+    /// Forgets `key`, so that a retry is handled as if it were the first request.
+    pub async fn release_idempotency_key(&self, key: &str) -> Result<(), AppError> {
+        Ok(self.users.release_idempotency_key(key).await?)
+    }
+
+    /// Signs up a new user, with a password if given. Anyone may register.
+    pub async fn register(&self, username: &str, email: &str, password: Option<&str>) -> Result<User, AppError> {
+        let fields = validate_new_user(username, email, password, self.passwords.policy())?;
+
+        // The hash is stored with the user, so there is never a user without the requested password.
+        let hash = match password {
+            Some(password) => Some(self.passwords.hash(password).await?),
+            None => None,
+        };
+
+        let registered = |_: Option<&User>, user: Option<&User>| {
+            user.map(|user| UserEvent::Registered { user: user.clone() }).into_iter().collect()
+        };
+        let user = self.users.add_user(&fields.username, &fields.email, hash.as_deref(), &registered).await?;
+
+        self.recorded();
+        Ok(user)
+    }
+
+    /// The user with this username or email and password, if they are still active.
+    pub async fn authenticate(&self, login: &str, password: &str) -> Result<User, AppError> {
+        let login = normalize_login(login);
+
+        let user = self.users.find_user_by_login(&login).await?;
+        let hash = match &user {
+            Some(user) => self.users.get_password_hash(user.id).await?,
+            None => None,
+        };
+
+        // Verify even without a hash, so unknown logins cannot be told apart by timing.
+        let valid = self.passwords.verify(password, hash.as_deref()).await?;
+
+        match user {
+            Some(user) if valid && user.active => Ok(user),
+            Some(_) if valid => Err(AppError::Unauthenticated(DEACTIVATED)),
+            _ => Err(AppError::Unauthenticated(INVALID_CREDENTIALS)),
+        }
+    }
+
+    /// User `id`, as long as they exist and are active; for identities vouched for by a token.
+    pub async fn active_user(&self, id: Uuid) -> Result<User, AppError> {
+        match self.users.get_user(id).await? {
+            Some(user) if user.active => Ok(user),
+            Some(_) => Err(AppError::Unauthenticated(DEACTIVATED)),
+            None => Err(AppError::Unauthenticated(USER_GONE)),
+        }
+    }
+
+    /// The caller for user `id` with their current roles. Roles are read on every call, so
+    /// granting or revoking one takes effect immediately.
+    pub async fn caller(&self, id: Uuid) -> Result<Caller, AppError> {
+        Ok(Caller::from(&self.active_user(id).await?))
+    }
+
+    pub async fn list_users(&self, caller: &Caller, query: &UserQuery) -> Result<UserPage, AppError> {
+        self.authorize(caller, Action::ListUsers)?;
+        Ok(self.users.query_users(query).await?)
+    }
+
+    /// How many users match `filter`'s filters, without loading them.
+    pub async fn count_users(&self, caller: &Caller, filter: &UserQuery) -> Result<u64, AppError> {
+        self.authorize(caller, Action::ListUsers)?;
+        Ok(self.users.count_users(filter).await?)
+    }
+
+    /// Every user matching `filter`'s filters, page by page; its limit and cursor are ignored.
+    pub async fn all_users(&self, caller: &Caller, filter: &UserQuery) -> Result<Vec<User>, AppError> {
+        self.authorize(caller, Action::ListUsers)?;
+
+        let mut query = UserQuery {
+            limit: UserQuery::MAX_LIMIT,
+            cursor: None,
+            ..filter.clone()
+        };
+        let mut users = Vec::new();
+        loop {
+            let page = self.users.query_users(&query).await?;
+            users.extend(page.users);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(users),
+            }
+        }
+    }
+
+    pub async fn get_user(&self, caller: &Caller, id: Uuid) -> Result<User, AppError> {
+        self.authorize(caller, Action::ReadUser(id))?;
+        self.users.get_user(id).await?.ok_or(AppError::NotFound)
+    }
+
+    /// The user whose username or email is `login`, normalized like at login.
+    pub async fn find_user(&self, caller: &Caller, login: &str) -> Result<User, AppError> {
+        self.authorize(caller, Action::ListUsers)?;
+        let login = normalize_login(login);
+        self.users.find_user_by_login(&login).await?.ok_or(AppError::NotFound)
+    }
+
+    /// Replaces both the username and the email of user `id`.
+    pub async fn update_user(&self, caller: &Caller, id: Uuid, username: &str, email: &str) -> Result<User, AppError> {
+        self.change_fields(caller, id, Some(username), Some(email)).await
+    }
+
+    pub async fn rename(&self, caller: &Caller, id: Uuid, username: &str) -> Result<User, AppError> {
+        self.change_fields(caller, id, Some(username), None).await
+    }
+
+    pub async fn change_email(&self, caller: &Caller, id: Uuid, email: &str) -> Result<User, AppError> {
+        self.change_fields(caller, id, None, Some(email)).await
+    }
+
+    /// Validates the new username and email (keeping the current one where `None`), stores
+    /// them and records an event for each one that changed.
+    async fn change_fields(
+        &self,
+        caller: &Caller,
+        id: Uuid,
+        username: Option<&str>,
+        email: Option<&str>,
+    ) -> Result<User, AppError> {
+        self.authorize(caller, Action::UpdateUser(id))?;
+
+        let current = self.users.get_user(id).await?.ok_or(AppError::NotFound)?;
+        let fields = validate_user_fields(username.unwrap_or(&current.username), email.unwrap_or(&current.email))?;
+
+        let user = self
+            .users
+            .update_user(id, &fields.username, &fields.email, &field_changes)
+            .await?
+            .ok_or(AppError::NotFound)?;
+
+        self.recorded();
+        Ok(user)
+    }
+
+    /// Sets a new password. `current` must match the existing password, if there is one.
+    pub async fn change_password(
+        &self,
+        caller: &Caller,
+        id: Uuid,
+        current: Option<&str>,
+        new: &str,
+    ) -> Result<(), AppError> {
+        self.authorize(caller, Action::ChangePassword(id))?;
+
+        let user = self.users.get_user(id).await?.ok_or(AppError::NotFound)?;
+
+        if let Some(hash) = self.users.get_password_hash(user.id).await?
+            && !self.passwords.verify(current.unwrap_or_default(), Some(&hash)).await?
+        {
+            return Err(AppError::Unauthenticated("current password is wrong"));
+        }
+
+        let fields = UserFields {
+            username: user.username,
+            email: user.email,
+        };
+        validate_password("new_password", new, self.passwords.policy(), &fields)?;
+
+        let hash = self.passwords.hash(new).await?;
+        let changed = |_: Option<&User>, _: Option<&User>| vec![UserEvent::PasswordChanged { id }];
+        if !self.users.set_password_hash(id, Some(&hash), &changed).await? {
+            return Err(AppError::NotFound);
+        }
+
+        self.recorded();
+        Ok(())
+    }
+
+    /// Stops user `id` from logging in or using their tokens, keeping their data.
+    pub async fn deactivate(&self, caller: &Caller, id: Uuid) -> Result<User, AppError> {
+        self.authorize(caller, Action::DeactivateUser(id))?;
+        self.set_active(id, false).await
+    }
+
+    /// Undoes [`deactivate`](Self::deactivate).
+    pub async fn reactivate(&self, caller: &Caller, id: Uuid) -> Result<User, AppError> {
+        self.authorize(caller, Action::ReactivateUser(id))?;
+        self.set_active(id, true).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool) -> Result<User, AppError> {
+        let user = self.users.set_active(id, active, &activity_changes).await?.ok_or(AppError::NotFound)?;
+        self.recorded();
+        Ok(user)
+    }
+
+    pub async fn delete_user(&self, caller: &Caller, id: Uuid) -> Result<User, AppError> {
+        self.authorize(caller, Action::DeleteUser(id))?;
+
+        let deleted = |user: Option<&User>, _: Option<&User>| {
+            user.map(|user| UserEvent::Deleted { user: user.clone() }).into_iter().collect()
+        };
+        let user = self.users.remove_user(id, &deleted).await?.ok_or(AppError::NotFound)?;
+        self.recorded();
+        Ok(user)
+    }
+
+    /// Gives user `id` the `role`, if `caller` may manage roles.
+    pub async fn grant_role(&self, caller: &Caller, id: Uuid, role: Role) -> Result<User, AppError> {
+        self.authorize(caller, Action::GrantRole(id, role))?;
+        log::info!("User {} grants role {role} to user {id}", caller.id);
+
+        let user = self.users.grant_role(id, role, &role_changes).await?.ok_or(AppError::NotFound)?;
+        self.recorded();
+        Ok(user)
+    }
+
+    /// Takes `role` away from user `id`, if `caller` may manage roles.
+    pub async fn revoke_role(&self, caller: &Caller, id: Uuid, role: Role) -> Result<User, AppError> {
+        self.authorize(caller, Action::RevokeRole(id, role))?;
+        log::info!("User {} revokes role {role} from user {id}", caller.id);
+
+        let user = self.users.revoke_role(id, role, &role_changes).await?.ok_or(AppError::NotFound)?;
+        self.recorded();
+        Ok(user)
+    }
+
+    /// Subscribes `url` to the events in `event_types`, signing deliveries with `secret`.
+    pub async fn create_webhook(
+        &self,
+        caller: &Caller,
+        url: &str,
+        secret: &str,
+        event_types: &[String],
+    ) -> Result<Webhook, AppError> {
+        self.authorize(caller, Action::ManageWebhooks)?;
+        let fields = validate_webhook(url, secret, event_types)?;
+
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: fields.url,
+            secret: fields.secret,
+            event_types: fields.event_types,
+            created_at: now_millis(),
+        };
+        self.users.add_webhook(&webhook).await?;
+        log::info!("User {} adds webhook {} for {}", caller.id, webhook.id, webhook.url);
+
+        Ok(webhook)
+    }
+
+    pub async fn list_webhooks(&self, caller: &Caller) -> Result<Vec<Webhook>, AppError> {
+        self.authorize(caller, Action::ManageWebhooks)?;
+        Ok(self.users.list_webhooks().await?)
+    }
+
+    pub async fn get_webhook(&self, caller: &Caller, id: Uuid) -> Result<Webhook, AppError> {
+        self.authorize(caller, Action::ManageWebhooks)?;
+        self.users.get_webhook(id).await?.ok_or(AppError::WebhookNotFound)
+    }
+
+    /// Removes webhook `id` and its delivery log; deliveries still pending are dropped.
+    pub async fn delete_webhook(&self, caller: &Caller, id: Uuid) -> Result<Webhook, AppError> {
+        self.authorize(caller, Action::ManageWebhooks)?;
+        log::info!("User {} removes webhook {id}", caller.id);
+        self.users.remove_webhook(id).await?.ok_or(AppError::WebhookNotFound)
+    }
+
+    /// Up to `limit` deliveries to webhook `id`, newest first.
+    pub async fn webhook_deliveries(&self, caller: &Caller, id: Uuid, limit: usize) -> Result<Vec<Delivery>, AppError> {
+        self.get_webhook(caller, id).await?;
+        Ok(self.users.list_deliveries(id, limit).await?)
+    }
+
+    /// Queues delivery `delivery_id` of webhook `webhook_id` to be sent again right away with a
+    /// fresh set of attempts, whether it succeeded, failed or is still pending.
+    pub async fn redeliver(&self, caller: &Caller, webhook_id: Uuid, delivery_id: Uuid) -> Result<Delivery, AppError> {
+        self.authorize(caller, Action::ManageWebhooks)?;
+
+        let mut delivery = match self.users.get_delivery(delivery_id).await? {
+            Some(delivery) if delivery.webhook_id == webhook_id => delivery,
+            _ => return Err(AppError::DeliveryNotFound),
+        };
+        delivery.redeliver();
+        if !self.users.update_delivery(&delivery).await? {
+            return Err(AppError::DeliveryNotFound);
+        }
+
+        log::info!("User {} redelivers {delivery_id} to webhook {webhook_id}", caller.id);
+        self.queued.notify_one();
+        Ok(delivery)
     }
 }
 
+/// A [`Renamed`](UserEvent::Renamed) and an [`EmailChanged`](UserEvent::EmailChanged) event,
+/// for whichever of the two actually changed.
+fn field_changes(before: Option<&User>, after: Option<&User>) -> Vec<UserEvent> {
+    let (Some(before), Some(after)) = (before, after) else {
+        return Vec::new();
+    };
+
+    let mut events = Vec::new();
+    if after.username != before.username {
+        events.push(UserEvent::Renamed {
+            user: after.clone(),
+            previous_username: before.username.clone(),
+        });
+    }
+    if after.email != before.email {
+        events.push(UserEvent::EmailChanged {
+            user: after.clone(),
+            previous_email: before.email.clone(),
+        });
+    }
+    events
+}
+
+fn role_changes(before: Option<&User>, after: Option<&User>) -> Vec<UserEvent> {
+    match (before, after) {
+        (Some(before), Some(after)) if after.roles != before.roles => vec![UserEvent::RolesChanged { user: after.clone() }],
+        _ => Vec::new(),
+    }
+}
+
+fn activity_changes(before: Option<&User>, after: Option<&User>) -> Vec<UserEvent> {
+    match (before, after) {
+        (Some(before), Some(after)) if after.active != before.active => {
+            let user = after.clone();
+            vec![if user.active { UserEvent::Reactivated { user } } else { UserEvent::Deactivated { user } }]
+        }
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,290 @@
+//! Command line user management straight against the configured backend, for operators
+//! fixing data without the HTTP API (or raw SQL).
+//!
+//! It reads the same configuration as the server: `config.toml`, `APP_*` variables and the
+//! same flags. Changes go through the [`Application`] use cases as the system, so they are
+//! validated and record events like any other; a running server relays those on its next
+//! poll.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use rust_webapp::adapters;
+use rust_webapp::app::{AppError, Application};
+use rust_webapp::config::{ConfigArgs, Settings};
+use rust_webapp::errors::error_chain;
+use rust_webapp::passwords::Passwords;
+use rust_webapp::policy::Caller;
+use rust_webapp::users::{DynUserRepo, User, UserQuery, UserSort};
+use std::error::Error;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use uuid::Uuid;
+
+/// Manage rust-webapp users directly in the backend the server uses.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// log4rs config file [default: warnings and errors on stderr]
+    #[arg(long)]
+    log_config: Option<PathBuf>,
+
+    /// How users are printed
+    #[arg(long, short, global = true, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Users(UserCommand),
+    /// Apply pending schema migrations
+    Migrate,
+}
+
+/// The commands run through the application, against a connected backend.
+#[derive(Subcommand)]
+enum UserCommand {
+    /// List users matching the filters
+    List {
+        #[arg(long, value_enum, default_value_t = Sort::Created)]
+        sort: Sort,
+        #[command(flatten)]
+        filters: Filters,
+    },
+    /// Show the user with an id
+    Get { id: Uuid },
+    /// Show the user with a username or email
+    Find { login: String },
+    /// Register a user
+    Create {
+        username: String,
+        email: String,
+        /// Set a password, read from the first line of standard input
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Delete the user with an id, printing what it was
+    Delete { id: Uuid },
+    /// Count users matching the filters
+    Count {
+        #[command(flatten)]
+        filters: Filters,
+    },
+}
+
+#[derive(Args)]
+struct Filters {
+    /// Only users whose username starts with this
+    #[arg(long)]
+    username_prefix: Option<String>,
+    /// Only users with an email in this domain, e.g. example.com
+    #[arg(long)]
+    email_domain: Option<String>,
+}
+
+impl Filters {
+    fn query(self, sort: UserSort) -> UserQuery {
+        UserQuery {
+            sort,
+            username_prefix: self.username_prefix,
+            email_domain: self.email_domain,
+            ..UserQuery::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Sort {
+    /// Oldest first
+    Created,
+    Username,
+    Email,
+}
+
+impl From<Sort> for UserSort {
+    fn from(sort: Sort) -> Self {
+        match sort {
+            Sort::Created => UserSort::Created,
+            Sort::Username => UserSort::Username,
+            Sort::Email => UserSort::Email,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Aligned columns, for people
+    Table,
+    /// A JSON object per user, or an array of them for `list`
+    Json,
+    /// RFC 4180 CSV with a header row; roles are separated by `;`
+    Csv,
+}
+
+const COLUMNS: [&str; 5] = ["id", "username", "email", "roles", "active"];
+
+fn fields(user: &User) -> [String; 5] {
+    let roles = user.roles.iter().map(|role| role.as_str()).collect::<Vec<_>>().join(";");
+    [user.id.to_string(), user.username.clone(), user.email.clone(), roles, user.active.to_string()]
+}
+
+/// `users` in `format`; `list` says whether they are a listing rather than a single user,
+/// which only JSON tells apart.
+fn render(users: &[User], format: Format, list: bool) -> String {
+    match format {
+        Format::Json if list => serde_json::to_string_pretty(users).expect("users serialize to JSON"),
+        Format::Json => users.iter().map(|user| serde_json::to_string_pretty(user).expect("users serialize to JSON")).collect(),
+        Format::Csv => {
+            let mut out = format!("{}\n", COLUMNS.join(","));
+            for user in users {
+                out.push_str(&fields(user).iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+                out.push('\n');
+            }
+            out.trim_end().to_owned()
+        }
+        Format::Table => {
+            let header = COLUMNS.map(str::to_uppercase);
+            let rows: Vec<[String; 5]> = users.iter().map(fields).collect();
+            let widths: Vec<usize> = (0..COLUMNS.len())
+                .map(|i| rows.iter().chain([&header]).map(|row| row[i].chars().count()).max().unwrap_or(0))
+                .collect();
+            [&header]
+                .into_iter()
+                .chain(&rows)
+                .map(|row| {
+                    let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{cell:<width$}")).collect();
+                    cells.join("  ").trim_end().to_owned()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// `field` quoted if it needs to be.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line).map_err(|e| format!("failed to read the password: {e}"))?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    match password.is_empty() {
+        true => Err("no password on standard input".to_owned()),
+        false => Ok(password.to_owned()),
+    }
+}
+
+/// Runs `command`, printing its result; returns the message to fail with otherwise.
+async fn execute(app: &Application<DynUserRepo>, command: UserCommand, format: Format) -> Result<(), String> {
+    let system = Caller::system();
+    let failed = |e: AppError| error_chain(&e);
+
+    let (users, list) = match command {
+        UserCommand::List { sort, filters } => (app.all_users(&system, &filters.query(sort.into())).await.map_err(failed)?, true),
+        UserCommand::Count { filters } => {
+            let count = app.count_users(&system, &filters.query(UserSort::default())).await.map_err(failed)?;
+            println!("{count}");
+            return Ok(());
+        }
+        UserCommand::Get { id } => match app.get_user(&system, id).await {
+            Err(AppError::NotFound) => return Err(format!("no user with id {id}")),
+            user => (vec![user.map_err(failed)?], false),
+        },
+        UserCommand::Find { login } => match app.find_user(&system, &login).await {
+            Err(AppError::NotFound) => return Err(format!("no user with username or email {login}")),
+            user => (vec![user.map_err(failed)?], false),
+        },
+        UserCommand::Create { username, email, password_stdin } => {
+            let password = password_stdin.then(read_password).transpose()?;
+            (vec![app.register(&username, &email, password.as_deref()).await.map_err(failed)?], false)
+        }
+        UserCommand::Delete { id } => match app.delete_user(&system, id).await {
+            Err(AppError::NotFound) => return Err(format!("no user with id {id}")),
+            user => (vec![user.map_err(failed)?], false),
+        },
+    };
+
+    println!("{}", render(&users, format, list));
+    Ok(())
+}
+
+async fn run(settings: &Settings, command: Command, format: Format) -> Result<(), String> {
+    let backend = settings.backend.kind;
+    let command = match command {
+        Command::Users(command) => command,
+        Command::Migrate => {
+            return adapters::migrate(&settings.backend)
+                .await
+                .map_err(|e| format!("failed to migrate the {backend:?} backend: {}", error_chain(&e)));
+        }
+    };
+
+    let users = adapters::connect(&settings.backend)
+        .await
+        .map_err(|e| format!("failed to connect to the {backend:?} backend: {}", error_chain(&e)))?;
+    let passwords = Passwords::new(&settings.auth.password).map_err(|e| format!("invalid password settings: {e}"))?;
+    let app = Application::new(users).with_passwords(passwords);
+
+    let result = execute(&app, command, format).await;
+    if let Err(e) = app.close().await {
+        log::warn!("Failed to close the {backend:?} backend: {}", error_chain(&e));
+    }
+    result
+}
+
+/// `path` if given; otherwise warnings and errors on stderr, keeping stdout for the output.
+fn init_logging(path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = path {
+        return Ok(log4rs::init_file(path, Default::default())?);
+    }
+
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new("{l}: {m}{n}")))
+        .build();
+    let config = Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .build(Root::builder().appender("stderr").build(LevelFilter::Warn))?;
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if let Err(e) = init_logging(cli.log_config.as_deref()) {
+        eprintln!("error: failed to set up logging: {e}");
+        return ExitCode::FAILURE;
+    }
+    let settings = match Settings::load(&cli.config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: failed to load configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(&settings, cli.command, cli.format).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
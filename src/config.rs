@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use thiserror::Error;
+use crate::validation::PasswordPolicy;
+
+/// File read when `--config` is not given. It is optional: without it the defaults apply.
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Everything the binaries need at startup.
+///
+/// Values are layered, later ones winning: built-in defaults, the TOML config file,
+/// `APP_*` environment variables, then command line flags.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub metrics: MetricsSettings,
+    pub backend: BackendSettings,
+    pub auth: AuthSettings,
+    pub events: EventSettings,
+    pub webhooks: WebhookSettings,
+    pub idempotency: IdempotencySettings,
+    pub rate_limit: RateLimitSettings,
+    pub tracing: TracingSettings,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    /// How long `/health/ready` waits for the backend before reporting it down.
+    pub readiness_timeout_ms: u64,
+    /// How long requests in flight get to finish after SIGTERM or SIGINT before their
+    /// connections are dropped, and then how long the event relay and the webhook dispatcher
+    /// get to finish theirs.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_owned(),
+            port: 8080,
+            readiness_timeout_ms: 2000,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+/// The Prometheus scrape endpoint. It gets a listener of its own, apart from the API's, so it
+/// can be kept off the network clients reach the API from.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: "127.0.0.1".to_owned(),
+            port: 9090,
+        }
+    }
+}
+
+/// Which [`UserRepo`](crate::users::UserRepo) adapter to use, plus the settings of every adapter
+/// so switching is a one-line change.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendSettings {
+    pub kind: BackendKind,
+    pub sqlite: SqliteSettings,
+    pub mongo: MongoSettings,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Sqlite,
+    Mongo,
+    Memory,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteSettings {
+    /// Database file, created if missing.
+    pub path: PathBuf,
+}
+
+impl Default for SqliteSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("data.sqlite"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoSettings {
+    pub uri: String,
+    pub database: String,
+    /// Accept a standalone server, which has no transactions: user changes and their outbox
+    /// events are then written one after the other, and a crash in between loses the events.
+    pub allow_standalone: bool,
+}
+
+impl Default for MongoSettings {
+    fn default() -> Self {
+        Self {
+            uri: "mongodb://localhost:27017".to_owned(),
+            database: "rust_webapp".to_owned(),
+            allow_standalone: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    pub password: PasswordSettings,
+    pub jwt: JwtSettings,
+}
+
+/// Password policy and hashing cost.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordSettings {
+    /// Shortest accepted password, in characters.
+    pub min_length: usize,
+    /// Longest accepted password, in characters; bounds the cost of hashing it.
+    pub max_length: usize,
+    pub argon2: Argon2Settings,
+}
+
+impl PasswordSettings {
+    pub fn policy(&self) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: self.min_length,
+            max_length: self.max_length,
+        }
+    }
+}
+
+impl Default for PasswordSettings {
+    fn default() -> Self {
+        Self {
+            min_length: 12,
+            max_length: 128,
+            argon2: Argon2Settings::default(),
+        }
+    }
+}
+
+/// Argon2id parameters. The defaults are the OWASP minimum (19 MiB, 2 passes, 1 lane);
+/// raise them as far as login latency allows.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Settings {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Settings {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Signing of access and refresh tokens.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtSettings {
+    pub algorithm: JwtAlgorithm,
+    /// HS256 shared secret, at least 32 bytes. Without one a random secret is generated at
+    /// startup, so tokens stop working on restart.
+    pub secret: Option<String>,
+    /// EdDSA: PKCS#8 PEM file with the Ed25519 private key.
+    pub private_key_file: Option<PathBuf>,
+    /// EdDSA: PEM file with the matching public key.
+    pub public_key_file: Option<PathBuf>,
+    /// `iss` claim of issued tokens; tokens from other issuers are rejected.
+    pub issuer: String,
+    pub access_ttl_secs: u64,
+    pub refresh_ttl_secs: u64,
+}
+
+impl Default for JwtSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: JwtAlgorithm::default(),
+            secret: None,
+            private_key_file: None,
+            public_key_file: None,
+            issuer: "rust-webapp".to_owned(),
+            access_ttl_secs: 15 * 60,
+            refresh_ttl_secs: 14 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum JwtAlgorithm {
+    #[default]
+    HS256,
+    EdDSA,
+}
+
+/// Delivery of user events from the outbox.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventSettings {
+    /// Also write every event to the log, on the `Events` target.
+    pub log: bool,
+    pub relay: RelaySettings,
+    pub stream: StreamSettings,
+    pub websocket: WebSocketSettings,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySettings {
+    /// How often to look for events recorded by other processes, such as the command line.
+    /// Events of the server's own changes are relayed right away.
+    pub poll_interval_ms: u64,
+    /// Most events read from the outbox at once.
+    pub batch_size: usize,
+    /// Delay before retrying after a sink failed; doubled after every further failure.
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 1000,
+            batch_size: 100,
+            retry_initial_ms: 500,
+            retry_max_ms: 60_000,
+        }
+    }
+}
+
+/// The `/users/events` Server-Sent Events stream.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamSettings {
+    /// How often an idle stream sends a comment, so proxies do not time it out.
+    pub heartbeat_interval_ms: u64,
+    /// Most recent events kept for clients resuming with `Last-Event-ID`.
+    pub replay_capacity: usize,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_ms: 15_000,
+            replay_capacity: 1024,
+        }
+    }
+}
+
+/// The `/users/live` WebSocket subscriptions.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketSettings {
+    /// How often to ping the client; one that stays silent for two intervals is dropped.
+    pub ping_interval_ms: u64,
+    /// How long a message may wait for a slow client before it is dropped.
+    pub send_timeout_ms: u64,
+    /// Most subscriptions per connection.
+    pub max_subscriptions: usize,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            ping_interval_ms: 15_000,
+            send_timeout_ms: 10_000,
+            max_subscriptions: 16,
+        }
+    }
+}
+
+/// Delivery of user events to webhooks.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    /// How long a receiver has to answer before the attempt counts as failed.
+    pub timeout_ms: u64,
+    /// Attempts per delivery before giving up on it; a manual redelivery starts over.
+    pub max_attempts: u32,
+    /// Delay before retrying a failed delivery; doubled after every further failure.
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+    /// How often to look for deliveries that have become due.
+    pub poll_interval_ms: u64,
+    /// Most deliveries attempted at once.
+    pub batch_size: usize,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 10_000,
+            max_attempts: 8,
+            retry_initial_ms: 30_000,
+            retry_max_ms: 6 * 60 * 60 * 1000,
+            poll_interval_ms: 1000,
+            batch_size: 50,
+        }
+    }
+}
+
+/// Responses kept for `Idempotency-Key` retries.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencySettings {
+    /// How long a response is replayed to retries; the key may be reused afterwards.
+    pub ttl_secs: u64,
+    /// How long a key stays claimed by a request that never finishes, e.g. because the server
+    /// died handling it.
+    pub lock_timeout_secs: u64,
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 24 * 60 * 60,
+            lock_timeout_secs: 60,
+        }
+    }
+}
+
+/// Per-client request quotas on the API routes.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    /// Take the client's address from `Forwarded` or `X-Forwarded-For`. Only behind a proxy
+    /// that sets them, since clients can send anything.
+    pub trust_forwarded: bool,
+    /// Quota of every route not listed in `routes`.
+    pub default: RateLimitQuota,
+    /// Quotas by route name, as in the metrics' `route` label (e.g. `create_user`).
+    pub routes: HashMap<String, RateLimitQuota>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let quota = |burst, per_minute| RateLimitQuota { burst, per_minute };
+        Self {
+            enabled: true,
+            trust_forwarded: false,
+            default: quota(100, 600),
+            routes: HashMap::from([
+                ("create_user".to_owned(), quota(5, 10)),
+                ("delete_user".to_owned(), quota(10, 30)),
+                ("delete_webhook".to_owned(), quota(10, 30)),
+                ("login".to_owned(), quota(10, 20)),
+            ]),
+        }
+    }
+}
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_minute` requests a minute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitQuota {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// OpenTelemetry traces of the HTTP requests and the repository calls they make.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSettings {
+    pub exporter: TraceExporterKind,
+    /// `service.name` of the spans.
+    pub service_name: String,
+    /// OTLP/HTTP traces endpoint of the collector, used by the `otlp` exporter.
+    pub otlp_endpoint: String,
+    /// File the `file` exporter appends spans to, one JSON object per line.
+    pub file: PathBuf,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            exporter: TraceExporterKind::None,
+            service_name: "rust-webapp".to_owned(),
+            otlp_endpoint: "http://localhost:4318/v1/traces".to_owned(),
+            file: PathBuf::from("traces.jsonl"),
+        }
+    }
+}
+
+/// Where spans go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporterKind {
+    /// Nowhere: spans are not recorded.
+    #[default]
+    None,
+    /// JSON lines on standard output.
+    Stdout,
+    /// JSON lines appended to `file`.
+    File,
+    /// An OpenTelemetry collector, over OTLP/HTTP.
+    Otlp,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("invalid config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+}
+
+/// Command line flags (each with an `APP_*` environment variable fallback) that override the
+/// config file. Binaries embed it with `#[command(flatten)]`.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConfigArgs {
+    /// Config file [default: config.toml, if present]
+    #[arg(long = "config", env = "APP_CONFIG")]
+    pub config_file: Option<PathBuf>,
+
+    /// Storage backend
+    #[arg(long, env = "APP_BACKEND")]
+    pub backend: Option<BackendKind>,
+
+    /// SQLite database file
+    #[arg(long, env = "APP_SQLITE_PATH")]
+    pub sqlite_path: Option<PathBuf>,
+
+    /// MongoDB connection string
+    #[arg(long, env = "APP_MONGO_URI")]
+    pub mongo_uri: Option<String>,
+
+    /// MongoDB database name
+    #[arg(long, env = "APP_MONGO_DATABASE")]
+    pub mongo_database: Option<String>,
+
+    /// Address to listen on
+    #[arg(long, env = "APP_HOST")]
+    pub host: Option<String>,
+
+    /// Port to listen on
+    #[arg(long, env = "APP_PORT")]
+    pub port: Option<u16>,
+
+    /// HS256 secret for signing tokens
+    #[arg(long, env = "APP_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    /// Where to export trace spans
+    #[arg(long, env = "APP_TRACE_EXPORTER")]
+    pub trace_exporter: Option<TraceExporterKind>,
+}
+
+impl Settings {
+    /// Reads the config file named by `args` (or the default one, if present) and applies
+    /// the overrides in `args` on top.
+    pub fn load(args: &ConfigArgs) -> Result<Self, ConfigError> {
+        let mut settings = match &args.config_file {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        settings.apply(args);
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    fn apply(&mut self, args: &ConfigArgs) {
+        if let Some(kind) = args.backend {
+            self.backend.kind = kind;
+        }
+        if let Some(path) = &args.sqlite_path {
+            self.backend.sqlite.path = path.clone();
+        }
+        if let Some(uri) = &args.mongo_uri {
+            self.backend.mongo.uri = uri.clone();
+        }
+        if let Some(database) = &args.mongo_database {
+            self.backend.mongo.database = database.clone();
+        }
+        if let Some(host) = &args.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+        }
+        if let Some(secret) = &args.jwt_secret {
+            self.auth.jwt.secret = Some(secret.clone());
+        }
+        if let Some(exporter) = args.trace_exporter {
+            self.tracing.exporter = exporter;
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! Helpers for reporting errors.
+
+use std::error::Error;
+
+/// `e` followed by its chain of sources, since repository and HTTP client errors keep the
+/// interesting part (refused, timed out, ...) behind `source()`.
+pub fn error_chain(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
+}
@@ -0,0 +1,139 @@
+use crate::users::User;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Something that happened to a user, emitted by the [`Application`](crate::app::Application)
+/// and recorded in the repository's outbox together with the change. Events carry the user as
+/// it is afterwards.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UserEvent {
+    Registered { user: User },
+    Renamed { user: User, previous_username: String },
+    EmailChanged { user: User, previous_email: String },
+    PasswordChanged { id: Uuid },
+    RolesChanged { user: User },
+    Deactivated { user: User },
+    Reactivated { user: User },
+    /// Carries the user as it was just before deletion.
+    Deleted { user: User },
+}
+
+impl UserEvent {
+    /// Every [`kind`](Self::kind) of event.
+    pub const KINDS: [&'static str; 8] = [
+        "registered",
+        "renamed",
+        "email_changed",
+        "password_changed",
+        "roles_changed",
+        "deactivated",
+        "reactivated",
+        "deleted",
+    ];
+
+    /// Id of the user the event is about.
+    pub fn user_id(&self) -> Uuid {
+        match self {
+            Self::PasswordChanged { id } => *id,
+            Self::Registered { user }
+            | Self::Renamed { user, .. }
+            | Self::EmailChanged { user, .. }
+            | Self::RolesChanged { user }
+            | Self::Deactivated { user }
+            | Self::Reactivated { user }
+            | Self::Deleted { user } => user.id,
+        }
+    }
+
+    /// The user as the event left them, or as they were just before deletion; `None` for
+    /// password changes, which carry nothing but the id.
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            Self::PasswordChanged { .. } => None,
+            Self::Registered { user }
+            | Self::Renamed { user, .. }
+            | Self::EmailChanged { user, .. }
+            | Self::RolesChanged { user }
+            | Self::Deactivated { user }
+            | Self::Reactivated { user }
+            | Self::Deleted { user } => Some(user),
+        }
+    }
+
+    /// The `type` the event is serialized with, e.g. `email_changed`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Registered { .. } => "registered",
+            Self::Renamed { .. } => "renamed",
+            Self::EmailChanged { .. } => "email_changed",
+            Self::PasswordChanged { .. } => "password_changed",
+            Self::RolesChanged { .. } => "roles_changed",
+            Self::Deactivated { .. } => "deactivated",
+            Self::Reactivated { .. } => "reactivated",
+            Self::Deleted { .. } => "deleted",
+        }
+    }
+}
+
+/// A [`UserEvent`] as stored in the outbox and handed to [`EventSink`]s.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedEvent {
+    /// Unique per event and stable across redeliveries, so consumers can drop duplicates.
+    pub id: Uuid,
+    /// Milliseconds since the Unix epoch.
+    pub recorded_at: u64,
+    #[serde(flatten)]
+    pub event: UserEvent,
+}
+
+impl RecordedEvent {
+    /// `event`, recorded now under a fresh id.
+    pub fn new(event: UserEvent) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            recorded_at: now_millis(),
+            event,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit of every stored timestamp.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after 1970")
+        .as_millis() as u64
+}
+
+pub type SinkError = Box<dyn Error + Send + Sync>;
+
+/// A destination the outbox relay delivers events to, e.g. a message broker.
+///
+/// Delivery is at least once: an event is retried until every sink has accepted it in the
+/// same attempt, so a sink may see an event again after it or another sink failed. Events
+/// arrive in the order they were recorded.
+#[async_trait::async_trait]
+pub trait EventSink: Send + Sync {
+    /// Short name for logs.
+    fn name(&self) -> &str;
+
+    async fn deliver(&self, event: &RecordedEvent) -> Result<(), SinkError>;
+}
+
+/// Writes every event to the log, on the `Events` target.
+pub struct LogSink;
+
+#[async_trait::async_trait]
+impl EventSink for LogSink {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    async fn deliver(&self, event: &RecordedEvent) -> Result<(), SinkError> {
+        let json = serde_json::to_string(event)?;
+        log::info!(target: "Events", "{json}");
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+/// A request made with an `Idempotency-Key`, stored so that retries with the same key get the
+/// same response instead of repeating the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyRecord {
+    pub key: String,
+    /// [`fingerprint`] of the request; a retry must match it.
+    pub fingerprint: String,
+    /// `None` while the first request with the key is still being handled.
+    pub response: Option<StoredResponse>,
+    /// Milliseconds since the Unix epoch after which the record counts as gone, and the key
+    /// may be used again.
+    pub expires_at: u64,
+}
+
+/// What a request was answered with, to answer its retries the same way.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    /// Only the headers worth replaying, e.g. `Content-Type` and `Location`.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// What became of an attempt to claim an idempotency key for a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The key is new (or its record expired): handle the request, then complete or release it.
+    New,
+    /// The same request was already answered; answer this one the same way.
+    Replay(StoredResponse),
+    /// The same request is still being handled.
+    InFlight,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Hex SHA-256 of `request` as JSON. Leave secrets such as passwords out: records are kept
+/// for hours, and a fast hash does little to protect them.
+pub fn fingerprint(request: &impl serde::Serialize) -> String {
+    let json = serde_json::to_vec(request).expect("requests serialize to JSON");
+    hex::encode(Sha256::digest(json))
+}
@@ -1,3 +1,17 @@
 pub mod app;
 pub mod users;
-pub mod adapters;
\ No newline at end of file
+pub mod adapters;
+pub mod validation;
+pub mod config;
+pub mod api;
+pub mod metrics;
+pub mod passwords;
+pub mod tokens;
+pub mod policy;
+pub mod events;
+pub mod webhooks;
+pub mod live;
+pub mod idempotency;
+pub mod rate_limit;
+pub mod telemetry;
+pub mod errors;
@@ -0,0 +1,77 @@
+//! Filtered views of the user directory that follow user events, for live subscriptions.
+
+use crate::events::UserEvent;
+use crate::users::{User, UserQuery};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// How an event changed a [`LiveView`], from its subscriber's point of view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The user now matches the filter: they were registered, or changed into it.
+    Created(User),
+    /// The user still matches, but something about them changed.
+    Updated(User),
+    /// The user no longer matches: they were deleted, or changed out of it. Carries the user as
+    /// they are now, or were just before deletion.
+    Deleted(User),
+}
+
+impl Change {
+    pub fn user(&self) -> &User {
+        match self {
+            Self::Created(user) | Self::Updated(user) | Self::Deleted(user) => user,
+        }
+    }
+}
+
+/// The users matching a query's filters, as far as a subscriber knows them: a snapshot
+/// ([`reset`](Self::reset)), kept up to date by [`apply`](Self::apply)ing every later event.
+///
+/// Only the ids are kept. Events carry the user as they are afterwards, so replaying events the
+/// snapshot already reflects yields the same users again rather than stale ones.
+#[derive(Debug, Clone)]
+pub struct LiveView {
+    filter: UserQuery,
+    members: HashSet<Uuid>,
+}
+
+impl LiveView {
+    /// An empty view of the users matching `filter`; its limit, cursor and sort are ignored.
+    pub fn new(filter: UserQuery) -> Self {
+        Self {
+            filter,
+            members: HashSet::new(),
+        }
+    }
+
+    pub fn filter(&self) -> &UserQuery {
+        &self.filter
+    }
+
+    /// Starts over from `users`, every user currently matching the filter.
+    pub fn reset(&mut self, users: &[User]) {
+        self.members = users.iter().map(|user| user.id).collect();
+    }
+
+    /// What `event` changes about the view, if anything. Password changes never do, since
+    /// subscribers never see passwords.
+    pub fn apply(&mut self, event: &UserEvent) -> Option<Change> {
+        let user = event.user()?;
+        let was_member = self.members.contains(&user.id);
+        let is_member = !matches!(event, UserEvent::Deleted { .. }) && self.filter.matches(user);
+
+        match (was_member, is_member) {
+            (false, true) => {
+                self.members.insert(user.id);
+                Some(Change::Created(user.clone()))
+            }
+            (true, true) => Some(Change::Updated(user.clone())),
+            (true, false) => {
+                self.members.remove(&user.id);
+                Some(Change::Deleted(user.clone()))
+            }
+            (false, false) => None,
+        }
+    }
+}
@@ -1,219 +1,303 @@
-pub mod adapters;
-pub mod app;
-pub mod users;
-
-use crate::adapters::sqlite::SqliteUserRepo;
-use crate::app::Application;
-use crate::users::{User, UserRepo, UserRepoError};
 use actix_cors::Cors;
-use actix_web::http::StatusCode;
-use actix_web::web::{Data, Json, Path};
-use actix_web::{delete, get, post, App, HttpServer, Responder, ResponseError, Result};
-use log::{error, info};
+use actix_web::middleware::from_fn;
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpServer};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use rust_webapp::adapters;
+use rust_webapp::api;
+use rust_webapp::app::{AppError, Application};
+use rust_webapp::policy::Caller;
+use rust_webapp::config::{ConfigArgs, Settings};
+use rust_webapp::errors::error_chain;
+use rust_webapp::events::LogSink;
+use rust_webapp::metrics::{MeteredUserRepo, Metrics};
+use rust_webapp::passwords::Passwords;
+use rust_webapp::rate_limit::RateLimiter;
+use rust_webapp::telemetry::{TracedUserRepo, Tracing};
+use rust_webapp::tokens::Tokens;
+use rust_webapp::users::{DynUserRepo, Role};
+use rust_webapp::webhooks::WebhookSender;
 use std::io;
-use sqlx::migrate::MigrateDatabase;
-use sqlx::Sqlite;
-use thiserror::Error;
-use utoipa;
-use utoipa::OpenApi;
-use uuid::Uuid;
-
-struct AppState {
-    application: Application<SqliteUserRepo>,
-}
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal;
 
-#[derive(OpenApi)]
-#[openapi(
-    paths(
-        get_users,
-        create_user,
-        get_user,
-        delete_user
-    ),
-    components(
-        schemas(UserDto, CreateUserDto)
-    ),
-    tags(
-        (name = "users", description = "User management")
-    )
-)]
-struct ApiDoc;
-
-#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
-struct UserDto {
-    /// Unique identifier for the user
-    /// example = "550e8400-e29b-41d4-a716-446655440000"
-    /// format = "uuid"
-    id: String,
-
-    /// Username of the user
-    /// example = "johndoe"
-    username: String,
-
-    /// Email address of the user
-    /// example = "johndoe@example.com"
-    email: String,
-}
+const API_PREFIX: &str = "/api";
+const HEALTH_PREFIX: &str = "/health";
+const METRICS_PATH: &str = "/metrics";
 
-#[derive(serde::Deserialize, utoipa::ToSchema)]
-struct CreateUserDto {
-    username: String,
-    email: String,
-}
+/// User management REST API.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
 
-impl From<User> for UserDto {
-    fn from(user: User) -> Self {
-        Self {
-            id: user.id.to_string(),
-            username: user.username,
-            email: user.email,
-        }
-    }
-}
+    /// log4rs config file, e.g. log4rs.json.yaml for JSON lines
+    #[arg(long, env = "APP_LOG_CONFIG", default_value = "log4rs.yaml")]
+    log_config: PathBuf,
 
-#[derive(Debug, Error)]
-pub enum ApiError {
-    #[error("conflict")]
-    Conflict,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    #[error("bad request: {0}")]
-    BadRequest(String),
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API (the default)
+    Serve,
+    /// Apply pending schema migrations to the configured backend and exit
+    Migrate,
+    /// Grant a role to an existing user and exit, e.g. to appoint the first admin
+    Grant {
+        /// Username or email of the user
+        login: String,
+        /// `admin` or `auditor`
+        role: Role,
+    },
+}
 
-    #[error("internal server error")]
-    Internal,
+async fn grant(settings: &Settings, login: &str, role: Role) -> io::Result<()> {
+    let users = adapters::connect(&settings.backend).await.map_err(|e| {
+        error!("Failed to initialize {:?} backend: {}", settings.backend.kind, error_chain(&e));
+        io::Error::other(e)
+    })?;
 
-    #[error("not found")]
-    NotFound,
-}
+    let app = Application::new(users);
+    let operator = Caller::system();
+    let granted = match app.find_user(&operator, login).await {
+        Ok(user) => app.grant_role(&operator, user.id, role).await,
+        Err(e) => Err(e),
+    };
 
-impl From<UserRepoError> for ApiError {
-    fn from(e: UserRepoError) -> Self {
-        match e {
-            UserRepoError::Conflict { .. } => ApiError::Conflict,
-            UserRepoError::Unavailable => ApiError::Internal, // or ServiceUnavailable if you add it
-            UserRepoError::Unexpected(_) => ApiError::Internal,
+    match granted {
+        Ok(user) => {
+            info!("Granted role {role} to user {} ({})", user.username, user.id);
+            Ok(())
         }
-    }
-}
-
-impl ResponseError for ApiError {
-    fn status_code(&self) -> StatusCode {
-        match self {
-            ApiError::Conflict => StatusCode::CONFLICT,
-            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
-            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-            ApiError::NotFound => StatusCode::NOT_FOUND,
+        Err(AppError::NotFound) => {
+            error!("No user with username or email {login}");
+            Err(io::Error::new(io::ErrorKind::NotFound, format!("no user {login}")))
+        }
+        Err(e) => {
+            error!("Failed to grant role {role} to {login}: {}", error_chain(&e));
+            Err(io::Error::other(e))
         }
     }
 }
 
-#[utoipa::path(
-    responses(
-        (status = 200, description = "List all users", body = [UserDto])
-    )
-)]
-#[get("/api/users")]
-async fn get_users(data: Data<AppState>) -> Result<Json<Vec<UserDto>>, ApiError> {
-    info!("Fetching all users");
-    let users = data.application.users.list_users().await?;
-    let users_dto: Vec<UserDto> = users.into_iter().map(UserDto::from).collect();
-    Ok(Json(users_dto))
-}
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    let cli = Cli::parse();
 
-#[utoipa::path(
-    request_body = CreateUserDto,
-    responses(
-        (status = 200, description = "User created successfully", body = UserDto),
-        (status = 409, description = "User already exists")
-    )
-)]
-#[post("/api/users")]
-async fn create_user(data: Data<AppState>, user_dto: Json<CreateUserDto>) -> Result<Json<UserDto>, ApiError> {
-    info!("Creating user: {}", user_dto.username);
-
-    let user = data
-        .application
-        .users
-        .add_user(&user_dto.username, &user_dto.email)
-        .await?;
-
-    Ok(Json(UserDto::from(user)))
-}
+    log4rs::init_file(&cli.log_config, Default::default()).unwrap();
 
-#[utoipa::path(
-    responses(
-        (status = 200, description = "Get user by ID", body = UserDto),
-        (status = 404, description = "User not found")
-    )
-)]
-#[get("/api/users/{id}")]
-async fn get_user(data: Data<AppState>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
-    info!("Fetching user: {}", id);
-    let user = data.application.users.get_user(*id).await?.ok_or(ApiError::NotFound)?;
-    Ok(Json(UserDto::from(user)))
-}
+    let settings = Settings::load(&cli.config).map_err(|e| {
+        error!("Failed to load configuration: {}", e);
+        io::Error::other(e)
+    })?;
 
-#[utoipa::path(
-    responses(
-        (status = 200, description = "User deleted successfully", body = UserDto),
-        (status = 404, description = "User not found")
-    )
-)]
-#[delete("/api/users/{id}")]
-async fn delete_user(data: Data<AppState>, id: Path<Uuid>) -> Result<Json<UserDto>, ApiError> {
-    info!("Deleting user: {}", id);
+    match cli.command {
+        Some(Command::Migrate) => {
+            return adapters::migrate(&settings.backend).await.map_err(|e| {
+                error!("Failed to migrate {:?} backend: {}", settings.backend.kind, error_chain(&e));
+                io::Error::other(e)
+            });
+        }
+        Some(Command::Grant { login, role }) => return grant(&settings, &login, role).await,
+        Some(Command::Serve) | None => {}
+    }
 
-    let removed = data.application.users.remove_user(*id).await?;
+    let users_impl = adapters::connect(&settings.backend).await.map_err(|e| {
+        error!("Failed to initialize {:?} backend: {}", settings.backend.kind, error_chain(&e));
+        io::Error::other(e)
+    })?;
 
-    let user = removed.ok_or(ApiError::NotFound)?;
-    Ok(Json(UserDto::from(user)))
-}
+    let passwords = Passwords::new(&settings.auth.password).map_err(|e| {
+        error!("Invalid password settings: {}", e);
+        io::Error::other(e)
+    })?;
 
-#[get("/v3/api-docs")]
-async fn api_docs() -> impl Responder {
-    Json(ApiDoc::openapi())
-}
+    let tokens = Tokens::new(&settings.auth.jwt).map_err(|e| {
+        error!("Invalid JWT settings: {}", e);
+        io::Error::other(e)
+    })?;
+    let tokens = Data::new(tokens);
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+    let sender = WebhookSender::new(&settings.webhooks).map_err(|e| {
+        error!("Failed to set up the webhook client: {}", e);
+        io::Error::other(e)
+    })?;
 
-    Sqlite::create_database("data.sqlite").await.map_err(|e| {
-        error!("Failed to create SQLite database: {}", e);
-        io::Error::new(io::ErrorKind::Other, e)
+    let tracing = Tracing::new(&settings.tracing).map_err(|e| {
+        error!("Failed to set up tracing: {}", e);
+        io::Error::other(e)
     })?;
+    let users_impl: DynUserRepo = match &tracing {
+        Some(tracing) => Arc::new(TracedUserRepo::new(users_impl, tracing, settings.backend.kind)),
+        None => users_impl,
+    };
+    let tracing = tracing.map(Data::new);
 
-    let pool = sqlx::SqlitePool::connect("sqlite:data.sqlite")
-        .await
-        .map_err(|e| {
-            error!("Failed to connect to SQLite database: {}", e);
-            io::Error::new(io::ErrorKind::Other, e)
-        })?;
+    let metrics = Arc::new(Metrics::new());
+    let users_impl: DynUserRepo = Arc::new(MeteredUserRepo::new(users_impl, metrics.clone()));
+    let metrics = Data::from(metrics);
 
-    let users_impl = SqliteUserRepo::new(pool).await.map_err(|e| {
-        error!("Failed to initialize SQLiteUserRepo: {}", e);
-        io::Error::new(io::ErrorKind::Other, e)
-    })?;
+    let mut app = Application::new(users_impl)
+        .with_passwords(passwords)
+        .with_replay_capacity(settings.events.stream.replay_capacity)
+        .with_idempotency(settings.idempotency.clone());
+    if settings.events.log {
+        app = app.with_sink(LogSink);
+    }
+    let data = Data::new(app);
 
-    let application = Application::new(users_impl);
-    let data = Data::new(AppState { application });
+    let openapi = api::openapi(API_PREFIX).merge_from(api::health::openapi(HEALTH_PREFIX));
+    let readiness_timeout = Duration::from_millis(settings.server.readiness_timeout_ms);
+    let stream_settings = Data::new(settings.events.stream.clone());
+    let websocket_settings = Data::new(settings.events.websocket.clone());
+    let rate_limiter = settings.rate_limit.enabled.then(|| Data::new(RateLimiter::new(settings.rate_limit.clone())));
 
-    HttpServer::new(move || {
-        App::new()
+    let server_data = data.clone();
+    let server_tracing = tracing.clone();
+    let server_metrics = metrics.clone();
+    let (host, port) = (settings.server.host.as_str(), settings.server.port);
+    let server = HttpServer::new(move || {
+        let mut app = App::new();
+        if let Some(rate_limiter) = &rate_limiter {
+            app = app.app_data(rate_limiter.clone());
+        }
+        if let Some(tracing) = &server_tracing {
+            app = app.app_data(tracing.clone());
+        }
+        app
             .wrap(
                 Cors::default()
                     .allow_any_origin()
                     .allow_any_method()
                     .allow_any_header(),
             )
-            .app_data(data.clone())
-            .service(get_users)
-            .service(create_user)
-            .service(get_user)
-            .service(api_docs)
-            .service(delete_user)
+            .wrap(from_fn(api::tracing::trace_request))
+            // Outermost, so that everything else logs with the request id.
+            .wrap(from_fn(api::request_log::request_log))
+            .app_data(server_data.clone())
+            .app_data(tokens.clone())
+            .app_data(server_metrics.clone())
+            .app_data(stream_settings.clone())
+            .app_data(websocket_settings.clone())
+            .service(api::scope::<DynUserRepo>(API_PREFIX))
+            .service(api::health::scope::<DynUserRepo>(HEALTH_PREFIX, readiness_timeout))
+            .service(api::openapi_route("/v3/api-docs", &openapi))
+            .default_service(web::to(api::error::not_found))
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .shutdown_timeout(settings.server.shutdown_timeout_secs)
+    // Stopped below instead, so that SIGINT drains requests as gracefully as SIGTERM.
+    .disable_signals()
+    .bind((host, port))
+    .inspect_err(|e| error!("Failed to listen on {host}:{port}: {e}"));
+
+    // Apart from the API, so that it can stay off the network clients reach the API from.
+    let (metrics_host, metrics_port) = (settings.metrics.host.as_str(), settings.metrics.port);
+    let metrics_server = match settings.metrics.enabled {
+        true => HttpServer::new(move || {
+            App::new()
+                .app_data(metrics.clone())
+                .service(api::metrics::metrics_route(METRICS_PATH))
+                .default_service(web::to(api::error::not_found))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind((metrics_host, metrics_port))
+        .inspect_err(|e| error!("Failed to listen for metrics on {metrics_host}:{metrics_port}: {e}"))
+        .map(Some),
+        false => Ok(None),
+    };
+
+    // Both bound before the relay and the dispatcher start, so that a taken port leaves nothing
+    // running, and the backend is still closed properly.
+    let (server, metrics_server) = match (server, metrics_server) {
+        (Ok(server), Ok(metrics_server)) => (server.run(), metrics_server.map(HttpServer::run)),
+        (Err(e), _) | (_, Err(e)) => {
+            release(&data, &settings, tracing).await;
+            return Err(e);
+        }
+    };
+    let metrics_handle = metrics_server.map(|metrics_server| {
+        let handle = metrics_server.handle();
+        actix_web::rt::spawn(metrics_server);
+        handle
+    });
+
+    let relay = data.clone();
+    let relay_settings = settings.events.relay.clone();
+    let mut relay = actix_web::rt::spawn(async move { relay.run_relay(&relay_settings).await });
+
+    let dispatcher = data.clone();
+    let webhook_settings = settings.webhooks.clone();
+    let mut dispatcher = actix_web::rt::spawn(async move { dispatcher.run_webhooks(&sender, &webhook_settings).await });
+
+    let handle = server.handle();
+    let draining = data.clone();
+    actix_web::rt::spawn(async move {
+        match shutdown_signal().await {
+            Ok(signal) => info!("{signal} received, draining requests in flight"),
+            Err(e) => {
+                error!("Failed to listen for shutdown signals: {}", e);
+                return;
+            }
+        }
+        // Event streams and WebSockets would otherwise hold up the drain until the deadline.
+        draining.close_subscriptions();
+        handle.stop(true).await;
+        if let Some(metrics_handle) = metrics_handle {
+            metrics_handle.stop(true).await;
+        }
+    });
+    let served = server.await;
+
+    // No more requests, so whatever they recorded can be relayed before the backend closes.
+    let deadline = Duration::from_secs(settings.server.shutdown_timeout_secs);
+    data.stop();
+    let finished = tokio::time::timeout(deadline, async { tokio::join!(&mut relay, &mut dispatcher) }).await;
+    if finished.is_err() {
+        warn!("Event relay and webhook dispatcher did not finish within {deadline:?}, abandoning them");
+        relay.abort();
+        dispatcher.abort();
+    }
+
+    release(&data, &settings, tracing).await;
+    info!("Shut down");
+    log::logger().flush();
+    served
+}
+
+/// Closes the backend, so that SQLite checkpoints its WAL, and flushes the spans not yet
+/// exported; the last thing done before exiting, however the server got there.
+async fn release(data: &Application<DynUserRepo>, settings: &Settings, tracing: Option<Data<Tracing>>) {
+    if let Err(e) = data.close().await {
+        error!("Failed to close the {:?} backend: {}", settings.backend.kind, error_chain(&e));
+    }
+    if let Some(tracing) = tracing
+        && let Err(e) = tracing.shutdown()
+    {
+        error!("{}", e);
+    }
+}
+
+/// Resolves with the name of the first shutdown signal received: SIGTERM, as sent by service
+/// managers and container runtimes, or SIGINT (Ctrl+C).
+async fn shutdown_signal() -> io::Result<&'static str> {
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())?.recv().await;
+        io::Result::Ok(())
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<io::Result<()>>();
+
+    tokio::select! {
+        interrupted = signal::ctrl_c() => interrupted.map(|()| "SIGINT"),
+        terminated = terminate => terminated.map(|()| "SIGTERM"),
+    }
 }
@@ -0,0 +1,259 @@
+use crate::events::RecordedEvent;
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::users::{RecordEvents, Role, User, UserPage, UserQuery, UserRepo, UserRepoError};
+use crate::webhooks::{Delivery, Webhook};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Prometheus metrics for the HTTP layer and the user repository, in a registry of their own
+/// so several instances (e.g. one per test) never collide.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    repo_operation_duration: HistogramVec,
+    repo_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled, by route and status"),
+            &["route", "method", "status"],
+        )
+        .expect("valid metric");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent handling HTTP requests, by route and status"),
+            &["route", "method", "status"],
+        )
+        .expect("valid metric");
+        let repo_operation_duration = HistogramVec::new(
+            HistogramOpts::new("user_repo_operation_duration_seconds", "Latency of user repository operations"),
+            &["operation"],
+        )
+        .expect("valid metric");
+        let repo_errors = IntCounterVec::new(
+            Opts::new("user_repo_errors_total", "Failed user repository operations, by error kind"),
+            &["operation", "error"],
+        )
+        .expect("valid metric");
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).expect("metric registered once");
+        registry.register(Box::new(http_request_duration.clone())).expect("metric registered once");
+        registry.register(Box::new(repo_operation_duration.clone())).expect("metric registered once");
+        registry.register(Box::new(repo_errors.clone())).expect("metric registered once");
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            repo_operation_duration,
+            repo_errors,
+        }
+    }
+
+    /// The underlying registry, for adding application-specific collectors.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn observe_http(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [route, method, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_repo(&self, operation: &str, error: Option<&UserRepoError>, elapsed: Duration) {
+        self.repo_operation_duration.with_label_values(&[operation]).observe(elapsed.as_secs_f64());
+        if let Some(e) = error {
+            self.repo_errors.with_label_values(&[operation, error_kind(e)]).inc();
+        }
+    }
+
+    /// Everything in the registry, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding into a Vec cannot fail");
+        String::from_utf8(buffer).expect("text encoding is UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn error_kind(e: &UserRepoError) -> &'static str {
+    match e {
+        UserRepoError::Unavailable => "unavailable",
+        UserRepoError::Conflict { .. } => "conflict",
+        UserRepoError::InvalidCursor => "invalid_cursor",
+        UserRepoError::Unexpected(_) => "unexpected",
+    }
+}
+
+/// Decorates any [`UserRepo`], recording the latency and failures of every call in [`Metrics`].
+pub struct MeteredUserRepo<R> {
+    inner: R,
+    metrics: Arc<Metrics>,
+}
+
+impl<R: UserRepo> MeteredUserRepo<R> {
+    pub fn new(inner: R, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    async fn observe<T>(
+        &self,
+        operation: &str,
+        call: impl Future<Output = Result<T, UserRepoError>>,
+    ) -> Result<T, UserRepoError> {
+        let started = Instant::now();
+        let result = call.await;
+        self.metrics.observe_repo(operation, result.as_ref().err(), started.elapsed());
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: UserRepo> UserRepo for MeteredUserRepo<R> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
+        self.observe("add_user", self.inner.add_user(username, email, password_hash, events)).await
+    }
+
+    async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
+        self.observe("get_user", self.inner.get_user(id)).await
+    }
+
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        self.observe("update_user", self.inner.update_user(id, username, email, events)).await
+    }
+
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.observe("remove_user", self.inner.remove_user(id, events)).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
+        self.observe("list_users", self.inner.list_users()).await
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        self.observe("query_users", self.inner.query_users(query)).await
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        self.observe("count_users", self.inner.count_users(query)).await
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        self.observe("find_user_by_login", self.inner.find_user_by_login(login)).await
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        self.observe("get_password_hash", self.inner.get_password_hash(id)).await
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        self.observe("set_password_hash", self.inner.set_password_hash(id, hash, events)).await
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.observe("grant_role", self.inner.grant_role(id, role, events)).await
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.observe("revoke_role", self.inner.revoke_role(id, role, events)).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.observe("set_active", self.inner.set_active(id, active, events)).await
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        self.observe("pending_events", self.inner.pending_events(limit)).await
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        self.observe("mark_delivered", self.inner.mark_delivered(ids)).await
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        self.observe("add_webhook", self.inner.add_webhook(webhook)).await
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        self.observe("get_webhook", self.inner.get_webhook(id)).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        self.observe("list_webhooks", self.inner.list_webhooks()).await
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        self.observe("remove_webhook", self.inner.remove_webhook(id)).await
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        self.observe("add_deliveries", self.inner.add_deliveries(deliveries)).await
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        self.observe("get_delivery", self.inner.get_delivery(id)).await
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        self.observe("update_delivery", self.inner.update_delivery(delivery)).await
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        self.observe("due_deliveries", self.inner.due_deliveries(now, limit)).await
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        self.observe("list_deliveries", self.inner.list_deliveries(webhook_id, limit)).await
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        self.observe("claim_idempotency_key", self.inner.claim_idempotency_key(record, now)).await
+    }
+
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        self.observe("complete_idempotency_key", self.inner.complete_idempotency_key(key, response, expires_at)).await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        self.observe("release_idempotency_key", self.inner.release_idempotency_key(key)).await
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        self.observe("health_check", self.inner.health_check()).await
+    }
+
+    async fn close(&self) -> Result<(), UserRepoError> {
+        self.observe("close", self.inner.close()).await
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::PasswordSettings;
+use crate::validation::PasswordPolicy;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error("invalid Argon2 parameters: {0}")]
+    Params(argon2::Error),
+
+    #[error("failed to hash or verify password: {0}")]
+    Hash(password_hash::Error),
+
+    #[error("password hashing task failed")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Argon2id hashing of user passwords, plus the policy new passwords must meet.
+///
+/// Hashing is deliberately expensive, so it runs on the blocking thread pool. Cloning is cheap.
+#[derive(Clone)]
+pub struct Passwords {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    argon2: Argon2<'static>,
+    policy: PasswordPolicy,
+    /// Verified against when a user has no password, so that takes as long as a wrong one.
+    dummy_hash: String,
+}
+
+impl Passwords {
+    pub fn new(settings: &PasswordSettings) -> Result<Self, PasswordError> {
+        let params = Params::new(
+            settings.argon2.memory_kib,
+            settings.argon2.iterations,
+            settings.argon2.parallelism,
+            None,
+        )
+        .map_err(PasswordError::Params)?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let dummy_hash = hash_with(&argon2, "not a real password")?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                argon2,
+                policy: settings.policy(),
+                dummy_hash,
+            }),
+        })
+    }
+
+    pub fn policy(&self) -> &PasswordPolicy {
+        &self.inner.policy
+    }
+
+    /// PHC string for `password`, with a fresh random salt and the configured parameters.
+    pub async fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let inner = self.inner.clone();
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || hash_with(&inner.argon2, &password)).await?
+    }
+
+    /// Whether `password` matches `hash`, using the parameters recorded in the hash.
+    /// Without a hash the answer is `false`, after the same amount of work.
+    pub async fn verify(&self, password: &str, hash: Option<&str>) -> Result<bool, PasswordError> {
+        let inner = self.inner.clone();
+        let password = password.to_owned();
+        let hash = hash.map(str::to_owned);
+
+        tokio::task::spawn_blocking(move || {
+            let stored = hash.as_deref().unwrap_or(&inner.dummy_hash);
+            let parsed = PasswordHash::new(stored).map_err(PasswordError::Hash)?;
+            match inner.argon2.verify_password(password.as_bytes(), &parsed) {
+                Ok(()) => Ok(hash.is_some()),
+                Err(password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(PasswordError::Hash(e)),
+            }
+        })
+        .await?
+    }
+}
+
+impl Default for Passwords {
+    fn default() -> Self {
+        Self::new(&PasswordSettings::default()).expect("default Argon2 parameters are valid")
+    }
+}
+
+fn hash_with(argon2: &Argon2<'_>, password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(PasswordError::Hash)
+}
@@ -0,0 +1,118 @@
+use crate::users::{Role, User};
+use std::fmt;
+use uuid::Uuid;
+
+/// Something a role allows beyond acting on one's own account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ListUsers,
+    ReadAnyUser,
+    UpdateAnyUser,
+    DeleteAnyUser,
+    ManageRoles,
+    ManageWebhooks,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+
+        match self {
+            Self::Admin => &[ListUsers, ReadAnyUser, UpdateAnyUser, DeleteAnyUser, ManageRoles, ManageWebhooks],
+            Self::Auditor => &[ListUsers, ReadAnyUser],
+        }
+    }
+}
+
+/// The authenticated user on whose behalf an operation runs, with their current roles.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub id: Uuid,
+    pub roles: Vec<Role>,
+}
+
+impl Caller {
+    /// The operator, acting through the command line rather than as a user: an admin whose
+    /// nil id matches no account.
+    pub fn system() -> Self {
+        Self {
+            id: Uuid::nil(),
+            roles: vec![Role::Admin],
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.roles.iter().any(|role| role.permissions().contains(&permission))
+    }
+}
+
+impl From<&User> for Caller {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id,
+            roles: user.roles.clone(),
+        }
+    }
+}
+
+/// An operation, with the id of the user it targets if it is about one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    ListUsers,
+    ReadUser(Uuid),
+    UpdateUser(Uuid),
+    DeactivateUser(Uuid),
+    /// Needs a role: deactivated users cannot authenticate to reactivate themselves.
+    ReactivateUser(Uuid),
+    DeleteUser(Uuid),
+    /// Only ever allowed on one's own account: nobody, admins included, sets another user's
+    /// password.
+    ChangePassword(Uuid),
+    GrantRole(Uuid, Role),
+    RevokeRole(Uuid, Role),
+    /// Creating, reading and deleting webhooks, and redelivering their events. Webhooks see
+    /// every user's events, so this is never about one's own account.
+    ManageWebhooks,
+}
+
+/// Why [`authorize`] refused an action; safe to show to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denied(pub &'static str);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// Whether `caller` may perform `action`. Every user may read, update, deactivate and delete
+/// their own account; anything else needs a role with the matching [`Permission`].
+pub fn authorize(caller: &Caller, action: Action) -> Result<(), Denied> {
+    const NOT_YOURS: Denied = Denied("you may only act on your own account");
+    let own_or = |id: Uuid, permission: Permission| {
+        if caller.id == id || caller.has(permission) { Ok(()) } else { Err(NOT_YOURS) }
+    };
+
+    match action {
+        Action::ListUsers if caller.has(Permission::ListUsers) => Ok(()),
+        Action::ListUsers => Err(Denied("listing users requires the admin or auditor role")),
+        Action::ReadUser(id) => own_or(id, Permission::ReadAnyUser),
+        Action::UpdateUser(id) | Action::DeactivateUser(id) => own_or(id, Permission::UpdateAnyUser),
+        Action::ReactivateUser(_) if caller.has(Permission::UpdateAnyUser) => Ok(()),
+        Action::ReactivateUser(_) => Err(Denied("reactivating users requires the admin role")),
+        Action::DeleteUser(id) => own_or(id, Permission::DeleteAnyUser),
+        Action::ChangePassword(id) if id == caller.id => Ok(()),
+        Action::ChangePassword(_) => Err(NOT_YOURS),
+        Action::GrantRole(..) | Action::RevokeRole(..) if !caller.has(Permission::ManageRoles) => {
+            Err(Denied("managing roles requires the admin role"))
+        }
+        // Only stops admins from demoting themselves; they may still delete or deactivate their
+        // own account, or demote each other.
+        Action::RevokeRole(id, Role::Admin) if id == caller.id => Err(Denied("admins cannot revoke their own admin role")),
+        Action::GrantRole(..) | Action::RevokeRole(..) => Ok(()),
+        Action::ManageWebhooks if caller.has(Permission::ManageWebhooks) => Ok(()),
+        Action::ManageWebhooks => Err(Denied("managing webhooks requires the admin role")),
+    }
+}
@@ -0,0 +1,167 @@
+//! Token-bucket rate limiting of API requests, per client and route.
+
+use crate::config::{RateLimitQuota, RateLimitSettings};
+use crate::events::now_millis;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Most buckets the in-process store holds. Reaching it, the store drops those that have
+/// refilled completely, which are no different from buckets never used, and if that is not
+/// enough, the least recently used half.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Whether a request may go ahead, and what its client has left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Requests the bucket holds when full: the quota's burst.
+    pub limit: u32,
+    /// Requests left right after this one.
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset_after: Duration,
+    /// Until the next request would be allowed; zero if this one was.
+    pub retry_after: Duration,
+}
+
+/// A token bucket's state. Stores keep one per client and route.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Bucket {
+    pub tokens: f64,
+    /// Milliseconds since the Unix epoch when `tokens` was last brought up to date.
+    pub updated_at: u64,
+}
+
+impl Bucket {
+    /// A bucket holding the whole burst, as every client starts out with.
+    pub fn full(quota: RateLimitQuota, now: u64) -> Self {
+        Self { tokens: quota.burst.into(), updated_at: now }
+    }
+
+    /// Refills the bucket for the time passed since it was last used, then takes a token from
+    /// it if there is one.
+    pub fn take(&mut self, quota: RateLimitQuota, now: u64) -> Decision {
+        let rate = refill_rate(quota);
+        let capacity = f64::from(quota.burst);
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated_at = self.updated_at.max(now);
+
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let millis = |tokens: f64| Duration::from_millis((tokens.max(0.0) / rate).ceil() as u64);
+
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: self.tokens.floor() as u32,
+            reset_after: millis(capacity - self.tokens),
+            retry_after: if allowed { Duration::ZERO } else { millis(1.0 - self.tokens) },
+        }
+    }
+
+    fn is_full(&self, quota: RateLimitQuota, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens + elapsed * refill_rate(quota) >= f64::from(quota.burst)
+    }
+}
+
+/// Tokens per millisecond; a quota of 0 per minute still refills, if slowly.
+fn refill_rate(quota: RateLimitQuota) -> f64 {
+    f64::from(quota.per_minute.max(1)) / 60_000.0
+}
+
+/// Where buckets live. The in-process [`MemoryRateLimitStore`] suits a single server; replicas
+/// sharing quotas need a store they all reach, such as Redis.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from bucket `key` under `quota` at `now` (milliseconds since the Unix
+    /// epoch), as [`Bucket::take`] does. A bucket not seen before starts [full](Bucket::full).
+    async fn acquire(&self, key: &str, quota: RateLimitQuota, now: u64) -> Result<Decision, StoreError>;
+}
+
+/// Buckets in a map in this process.
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, (Bucket, RateLimitQuota)>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn acquire(&self, key: &str, quota: RateLimitQuota, now: u64) -> Result<Decision, StoreError> {
+        let mut buckets = self.buckets.lock().expect("rate limit buckets lock poisoned");
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(key) {
+            buckets.retain(|_, (bucket, quota)| !bucket.is_full(*quota, now));
+            // Clients keeping their buckets drained (e.g. from rotating addresses) would grow
+            // the map without end; forgetting the oldest gives those clients a full bucket again.
+            if buckets.len() >= PRUNE_THRESHOLD {
+                let excess = buckets.len() - PRUNE_THRESHOLD / 2;
+                evict_least_recently_used(&mut buckets, excess);
+            }
+        }
+
+        let (bucket, _) = buckets.entry(key.to_owned()).or_insert_with(|| (Bucket::full(quota, now), quota));
+        Ok(bucket.take(quota, now))
+    }
+}
+
+/// Drops the `count` buckets updated longest ago.
+fn evict_least_recently_used(buckets: &mut HashMap<String, (Bucket, RateLimitQuota)>, count: usize) {
+    let mut updated: Vec<u64> = buckets.values().map(|(bucket, _)| bucket.updated_at).collect();
+    let (_, &mut cutoff, _) = updated.select_nth_unstable(count - 1);
+
+    // Buckets updated exactly at the cutoff may be more than needed; keep the rest of them.
+    let mut evicted = 0;
+    buckets.retain(|_, (bucket, _)| {
+        let evict = evicted < count && bucket.updated_at <= cutoff;
+        evicted += usize::from(evict);
+        !evict
+    });
+}
+
+/// The quotas of [`RateLimitSettings`] applied to buckets in a [`RateLimitStore`].
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// A limiter keeping its buckets in process.
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            store: Arc::new(MemoryRateLimitStore::new()),
+        }
+    }
+
+    pub fn with_store(self, store: impl RateLimitStore + 'static) -> Self {
+        Self { store: Arc::new(store), ..self }
+    }
+
+    /// The quota of the route named `route`.
+    pub fn quota(&self, route: &str) -> RateLimitQuota {
+        self.settings.routes.get(route).copied().unwrap_or(self.settings.default)
+    }
+
+    /// Whether client addresses may be taken from forwarding headers.
+    pub fn trusts_forwarded(&self) -> bool {
+        self.settings.trust_forwarded
+    }
+
+    /// Takes a token from `client`'s bucket for `route`; each route has its own.
+    pub async fn acquire(&self, route: &str, client: &str) -> Result<Decision, StoreError> {
+        self.store.acquire(&format!("{route} {client}"), self.quota(route), now_millis()).await
+    }
+}
@@ -0,0 +1,363 @@
+//! OpenTelemetry tracing of the HTTP requests and the [`UserRepo`] calls made for them.
+//!
+//! [`Tracing`] owns the tracer provider and its exporter. The HTTP layer starts a server span
+//! per request (see `api::tracing`) and [`TracedUserRepo`] a client span per repository call
+//! made within it, so a trace shows which queries a request ran and how long each took.
+
+use crate::config::{BackendKind, TraceExporterKind, TracingSettings};
+use crate::events::RecordedEvent;
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::users::{RecordEvents, Role, User, UserPage, UserQuery, UserRepo, UserRepoError};
+use crate::webhooks::{Delivery, Webhook};
+use opentelemetry::trace::{SpanId, SpanKind, Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::context::FutureExt;
+use opentelemetry::{Context, Key, KeyValue, Value};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use serde_json::{json, Map};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Instrumentation scope of every span the service starts.
+pub const TRACER_NAME: &str = "rust-webapp";
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("failed to open trace file {path}: {source}")]
+    File { path: PathBuf, source: io::Error },
+
+    #[error("failed to set up the OTLP exporter: {0}")]
+    Otlp(#[from] opentelemetry_otlp::ExporterBuildError),
+
+    #[error("failed to export the remaining spans: {0}")]
+    Export(#[from] OTelSdkError),
+}
+
+/// The tracer provider and the tracer the service starts its spans with.
+pub struct Tracing {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+impl Tracing {
+    /// Tracing into the exporter chosen in `settings`, or `None` if that is none. Spans are
+    /// exported in batches from a thread of their own.
+    pub fn new(settings: &TracingSettings) -> Result<Option<Self>, TelemetryError> {
+        let resource = Resource::builder().with_service_name(settings.service_name.clone()).build();
+        let builder = SdkTracerProvider::builder().with_resource(resource);
+        let builder = match settings.exporter {
+            TraceExporterKind::None => return Ok(None),
+            TraceExporterKind::Stdout => builder.with_batch_exporter(JsonLinesExporter::new(io::stdout())),
+            TraceExporterKind::File => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&settings.file)
+                    .map_err(|source| TelemetryError::File { path: settings.file.clone(), source })?;
+                builder.with_batch_exporter(JsonLinesExporter::new(file))
+            }
+            TraceExporterKind::Otlp => {
+                let exporter = opentelemetry_otlp::SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(settings.otlp_endpoint.as_str())
+                    .build()?;
+                builder.with_batch_exporter(exporter)
+            }
+        };
+        Ok(Some(Self::from_provider(builder.build())))
+    }
+
+    /// Tracing into `exporter`, each span as soon as it ends, e.g. for tests.
+    pub fn with_exporter(exporter: impl SpanExporter + 'static) -> Self {
+        Self::from_provider(SdkTracerProvider::builder().with_simple_exporter(exporter).build())
+    }
+
+    fn from_provider(provider: SdkTracerProvider) -> Self {
+        let tracer = provider.tracer(TRACER_NAME);
+        Self { provider, tracer }
+    }
+
+    pub fn tracer(&self) -> &SdkTracer {
+        &self.tracer
+    }
+
+    /// Exports the spans not exported yet, then stops exporting.
+    pub fn shutdown(&self) -> Result<(), TelemetryError> {
+        Ok(self.provider.shutdown()?)
+    }
+}
+
+/// Writes spans as JSON objects, one per line, for reading offline or feeding to `jq`.
+struct JsonLinesExporter {
+    out: Mutex<Box<dyn Write + Send>>,
+    service_name: Option<String>,
+}
+
+impl JsonLinesExporter {
+    fn new(out: impl Write + Send + 'static) -> Self {
+        Self { out: Mutex::new(Box::new(out)), service_name: None }
+    }
+
+    fn write(&self, batch: &[SpanData]) -> io::Result<()> {
+        let mut out = self.out.lock().expect("trace output lock poisoned");
+        for span in batch {
+            serde_json::to_writer(&mut *out, &self.to_json(span))?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()
+    }
+
+    fn to_json(&self, span: &SpanData) -> serde_json::Value {
+        let attributes: Map<_, _> = span
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.key.to_string(), value_to_json(&attribute.value)))
+            .collect();
+        let parent_span_id = (span.parent_span_id != SpanId::INVALID).then(|| span.parent_span_id.to_string());
+        let status = match &span.status {
+            Status::Unset => json!({ "code": "unset" }),
+            Status::Ok => json!({ "code": "ok" }),
+            Status::Error { description } => json!({ "code": "error", "description": description }),
+        };
+        json!({
+            "service_name": self.service_name,
+            "trace_id": span.span_context.trace_id().to_string(),
+            "span_id": span.span_context.span_id().to_string(),
+            "parent_span_id": parent_span_id,
+            "name": span.name,
+            "kind": format!("{:?}", span.span_kind).to_lowercase(),
+            "start_time_unix_nano": unix_nanos(span.start_time),
+            "end_time_unix_nano": unix_nanos(span.end_time),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+}
+
+impl fmt::Debug for JsonLinesExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLinesExporter").field("service_name", &self.service_name).finish_non_exhaustive()
+    }
+}
+
+impl SpanExporter for JsonLinesExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.write(&batch).map_err(|e| OTelSdkError::InternalFailure(format!("failed to write spans: {e}")))
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.service_name = resource.get(&Key::from_static_str("service.name")).map(|name| name.to_string());
+    }
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(b) => json!(b),
+        Value::I64(i) => json!(i),
+        Value::F64(f) => json!(f),
+        other => json!(other.to_string()),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+/// The `db.system` of the spans of each backend's adapter.
+fn db_system(backend: BackendKind) -> &'static str {
+    match backend {
+        BackendKind::Sqlite => "sqlite",
+        BackendKind::Mongo => "mongodb",
+        BackendKind::Memory => "memory",
+    }
+}
+
+/// Decorates any [`UserRepo`], recording a client span for every call made within a trace,
+/// with the backend as `db.system` and the method as `db.operation`.
+///
+/// Calls outside of a trace, such as the event relay's polling, are not recorded: they would
+/// make a trace of their own every second.
+pub struct TracedUserRepo<R> {
+    inner: R,
+    tracer: SdkTracer,
+    db_system: &'static str,
+}
+
+impl<R: UserRepo> TracedUserRepo<R> {
+    pub fn new(inner: R, tracing: &Tracing, backend: BackendKind) -> Self {
+        Self {
+            inner,
+            tracer: tracing.tracer().clone(),
+            db_system: db_system(backend),
+        }
+    }
+
+    async fn trace<T>(
+        &self,
+        operation: &'static str,
+        call: impl Future<Output = Result<T, UserRepoError>>,
+    ) -> Result<T, UserRepoError> {
+        let parent = Context::current();
+        if !parent.has_active_span() {
+            return call.await;
+        }
+
+        let span = self
+            .tracer
+            .span_builder(operation)
+            .with_kind(SpanKind::Client)
+            .with_attributes([KeyValue::new("db.system", self.db_system), KeyValue::new("db.operation", operation)])
+            .start_with_context(&self.tracer, &parent);
+        let cx = parent.with_span(span);
+        let result = call.with_context(cx.clone()).await;
+        if let Err(e) = &result {
+            cx.span().set_status(Status::error(e.to_string()));
+        }
+        cx.span().end();
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: UserRepo> UserRepo for TracedUserRepo<R> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
+        self.trace("add_user", self.inner.add_user(username, email, password_hash, events)).await
+    }
+
+    async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
+        self.trace("get_user", self.inner.get_user(id)).await
+    }
+
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        self.trace("update_user", self.inner.update_user(id, username, email, events)).await
+    }
+
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.trace("remove_user", self.inner.remove_user(id, events)).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
+        self.trace("list_users", self.inner.list_users()).await
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        self.trace("query_users", self.inner.query_users(query)).await
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        self.trace("count_users", self.inner.count_users(query)).await
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        self.trace("find_user_by_login", self.inner.find_user_by_login(login)).await
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        self.trace("get_password_hash", self.inner.get_password_hash(id)).await
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        self.trace("set_password_hash", self.inner.set_password_hash(id, hash, events)).await
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.trace("grant_role", self.inner.grant_role(id, role, events)).await
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.trace("revoke_role", self.inner.revoke_role(id, role, events)).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        self.trace("set_active", self.inner.set_active(id, active, events)).await
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        self.trace("pending_events", self.inner.pending_events(limit)).await
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        self.trace("mark_delivered", self.inner.mark_delivered(ids)).await
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        self.trace("add_webhook", self.inner.add_webhook(webhook)).await
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        self.trace("get_webhook", self.inner.get_webhook(id)).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        self.trace("list_webhooks", self.inner.list_webhooks()).await
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        self.trace("remove_webhook", self.inner.remove_webhook(id)).await
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        self.trace("add_deliveries", self.inner.add_deliveries(deliveries)).await
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        self.trace("get_delivery", self.inner.get_delivery(id)).await
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        self.trace("update_delivery", self.inner.update_delivery(delivery)).await
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        self.trace("due_deliveries", self.inner.due_deliveries(now, limit)).await
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        self.trace("list_deliveries", self.inner.list_deliveries(webhook_id, limit)).await
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        self.trace("claim_idempotency_key", self.inner.claim_idempotency_key(record, now)).await
+    }
+
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        self.trace("complete_idempotency_key", self.inner.complete_idempotency_key(key, response, expires_at)).await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        self.trace("release_idempotency_key", self.inner.release_idempotency_key(key)).await
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        self.trace("health_check", self.inner.health_check()).await
+    }
+
+    async fn close(&self) -> Result<(), UserRepoError> {
+        self.trace("close", self.inner.close()).await
+    }
+}
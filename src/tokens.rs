@@ -0,0 +1,191 @@
+use crate::config::{JwtAlgorithm, JwtSettings};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Shortest HS256 secret accepted, matching the size of the SHA-256 output.
+pub const MIN_SECRET_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("JWT secret must be at least {MIN_SECRET_LEN} bytes")]
+    SecretTooShort,
+
+    #[error("EdDSA needs both private_key_file and public_key_file")]
+    MissingKeyFile,
+
+    #[error("failed to read key file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("invalid key in {path}: {source}")]
+    Invalid { path: PathBuf, source: jsonwebtoken::errors::Error },
+}
+
+/// Why a token was not accepted. Deliberately coarse: callers only learn it is unusable.
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("token has expired")]
+    Expired,
+
+    #[error("token is invalid")]
+    Invalid,
+
+    #[error("expected {expected} token")]
+    WrongKind { expected: TokenKind },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    /// Short-lived, sent as `Authorization: Bearer` on API calls.
+    Access,
+    /// Long-lived, only exchanged for a new token pair.
+    Refresh,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Access => "an access",
+            Self::Refresh => "a refresh",
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    /// Id of the authenticated user.
+    pub sub: Uuid,
+    pub iss: String,
+    pub iat: u64,
+    pub exp: u64,
+    #[serde(rename = "typ")]
+    pub kind: TokenKind,
+}
+
+/// A freshly issued token and how long it stays valid.
+#[derive(Debug, Clone)]
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_in: u64,
+}
+
+/// Issues and validates signed JWTs for authenticated users. Cloning is cheap.
+#[derive(Clone)]
+pub struct Tokens {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    header: Header,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    issuer: String,
+    access_ttl: u64,
+    refresh_ttl: u64,
+}
+
+impl Tokens {
+    pub fn new(settings: &JwtSettings) -> Result<Self, KeyError> {
+        let (algorithm, encoding, decoding) = match settings.algorithm {
+            JwtAlgorithm::HS256 => {
+                let secret = match &settings.secret {
+                    Some(secret) if secret.len() < MIN_SECRET_LEN => return Err(KeyError::SecretTooShort),
+                    Some(secret) => secret.as_bytes().to_vec(),
+                    None => {
+                        log::warn!("No JWT secret configured; using a random one, so tokens will not survive a restart");
+                        let mut secret = vec![0; MIN_SECRET_LEN];
+                        OsRng.fill_bytes(&mut secret);
+                        secret
+                    }
+                };
+                (Algorithm::HS256, EncodingKey::from_secret(&secret), DecodingKey::from_secret(&secret))
+            }
+            JwtAlgorithm::EdDSA => {
+                let (Some(private), Some(public)) = (&settings.private_key_file, &settings.public_key_file) else {
+                    return Err(KeyError::MissingKeyFile);
+                };
+                let encoding = read_key(private, EncodingKey::from_ed_pem)?;
+                let decoding = read_key(public, DecodingKey::from_ed_pem)?;
+                (Algorithm::EdDSA, encoding, decoding)
+            }
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&settings.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                header: Header::new(algorithm),
+                encoding,
+                decoding,
+                validation,
+                issuer: settings.issuer.clone(),
+                access_ttl: settings.access_ttl_secs,
+                refresh_ttl: settings.refresh_ttl_secs,
+            }),
+        })
+    }
+
+    /// A token of `kind` for user `sub`.
+    pub fn issue(&self, sub: Uuid, kind: TokenKind) -> IssuedToken {
+        let ttl = match kind {
+            TokenKind::Access => self.inner.access_ttl,
+            TokenKind::Refresh => self.inner.refresh_ttl,
+        };
+        let iat = now();
+        let claims = Claims {
+            sub,
+            iss: self.inner.issuer.clone(),
+            iat,
+            exp: iat + ttl,
+            kind,
+        };
+
+        let token = jsonwebtoken::encode(&self.inner.header, &claims, &self.inner.encoding)
+            .expect("claims always serialize and keys always sign");
+        IssuedToken { token, expires_in: ttl }
+    }
+
+    /// The claims of `token` if it is correctly signed, unexpired, from our issuer and of `kind`.
+    pub fn validate(&self, token: &str, kind: TokenKind) -> Result<Claims, TokenError> {
+        use jsonwebtoken::errors::ErrorKind;
+
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.inner.decoding, &self.inner.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => TokenError::Expired,
+                _ => TokenError::Invalid,
+            })?
+            .claims;
+
+        if claims.kind != kind {
+            return Err(TokenError::WrongKind { expected: kind });
+        }
+        Ok(claims)
+    }
+}
+
+fn read_key<K>(path: &Path, parse: fn(&[u8]) -> jsonwebtoken::errors::Result<K>) -> Result<K, KeyError> {
+    let pem = fs::read(path).map_err(|source| KeyError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    parse(&pem).map_err(|source| KeyError::Invalid {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after 1970")
+        .as_secs()
+}
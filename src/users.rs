@@ -1,17 +1,100 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use crate::events::{RecordedEvent, UserEvent};
+use crate::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::webhooks::{Delivery, Webhook};
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    /// Sorted and without duplicates; empty for an ordinary user.
+    pub roles: Vec<Role>,
+    /// Deactivated users keep their data but can no longer log in.
+    pub active: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Grants extra permissions on top of what every user may do to their own account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May do anything to any user, including granting and revoking roles.
+    Admin,
+    /// May list and read any user, but not change them.
+    Auditor,
+}
+
+impl Role {
+    pub const ALL: [Role; 2] = [Role::Admin, Role::Auditor];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Auditor => "auditor",
+        }
+    }
+
+    /// Parses stored role names into the sorted, duplicate-free form [`User::roles`] uses.
+    pub fn parse_all<S: AsRef<str>>(names: &[S]) -> Result<Vec<Role>, UnknownRole> {
+        let mut roles = names.iter().map(|name| name.as_ref().parse()).collect::<Result<Vec<Role>, _>>()?;
+        roles.sort();
+        roles.dedup();
+        Ok(roles)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownRole(pub String);
+
+impl fmt::Display for UnknownRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown role `{}`", self.0)
+    }
+}
+
+impl Error for UnknownRole {}
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| UnknownRole(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConflictField { Username, Email }
 
+impl ConflictField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Username => "username",
+            Self::Email => "email",
+        }
+    }
+}
+
+impl fmt::Display for ConflictField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub enum UserRepoError {
     /// Database is unavailable / network / pool closed / timeouts, etc.
@@ -23,6 +106,9 @@ pub enum UserRepoError {
         value: String,
     },
 
+    /// A pagination cursor that this repository did not hand out.
+    InvalidCursor,
+
     /// Everything else you didn’t classify yet.
     /// Keep source for logs/telemetry, but don’t leak it to callers.
     Unexpected(Box<dyn Error + Send + Sync>),
@@ -40,7 +126,8 @@ impl fmt::Display for UserRepoError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Unavailable => write!(f, "repository unavailable"),
-            Self::Conflict { field, .. } => write!(f, "conflict on field {:?}", field),
+            Self::Conflict { field, .. } => write!(f, "conflict on field {}", field),
+            Self::InvalidCursor => write!(f, "invalid pagination cursor"),
             Self::Unexpected(_) => write!(f, "unexpected repository error"),
         }
     }
@@ -55,10 +142,380 @@ impl std::error::Error for UserRepoError {
     }
 }
 
+/// Order in which [`UserRepo::query_users`] returns users. Ties are broken by id.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UserSort {
+    /// Insertion order, oldest first.
+    #[default]
+    Created,
+    Username,
+    Email,
+}
+
+impl UserSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Username => "username",
+            Self::Email => "email",
+        }
+    }
+}
+
+/// Position after the last user of a page.
+///
+/// `key` is the sort key of that user in whatever form the adapter needs (e.g. an insertion
+/// sequence number for [`UserSort::Created`]); callers only ever see the encoded form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub sort: UserSort,
+    pub id: Uuid,
+    pub key: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}|{}|{}", self.sort.as_str(), self.id, self.key))
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, UserRepoError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| UserRepoError::InvalidCursor)?;
+        let raw = String::from_utf8(bytes).map_err(|_| UserRepoError::InvalidCursor)?;
+
+        let mut parts = raw.splitn(3, '|');
+        let (Some(sort), Some(id), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(UserRepoError::InvalidCursor);
+        };
+
+        Ok(Self {
+            sort: match sort {
+                "created" => UserSort::Created,
+                "username" => UserSort::Username,
+                "email" => UserSort::Email,
+                _ => return Err(UserRepoError::InvalidCursor),
+            },
+            id: Uuid::parse_str(id).map_err(|_| UserRepoError::InvalidCursor)?,
+            key: key.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserQuery {
+    /// Maximum number of users in the page, clamped to `1..=MAX_LIMIT`.
+    pub limit: usize,
+    /// Resume after this position; must have been produced with the same `sort`.
+    pub cursor: Option<Cursor>,
+    pub sort: UserSort,
+    /// Only users whose username starts with this string (case-sensitive).
+    pub username_prefix: Option<String>,
+    /// Only users whose email is in this domain (case-insensitive), e.g. `example.com`.
+    pub email_domain: Option<String>,
+}
+
+impl UserQuery {
+    pub const DEFAULT_LIMIT: usize = 50;
+    pub const MAX_LIMIT: usize = 500;
+
+    /// The effective page size.
+    pub fn page_size(&self) -> usize {
+        self.limit.clamp(1, Self::MAX_LIMIT)
+    }
+
+    /// The cursor to resume from, rejecting cursors issued for another sort order.
+    pub fn cursor(&self) -> Result<Option<&Cursor>, UserRepoError> {
+        match &self.cursor {
+            Some(cursor) if cursor.sort != self.sort => Err(UserRepoError::InvalidCursor),
+            cursor => Ok(cursor.as_ref()),
+        }
+    }
+
+    /// Whether `user` passes the query's filters.
+    pub fn matches(&self, user: &User) -> bool {
+        let prefix_ok = self
+            .username_prefix
+            .as_deref()
+            .is_none_or(|prefix| user.username.starts_with(prefix));
+
+        let domain_ok = self.email_domain.as_deref().is_none_or(|domain| {
+            user.email
+                .rsplit_once('@')
+                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain))
+        });
+
+        prefix_ok && domain_ok
+    }
+}
+
+impl Default for UserQuery {
+    fn default() -> Self {
+        Self {
+            limit: Self::DEFAULT_LIMIT,
+            cursor: None,
+            sort: UserSort::default(),
+            username_prefix: None,
+            email_domain: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// Present when more users match the query.
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Derives the events to record for a change from the user before and after it: `None` before
+/// an add and after a removal. Called at most once, inside the change's transaction.
+pub type RecordEvents<'a> = &'a (dyn Fn(Option<&User>, Option<&User>) -> Vec<UserEvent> + Send + Sync);
+
+/// Storage of users, plus the outbox of [`UserEvent`]s about them, the webhooks those events
+/// are delivered to and the responses kept for `Idempotency-Key` retries.
+///
+/// Every method that changes a user takes a [`RecordEvents`] and stores the events it returns
+/// in the outbox atomically with the change, so a change is never stored without its events or
+/// the other way around. Nothing is recorded if the change fails or finds no user.
 #[async_trait::async_trait]
 pub trait UserRepo: Send + Sync {
-    async fn add_user(&self, username: &str, email: &str) -> Result<User, UserRepoError>;
+    /// Adds a user with the given password hash (a PHC string), if any.
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError>;
     async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError>;
-    async fn remove_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError>;
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError>;
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError>;
     async fn list_users(&self) -> Result<Vec<User>, UserRepoError>;
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError>;
+
+    /// How many users match `query`'s filters; its limit, cursor and sort are ignored.
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError>;
+
+    /// The user whose username or email is exactly `login`.
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError>;
+
+    /// The stored password hash (a PHC string) of user `id`, if that user exists and has one.
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError>;
+
+    /// Replaces the password hash of user `id`, or removes it with `None`.
+    /// Returns `false` if there is no such user.
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError>;
+
+    /// Adds `role` to user `id`, if they do not have it yet. Returns the updated user, or
+    /// `None` if there is no such user.
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError>;
+
+    /// Removes `role` from user `id`, if they have it. Returns the updated user, or `None` if
+    /// there is no such user.
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError>;
+
+    /// Activates or deactivates user `id`. Returns the updated user, or `None` if there is no
+    /// such user.
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError>;
+
+    /// Up to `limit` events from the outbox, oldest first.
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError>;
+
+    /// Removes delivered events from the outbox. Unknown ids are ignored.
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError>;
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError>;
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError>;
+
+    /// Every webhook, oldest first.
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError>;
+
+    /// Removes webhook `id` together with its deliveries. Returns the webhook, or `None` if
+    /// there is no such webhook.
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError>;
+
+    /// Stores new deliveries, skipping any whose webhook already has a delivery of the same
+    /// event, or no longer exists.
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError>;
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError>;
+
+    /// Replaces the stored state of a delivery. Returns `false` if there is no such delivery.
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError>;
+
+    /// Up to `limit` pending deliveries due at `now` (milliseconds since the Unix epoch),
+    /// earliest first.
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError>;
+
+    /// Up to `limit` deliveries to webhook `webhook_id`, newest first.
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError>;
+
+    /// Stores `record` (without a response yet) unless its key has a record that has not
+    /// expired at `now`; returns that record instead. Expired records are removed.
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError>;
+
+    /// Stores the response for a claimed key and keeps it until `expires_at`. Does nothing if
+    /// the key has no record.
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError>;
+
+    /// Removes the record of `key`, if any, so the key can be used again.
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError>;
+
+    /// Cheap round trip to the backing store, for readiness probes.
+    async fn health_check(&self) -> Result<(), UserRepoError>;
+
+    /// Releases the connections to the backing store once the server has stopped. Calls made
+    /// afterwards fail.
+    async fn close(&self) -> Result<(), UserRepoError>;
+}
+
+/// A repository whose adapter is chosen at runtime.
+pub type DynUserRepo = Arc<dyn UserRepo>;
+
+#[async_trait::async_trait]
+impl<R: UserRepo + ?Sized> UserRepo for Arc<R> {
+    async fn add_user(
+        &self,
+        username: &str,
+        email: &str,
+        password_hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<User, UserRepoError> {
+        (**self).add_user(username, email, password_hash, events).await
+    }
+
+    async fn get_user(&self, id: Uuid) -> Result<Option<User>, UserRepoError> {
+        (**self).get_user(id).await
+    }
+
+    async fn update_user(
+        &self,
+        id: Uuid,
+        username: &str,
+        email: &str,
+        events: RecordEvents<'_>,
+    ) -> Result<Option<User>, UserRepoError> {
+        (**self).update_user(id, username, email, events).await
+    }
+
+    async fn remove_user(&self, id: Uuid, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        (**self).remove_user(id, events).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserRepoError> {
+        (**self).list_users().await
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<UserPage, UserRepoError> {
+        (**self).query_users(query).await
+    }
+
+    async fn count_users(&self, query: &UserQuery) -> Result<u64, UserRepoError> {
+        (**self).count_users(query).await
+    }
+
+    async fn find_user_by_login(&self, login: &str) -> Result<Option<User>, UserRepoError> {
+        (**self).find_user_by_login(login).await
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, UserRepoError> {
+        (**self).get_password_hash(id).await
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: Uuid,
+        hash: Option<&str>,
+        events: RecordEvents<'_>,
+    ) -> Result<bool, UserRepoError> {
+        (**self).set_password_hash(id, hash, events).await
+    }
+
+    async fn grant_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        (**self).grant_role(id, role, events).await
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: Role, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        (**self).revoke_role(id, role, events).await
+    }
+
+    async fn set_active(&self, id: Uuid, active: bool, events: RecordEvents<'_>) -> Result<Option<User>, UserRepoError> {
+        (**self).set_active(id, active, events).await
+    }
+
+    async fn pending_events(&self, limit: usize) -> Result<Vec<RecordedEvent>, UserRepoError> {
+        (**self).pending_events(limit).await
+    }
+
+    async fn mark_delivered(&self, ids: &[Uuid]) -> Result<(), UserRepoError> {
+        (**self).mark_delivered(ids).await
+    }
+
+    async fn add_webhook(&self, webhook: &Webhook) -> Result<(), UserRepoError> {
+        (**self).add_webhook(webhook).await
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        (**self).get_webhook(id).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, UserRepoError> {
+        (**self).list_webhooks().await
+    }
+
+    async fn remove_webhook(&self, id: Uuid) -> Result<Option<Webhook>, UserRepoError> {
+        (**self).remove_webhook(id).await
+    }
+
+    async fn add_deliveries(&self, deliveries: &[Delivery]) -> Result<(), UserRepoError> {
+        (**self).add_deliveries(deliveries).await
+    }
+
+    async fn get_delivery(&self, id: Uuid) -> Result<Option<Delivery>, UserRepoError> {
+        (**self).get_delivery(id).await
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) -> Result<bool, UserRepoError> {
+        (**self).update_delivery(delivery).await
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        (**self).due_deliveries(now, limit).await
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<Delivery>, UserRepoError> {
+        (**self).list_deliveries(webhook_id, limit).await
+    }
+
+    async fn claim_idempotency_key(&self, record: &IdempotencyRecord, now: u64) -> Result<Option<IdempotencyRecord>, UserRepoError> {
+        (**self).claim_idempotency_key(record, now).await
+    }
+
+    async fn complete_idempotency_key(&self, key: &str, response: &StoredResponse, expires_at: u64) -> Result<(), UserRepoError> {
+        (**self).complete_idempotency_key(key, response, expires_at).await
+    }
+
+    async fn release_idempotency_key(&self, key: &str) -> Result<(), UserRepoError> {
+        (**self).release_idempotency_key(key).await
+    }
+
+    async fn health_check(&self) -> Result<(), UserRepoError> {
+        (**self).health_check().await
+    }
+
+    async fn close(&self) -> Result<(), UserRepoError> {
+        (**self).close().await
+    }
 }
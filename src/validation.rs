@@ -0,0 +1,293 @@
+use crate::events::UserEvent;
+use std::fmt;
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+pub const EMAIL_MAX_LEN: usize = 254;
+pub const WEBHOOK_URL_MAX_LEN: usize = 2048;
+pub const WEBHOOK_SECRET_MIN_LEN: usize = 16;
+pub const WEBHOOK_SECRET_MAX_LEN: usize = 256;
+
+/// A single failed constraint on an input field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    /// Stable, machine-readable reason, e.g. `too_short`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Every constraint violated by an input, so callers can fix them all in one go.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn add(&mut self, field: &'static str, code: &'static str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field,
+            code,
+            message: message.into(),
+        });
+    }
+
+    /// `Ok(value)` if nothing was recorded.
+    pub fn into_result<T>(self, value: T) -> Result<T, ValidationErrors> {
+        if self.is_empty() { Ok(value) } else { Err(self) }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "validation failed")?;
+        for (i, error) in self.errors.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { "; " };
+            write!(f, "{sep}{}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Username and email after normalization, known to satisfy every constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserFields {
+    pub username: String,
+    pub email: String,
+}
+
+/// A webhook's settings after normalization, known to satisfy every constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookFields {
+    pub url: String,
+    pub secret: String,
+    /// Sorted and without duplicates.
+    pub event_types: Vec<String>,
+}
+
+/// Bounds on new passwords, in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+/// Trims both fields, lower-cases the email and checks them, reporting all failures together.
+pub fn validate_user_fields(username: &str, email: &str) -> Result<UserFields, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    let fields = check_user_fields(username, email, &mut errors);
+    errors.into_result(fields)
+}
+
+/// Like [`validate_user_fields`], also checking the initial `password` against `policy` if
+/// one is given.
+pub fn validate_new_user(
+    username: &str,
+    email: &str,
+    password: Option<&str>,
+    policy: &PasswordPolicy,
+) -> Result<UserFields, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    let fields = check_user_fields(username, email, &mut errors);
+    if let Some(password) = password {
+        check_password("password", password, policy, &fields, &mut errors);
+    }
+    errors.into_result(fields)
+}
+
+/// Checks a new password for the user with `fields` against `policy`, reporting failures
+/// under `field`. Passwords are never normalized.
+pub fn validate_password(
+    field: &'static str,
+    password: &str,
+    policy: &PasswordPolicy,
+    fields: &UserFields,
+) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    check_password(field, password, policy, fields, &mut errors);
+    errors.into_result(())
+}
+
+/// Checks a webhook's target `url`, signing `secret` and `event_types`, reporting all failures
+/// together. The URL is trimmed; the secret, like a password, is taken as is.
+pub fn validate_webhook(url: &str, secret: &str, event_types: &[String]) -> Result<WebhookFields, ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+
+    let url = url.trim().to_owned();
+    check_webhook_url(&url, &mut errors);
+    check_webhook_secret(secret, &mut errors);
+
+    let mut event_types = event_types.to_vec();
+    event_types.sort();
+    event_types.dedup();
+    if event_types.is_empty() {
+        errors.add("event_types", "required", "must name at least one event type");
+    }
+    if let Some(unknown) = event_types.iter().find(|kind| !UserEvent::KINDS.contains(&kind.as_str())) {
+        errors.add("event_types", "invalid_value", format!("unknown event type `{unknown}`"));
+    }
+
+    errors.into_result(WebhookFields {
+        url,
+        secret: secret.to_owned(),
+        event_types,
+    })
+}
+
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_owned()
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// A username or an email, normalized as whichever it is (usernames cannot contain `@`).
+pub fn normalize_login(login: &str) -> String {
+    if login.contains('@') { normalize_email(login) } else { normalize_username(login) }
+}
+
+fn check_user_fields(username: &str, email: &str, errors: &mut ValidationErrors) -> UserFields {
+    let username = normalize_username(username);
+    check_username(&username, errors);
+
+    let email = normalize_email(email);
+    check_email(&email, errors);
+
+    UserFields { username, email }
+}
+
+fn check_username(username: &str, errors: &mut ValidationErrors) {
+    let len = username.chars().count();
+
+    if len == 0 {
+        errors.add("username", "required", "must not be empty");
+        return;
+    }
+
+    if len < USERNAME_MIN_LEN {
+        errors.add("username", "too_short", format!("must be at least {USERNAME_MIN_LEN} characters"));
+    } else if len > USERNAME_MAX_LEN {
+        errors.add("username", "too_long", format!("must be at most {USERNAME_MAX_LEN} characters"));
+    }
+
+    if !username.chars().all(is_username_char) {
+        errors.add("username", "invalid_chars", "may only contain letters, digits, '_', '.' and '-'");
+    }
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')
+}
+
+fn check_email(email: &str, errors: &mut ValidationErrors) {
+    if email.is_empty() {
+        errors.add("email", "required", "must not be empty");
+        return;
+    }
+
+    if email.len() > EMAIL_MAX_LEN {
+        errors.add("email", "too_long", format!("must be at most {EMAIL_MAX_LEN} characters"));
+        return;
+    }
+
+    if !is_valid_email(email) {
+        errors.add("email", "invalid_format", "must be an address like name@example.com");
+    }
+}
+
+fn check_webhook_url(url: &str, errors: &mut ValidationErrors) {
+    if url.is_empty() {
+        errors.add("url", "required", "must not be empty");
+        return;
+    }
+
+    if url.len() > WEBHOOK_URL_MAX_LEN {
+        errors.add("url", "too_long", format!("must be at most {WEBHOOK_URL_MAX_LEN} characters"));
+        return;
+    }
+
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.host().is_some() => {}
+        _ => errors.add("url", "invalid_format", "must be an absolute http or https URL"),
+    }
+}
+
+fn check_webhook_secret(secret: &str, errors: &mut ValidationErrors) {
+    let len = secret.chars().count();
+
+    if len == 0 {
+        errors.add("secret", "required", "must not be empty");
+    } else if len < WEBHOOK_SECRET_MIN_LEN {
+        errors.add("secret", "too_short", format!("must be at least {WEBHOOK_SECRET_MIN_LEN} characters"));
+    } else if len > WEBHOOK_SECRET_MAX_LEN {
+        errors.add("secret", "too_long", format!("must be at most {WEBHOOK_SECRET_MAX_LEN} characters"));
+    }
+}
+
+fn check_password(field: &'static str, password: &str, policy: &PasswordPolicy, fields: &UserFields, errors: &mut ValidationErrors) {
+    let len = password.chars().count();
+
+    if len == 0 {
+        errors.add(field, "required", "must not be empty");
+        return;
+    }
+
+    if len < policy.min_length {
+        errors.add(field, "too_short", format!("must be at least {} characters", policy.min_length));
+    } else if len > policy.max_length {
+        errors.add(field, "too_long", format!("must be at most {} characters", policy.max_length));
+    }
+
+    if password.chars().any(char::is_control) {
+        errors.add(field, "invalid_chars", "must not contain control characters");
+    }
+
+    let lowered = password.to_lowercase();
+    let local_part = fields.email.split('@').next().unwrap_or_default();
+    if [fields.username.to_lowercase().as_str(), local_part]
+        .iter()
+        .any(|identity| identity.chars().count() >= USERNAME_MIN_LEN && lowered.contains(identity))
+    {
+        errors.add(field, "contains_identity", "must not contain the username or email");
+    }
+}
+
+/// Pragmatic subset of RFC 5321 addresses: dot-atom local part, and a domain of at least two
+/// DNS labels. Quoted local parts and IP literals are deliberately rejected.
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c))
+        });
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    local_ok && domain_ok
+}
@@ -0,0 +1,260 @@
+use crate::config::WebhookSettings;
+use crate::errors::error_chain;
+use crate::events::{now_millis, RecordedEvent};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Carries `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`; see [`sign`].
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+/// Id of the delivery, the same on every attempt.
+pub const ID_HEADER: &str = "Webhook-Id";
+/// `type` of the event in the body, e.g. `registered`.
+pub const EVENT_HEADER: &str = "Webhook-Event";
+
+/// A subscription to user events: every matching event is POSTed to `url` as JSON, signed
+/// with `secret`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    pub secret: String,
+    /// [`UserEvent::kind`](crate::events::UserEvent::kind)s to deliver, sorted and without
+    /// duplicates.
+    pub event_types: Vec<String>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &RecordedEvent) -> bool {
+        self.event_types.iter().any(|kind| kind == event.event.kind())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Not delivered yet; attempted again at [`Delivery::next_attempt_at`].
+    Pending,
+    /// The receiver answered with a 2xx status.
+    Succeeded,
+    /// Every attempt failed; only a manual redelivery tries again.
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownStatus(pub String);
+
+impl fmt::Display for UnknownStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown delivery status `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownStatus {}
+
+impl FromStr for DeliveryStatus {
+    type Err = UnknownStatus;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Pending, Self::Succeeded, Self::Failed]
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| UnknownStatus(s.to_owned()))
+    }
+}
+
+/// One event on its way to one webhook, and how far it got. There is at most one delivery
+/// per webhook and event, however often the relay hands the event over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event_id: Uuid,
+    pub event_type: String,
+    /// The request body: the event as JSON.
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// When the dispatcher tries next, in milliseconds since the Unix epoch; `None` unless
+    /// pending.
+    pub next_attempt_at: Option<u64>,
+    pub last_attempt_at: Option<u64>,
+    /// HTTP status of the last response, if the receiver answered at all.
+    pub last_status_code: Option<u16>,
+    /// Why the last attempt failed.
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+impl Delivery {
+    /// A delivery of `event` to `webhook`, due right away.
+    pub fn new(webhook: &Webhook, event: &RecordedEvent) -> Self {
+        let now = now_millis();
+        Self {
+            id: Uuid::new_v4(),
+            webhook_id: webhook.id,
+            event_id: event.id,
+            event_type: event.event.kind().to_owned(),
+            payload: serde_json::to_string(event).expect("events serialize to JSON"),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: Some(now),
+            last_attempt_at: None,
+            last_status_code: None,
+            last_error: None,
+            created_at: now,
+        }
+    }
+
+    /// Records the outcome of an attempt made at `now`: done on success, otherwise retried
+    /// with exponential backoff until `settings.max_attempts` is reached.
+    pub fn record_attempt(&mut self, now: u64, outcome: Attempt, settings: &WebhookSettings) {
+        self.attempts += 1;
+        self.last_attempt_at = Some(now);
+        self.last_status_code = outcome.status_code;
+        self.last_error = outcome.error;
+
+        self.status = if self.last_error.is_none() {
+            DeliveryStatus::Succeeded
+        } else if self.attempts >= settings.max_attempts {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+        self.next_attempt_at = match self.status {
+            DeliveryStatus::Pending => Some(now + retry_delay(self.attempts, settings).as_millis() as u64),
+            _ => None,
+        };
+    }
+
+    /// Makes the delivery due again right away, with a fresh set of attempts.
+    pub fn redeliver(&mut self) {
+        self.status = DeliveryStatus::Pending;
+        self.attempts = 0;
+        self.next_attempt_at = Some(now_millis());
+    }
+}
+
+/// How long to wait after the `attempts`th failed attempt: `retry_initial_ms`, doubled after
+/// every further failure, up to `retry_max_ms`.
+pub fn retry_delay(attempts: u32, settings: &WebhookSettings) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_millis(settings.retry_initial_ms.saturating_mul(factor).min(settings.retry_max_ms))
+}
+
+/// What came of sending a delivery once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub status_code: Option<u16>,
+    /// `None` on success.
+    pub error: Option<String>,
+}
+
+/// The [`SIGNATURE_HEADER`] value for `body` sent at `timestamp` (Unix seconds).
+///
+/// Receivers recompute the HMAC over the timestamp, a `.` and the raw body, compare it in
+/// constant time and reject old timestamps, which stops replays of captured requests.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    format!("t={timestamp},v1={}", hex::encode(signature(secret, timestamp, body).finalize().into_bytes()))
+}
+
+/// Whether `header` is a valid [`SIGNATURE_HEADER`] for `body`, signed with `secret` no more
+/// than `tolerance` from `now` (Unix seconds).
+pub fn verify(secret: &str, header: &str, body: &[u8], now: u64, tolerance: Duration) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+            Some(("v1", value)) => signatures.extend(hex::decode(value).ok()),
+            _ => {}
+        }
+    }
+
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    if now.abs_diff(timestamp) > tolerance.as_secs() {
+        return false;
+    }
+
+    signatures
+        .iter()
+        .any(|expected| signature(secret, timestamp, body).verify_slice(expected).is_ok())
+}
+
+fn signature(secret: &str, timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// Sends deliveries over HTTP.
+#[derive(Debug, Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+}
+
+impl WebhookSender {
+    pub fn new(settings: &WebhookSettings) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .user_agent(concat!("rust-webapp-webhooks/", env!("CARGO_PKG_VERSION")))
+            // A redirect would resend the signed body to wherever the receiver points.
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self { client })
+    }
+
+    /// POSTs `delivery` to `webhook`, signed now. Any 2xx response counts as delivered.
+    pub async fn send(&self, webhook: &Webhook, delivery: &Delivery) -> Attempt {
+        let timestamp = now_millis() / 1000;
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(ID_HEADER, delivery.id.to_string())
+            .header(EVENT_HEADER, &delivery.event_type)
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, timestamp, delivery.payload.as_bytes()))
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => Attempt {
+                status_code: Some(response.status().as_u16()),
+                error: None,
+            },
+            Ok(response) => Attempt {
+                status_code: Some(response.status().as_u16()),
+                error: Some(format!("receiver answered {}", response.status())),
+            },
+            Err(e) => Attempt {
+                status_code: None,
+                error: Some(error_chain(&e)),
+            },
+        }
+    }
+}
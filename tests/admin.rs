@@ -0,0 +1,135 @@
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// A fresh SQLite database and an empty config file, so nothing in the working directory
+/// leaks in.
+struct Backend {
+    config: PathBuf,
+    database: PathBuf,
+}
+
+impl Backend {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir();
+        let prefix = format!("rust-webapp-admin-{}-{name}", std::process::id());
+        let backend = Self {
+            config: dir.join(format!("{prefix}.toml")),
+            database: dir.join(format!("{prefix}.sqlite")),
+        };
+        std::fs::write(&backend.config, "").expect("Failed to write config file");
+        let _ = std::fs::remove_file(&backend.database);
+        backend
+    }
+
+    fn admin(&self, args: &[&str], stdin: Option<&str>) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rust-webapp-admin"))
+            .arg("--config")
+            .arg(&self.config)
+            .args(["--backend", "sqlite", "--sqlite-path"])
+            .arg(&self.database)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to run rust-webapp-admin");
+        if let Some(input) = stdin {
+            child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        }
+        child.wait_with_output().expect("Failed to wait for rust-webapp-admin")
+    }
+
+    /// Standard output of a run that must succeed.
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.admin(args, None);
+        assert!(output.status.success(), "{args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).expect("Output is not UTF-8")
+    }
+
+    /// Standard error of a run that must fail.
+    fn fail(&self, args: &[&str]) -> String {
+        let output = self.admin(args, None);
+        assert!(!output.status.success(), "{args:?} succeeded");
+        assert!(output.stdout.is_empty());
+        String::from_utf8(output.stderr).expect("Output is not UTF-8")
+    }
+
+    fn json(&self, args: &[&str]) -> Value {
+        serde_json::from_str(&self.run(args)).expect("Output is not JSON")
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.config);
+        let _ = std::fs::remove_file(&self.database);
+    }
+}
+
+#[test]
+fn users_are_managed_from_the_command_line() {
+    let backend = Backend::new("manage");
+    assert_eq!(backend.run(&["migrate"]), "");
+
+    let alice = backend.json(&["create", "alice", "Alice@Example.com", "--format", "json"]);
+    assert_eq!((alice["username"].as_str(), alice["email"].as_str()), (Some("alice"), Some("alice@example.com")));
+    let id = alice["id"].as_str().unwrap();
+    backend.run(&["create", "bob", "bob@example.org"]);
+
+    assert_eq!(backend.json(&["get", id, "-f", "json"]), alice);
+    assert_eq!(backend.json(&["find", "ALICE@example.com", "-f", "json"]), alice);
+    assert_eq!(backend.run(&["count"]), "2\n");
+    assert_eq!(backend.run(&["count", "--email-domain", "example.org"]), "1\n");
+
+    let listed = backend.json(&["list", "--sort", "email", "-f", "json"]);
+    let usernames: Vec<_> = listed.as_array().unwrap().iter().map(|user| user["username"].as_str().unwrap()).collect();
+    assert_eq!(usernames, ["alice", "bob"]);
+
+    assert_eq!(backend.json(&["delete", id, "-f", "json"]), alice);
+    assert_eq!(backend.run(&["count"]), "1\n");
+    assert!(backend.fail(&["get", id]).contains(&format!("no user with id {id}")));
+    assert!(backend.fail(&["delete", id]).contains("no user"));
+    assert!(backend.fail(&["find", "alice"]).contains("no user with username or email alice"));
+}
+
+#[test]
+fn listings_come_as_tables_json_or_csv() {
+    let backend = Backend::new("formats");
+    backend.run(&["create", "carol", "carol@example.com"]);
+    backend.run(&["create", "dave", "dave@example.com"]);
+
+    let table = backend.run(&["list"]);
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("ID") && lines[0].ends_with("ACTIVE"));
+    // Columns line up.
+    assert_eq!(lines[1].find("carol@"), lines[2].find("dave@"));
+
+    let csv = backend.run(&["list", "--format", "csv", "--username-prefix", "d"]);
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines[0], "id,username,email,roles,active");
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].ends_with(",dave,dave@example.com,,true"), "{}", lines[1]);
+
+    assert_eq!(backend.json(&["list", "-f", "json", "--username-prefix", "z"]), Value::Array(Vec::new()));
+}
+
+#[test]
+fn invalid_input_is_rejected() {
+    let backend = Backend::new("invalid");
+
+    let error = backend.fail(&["create", "x", "not-an-email"]);
+    assert!(error.contains("username") && error.contains("email"), "{error}");
+
+    let output = backend.admin(&["create", "erin", "erin@example.com", "--password-stdin"], Some("short\n"));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("password"));
+
+    let output = backend.admin(&["create", "erin", "erin@example.com", "--password-stdin"], Some("a long and secret phrase\n"));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(backend.run(&["count"]), "1\n");
+
+    backend.fail(&["get", "not-a-uuid"]);
+}
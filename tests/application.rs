@@ -1,34 +1,551 @@
 mod common;
 
 use log::info;
-use rust_webapp::app::Application;
-use rust_webapp::users::UserRepo;
+use rust_webapp::app::{AppError, Application, RelayError, INVALID_CREDENTIALS};
+use rust_webapp::config::{IdempotencySettings, RelaySettings, WebhookSettings};
+use rust_webapp::events::{EventSink, RecordedEvent, SinkError, UserEvent};
+use rust_webapp::idempotency::{Claim, StoredResponse};
+use rust_webapp::policy::Caller;
+use rust_webapp::users::{ConflictField, Cursor, Role, UserPage, UserQuery, UserRepo, UserRepoError, UserSort};
+use std::sync::{Arc, Mutex};
+use rust_webapp::webhooks::WebhookSender;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 
-async fn scenario_add_user<R: UserRepo>(app: &mut Application<R>) {
-    assert_eq!(app.users.list_users().await.expect("Failed to get users").len(), 0);
-    app.users.add_user("johndoe".into(), "johndoe@example.com".into()).await.expect("Failed to add user");
-    assert_eq!(app.users.list_users().await.expect("Failed to get users").len(), 1);
+async fn count<R: UserRepo>(app: &Application<R>) -> usize {
+    let query = UserQuery { limit: 100, ..UserQuery::default() };
+    app.list_users(&Caller::system(), &query).await.expect("Failed to list users").users.len()
 }
 
-async fn scenario_remove_user<R: UserRepo>(app: &mut Application<R>) {
-    let addeduser = app.users.add_user("janedoe".into(), "johndoe@example.com".into()).await.expect("Failed to add user");
+async fn scenario_add_user<R: UserRepo>(app: Application<R>) {
+    assert_eq!(count(&app).await, 0);
+    let added = app.register(" johndoe ", "JohnDoe@Example.com", None).await.expect("Failed to register user");
+    assert_eq!((added.username.as_str(), added.email.as_str()), ("johndoe", "johndoe@example.com"));
+    assert!(added.active);
+    assert_eq!(count(&app).await, 1);
+}
+
+async fn scenario_remove_user<R: UserRepo>(app: Application<R>) {
+    let addeduser = app.register("janedoe", "johndoe@example.com", None).await.expect("Failed to register user");
     info!(target: "Users", "Removing user: {:?}", addeduser);
-    let users = app.users.list_users().await.expect("Failed to get users");
-    assert_eq!(users.len(), 1);
-    let removed = app.users.remove_user(addeduser.id).await.expect("Failed to remove user");
+    assert_eq!(count(&app).await, 1);
+
+    // Users may delete themselves, but nobody else without a role.
+    let stranger = Caller { id: uuid::Uuid::new_v4(), roles: vec![] };
+    let err = app.delete_user(&stranger, addeduser.id).await.expect_err("Stranger deleted a user");
+    assert!(matches!(err, AppError::Denied(_)));
+
+    let removed = app.delete_user(&Caller::from(&addeduser), addeduser.id).await.expect("Failed to remove user");
     info!(target: "Users", "Removed user: {:?}", removed);
-    assert!(removed.is_some());
-    assert_eq!(app.users.list_users().await.expect("Failed to get users").len(), 0);
+    assert_eq!(removed.id, addeduser.id);
+    assert_eq!(count(&app).await, 0);
+
+    let err = app.delete_user(&Caller::system(), addeduser.id).await.expect_err("Removed a missing user");
+    assert!(matches!(err, AppError::NotFound));
+}
+
+async fn scenario_list_users<R: UserRepo>(app: Application<R>) {
+    assert_eq!(count(&app).await, 0);
+    let alice = app.register("alice", "alice@example.com", None).await.expect("Failed to register user");
+    app.register("bob", "bob@example.com", None).await.expect("Failed to register user");
+    assert_eq!(count(&app).await, 2);
+
+    let err = app.list_users(&Caller::from(&alice), &UserQuery::default()).await.expect_err("Listed without a role");
+    assert!(matches!(err, AppError::Denied(_)));
+}
+
+async fn scenario_update_user<R: UserRepo>(app: Application<R>) {
+    let added = app.register("carol", "carol@example.com", None).await.expect("Failed to register user");
+    let carol = Caller::from(&added);
+    let updated = app.update_user(&carol, added.id, "carol", " Carol@Example.org").await.expect("Failed to update user");
+    assert_eq!(updated.email, "carol@example.org");
+    let fetched = app.get_user(&carol, added.id).await.expect("Failed to get user");
+    assert_eq!(fetched.id, added.id);
+    assert_eq!(fetched.email, "carol@example.org");
+
+    let renamed = app.rename(&carol, added.id, "caroline").await.expect("Failed to rename user");
+    assert_eq!((renamed.username.as_str(), renamed.email.as_str()), ("caroline", "carol@example.org"));
+    let moved = app.change_email(&carol, added.id, "caroline@example.com").await.expect("Failed to change email");
+    assert_eq!((moved.username.as_str(), moved.email.as_str()), ("caroline", "caroline@example.com"));
+
+    let err = app.rename(&carol, added.id, "x").await.expect_err("Invalid username accepted");
+    assert!(matches!(err, AppError::Validation(_)));
+
+    let missing = uuid::Uuid::new_v4();
+    let err = app.update_user(&Caller::system(), missing, "nobody", "nobody@example.com").await.expect_err("Updated a missing user");
+    assert!(matches!(err, AppError::NotFound));
+}
+
+async fn scenario_unique_fields<R: UserRepo>(app: Application<R>) {
+    let system = Caller::system();
+    let dave = app.register("dave", "dave@example.com", None).await.expect("Failed to register user");
+
+    let err = app.register("dave", "other@example.com", None).await.expect_err("Duplicate username accepted");
+    assert!(matches!(err, AppError::Repo(UserRepoError::Conflict { field: ConflictField::Username, ref value }) if value == "dave"));
+
+    let err = app.register("other", "dave@example.com", None).await.expect_err("Duplicate email accepted");
+    assert!(matches!(err, AppError::Repo(UserRepoError::Conflict { field: ConflictField::Email, ref value }) if value == "dave@example.com"));
+
+    let erin = app.register("erin", "erin@example.com", None).await.expect("Failed to register user");
+    let err = app.change_email(&system, erin.id, "dave@example.com").await.expect_err("Duplicate email accepted on update");
+    assert!(matches!(err, AppError::Repo(UserRepoError::Conflict { field: ConflictField::Email, .. })));
+
+    // Re-saving a user's own values is not a conflict.
+    app.update_user(&system, dave.id, "dave", "dave@example.com").await.expect("Failed to update user");
+
+    // Freed values can be reused.
+    app.delete_user(&system, dave.id).await.expect("Failed to remove user");
+    app.register("dave", "dave@example.com", None).await.expect("Failed to re-register user");
+    assert_eq!(count(&app).await, 2);
+}
+
+async fn scenario_query_users<R: UserRepo>(app: Application<R>) {
+    for (username, email) in [
+        ("mallory", "mallory@evil.test"),
+        ("alice", "alice@example.com"),
+        ("bob", "bob@Example.COM"),
+        ("alfred", "alfred@example.org"),
+        ("albert", "albert@example.com"),
+    ] {
+        app.register(username, email, None).await.expect("Failed to register user");
+    }
+
+    let system = Caller::system();
+    let usernames = |page: &UserPage| page.users.iter().map(|u| u.username.clone()).collect::<Vec<_>>();
+
+    // Created order, walked two at a time.
+    let mut query = UserQuery { limit: 2, ..UserQuery::default() };
+    let first = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&first), ["mallory", "alice"]);
+    query.cursor = first.next_cursor;
+    let second = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&second), ["bob", "alfred"]);
+    query.cursor = second.next_cursor;
+    let third = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&third), ["albert"]);
+    assert!(third.next_cursor.is_none());
+
+    // Username order with a prefix filter; the cursor survives an encode/decode round trip.
+    let mut query = UserQuery { limit: 2, sort: UserSort::Username, username_prefix: Some("al".into()), ..UserQuery::default() };
+    let first = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&first), ["albert", "alfred"]);
+    let encoded = first.next_cursor.expect("Missing cursor").encode();
+    query.cursor = Some(Cursor::decode(&encoded).expect("Failed to decode cursor"));
+    let second = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&second), ["alice"]);
+    assert!(second.next_cursor.is_none());
+
+    // Email domain filter is case-insensitive and exact.
+    let query = UserQuery { sort: UserSort::Email, email_domain: Some("example.com".into()), ..UserQuery::default() };
+    let page = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&page), ["albert", "alice", "bob"]);
+    // Counting applies the filters but not the page size.
+    let query = UserQuery { limit: 1, ..query };
+    assert_eq!(app.count_users(&system, &query).await.expect("Failed to count users"), 3);
+    let query = UserQuery { username_prefix: Some("al".into()), ..query };
+    assert_eq!(app.count_users(&system, &query).await.expect("Failed to count users"), 2);
+    assert_eq!(app.count_users(&system, &UserQuery::default()).await.expect("Failed to count users"), 5);
+
+    // A cursor issued for one sort order is rejected for another.
+    let query = UserQuery { limit: 1, sort: UserSort::Username, ..UserQuery::default() };
+    let cursor = app.list_users(&system, &query).await.expect("Failed to query users").next_cursor;
+    let query = UserQuery { cursor, sort: UserSort::Email, ..UserQuery::default() };
+    let err = app.list_users(&system, &query).await.expect_err("Mismatched cursor accepted");
+    assert!(matches!(err, AppError::Repo(UserRepoError::InvalidCursor)));
+}
+
+async fn scenario_created_order<R: UserRepo>(app: Application<R>) {
+    let system = Caller::system();
+    let usernames = |page: &UserPage| page.users.iter().map(|u| u.username.clone()).collect::<Vec<_>>();
+    let mut users = Vec::new();
+    for username in ["anna", "bert", "cleo"] {
+        users.push(app.register(username, &format!("{username}@example.com"), None).await.expect("Failed to register user"));
+    }
+
+    let mut query = UserQuery { limit: 2, ..UserQuery::default() };
+    let first = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&first), ["anna", "bert"]);
+
+    // Deleting the newest users leaves the cursor past the last one; positions are never
+    // handed out again, so users added afterwards still come after it.
+    app.delete_user(&system, users[2].id).await.expect("Failed to remove user");
+    app.delete_user(&system, users[1].id).await.expect("Failed to remove user");
+    app.register("dora", "dora@example.com", None).await.expect("Failed to register user");
+
+    query.cursor = first.next_cursor;
+    let second = app.list_users(&system, &query).await.expect("Failed to query users");
+    assert_eq!(usernames(&second), ["dora"]);
+    let all = app.list_users(&system, &UserQuery::default()).await.expect("Failed to query users");
+    assert_eq!(usernames(&all), ["anna", "dora"]);
+}
+
+async fn scenario_passwords<R: UserRepo>(app: Application<R>) {
+    let frank = app.register("frank", "frank@example.com", Some("correct horse battery")).await.expect("Failed to register user");
+    let caller = Caller::from(&frank);
+
+    for login in ["frank", " Frank@Example.com "] {
+        let found = app.authenticate(login, "correct horse battery").await.expect("Failed to authenticate");
+        assert_eq!(found.id, frank.id);
+    }
+    for (login, password) in [("frank", "wrong horse battery"), ("nobody", "correct horse battery")] {
+        let err = app.authenticate(login, password).await.expect_err("Bad credentials accepted");
+        assert!(matches!(err, AppError::Unauthenticated(INVALID_CREDENTIALS)));
+    }
+
+    // Profile updates leave the password alone.
+    app.rename(&caller, frank.id, "franky").await.expect("Failed to rename user");
+    app.authenticate("franky", "correct horse battery").await.expect("Failed to authenticate after rename");
+
+    let err = app.change_password(&caller, frank.id, Some("wrong horse battery"), "staple in the stable").await.expect_err("Wrong current password accepted");
+    assert!(matches!(err, AppError::Unauthenticated(_)));
+    let err = app.change_password(&caller, frank.id, Some("correct horse battery"), "short").await.expect_err("Weak password accepted");
+    assert!(matches!(err, AppError::Validation(_)));
+    let err = app.change_password(&Caller::system(), frank.id, None, "staple in the stable").await.expect_err("Admin set a password");
+    assert!(matches!(err, AppError::Denied(_)));
+
+    app.change_password(&caller, frank.id, Some("correct horse battery"), "staple in the stable").await.expect("Failed to change password");
+    app.authenticate("franky", "staple in the stable").await.expect("Failed to authenticate with new password");
+    assert!(app.authenticate("franky", "correct horse battery").await.is_err());
+
+    // Users registered without a password cannot log in until they set one.
+    let grace = app.register("grace", "grace@example.com", None).await.expect("Failed to register user");
+    assert!(app.authenticate("grace", "").await.is_err());
+    app.change_password(&Caller::from(&grace), grace.id, None, "a long and secret phrase").await.expect("Failed to set password");
+    app.authenticate("grace", "a long and secret phrase").await.expect("Failed to authenticate");
+
+    // A failed registration stores neither the user nor the password, so it can be retried.
+    let err = app.register("heidi", "grace@example.com", Some("a long and secret phrase")).await.expect_err("Duplicate email accepted");
+    assert!(matches!(err, AppError::Repo(UserRepoError::Conflict { field: ConflictField::Email, .. })));
+    app.register("heidi", "heidi@example.com", Some("a long and secret phrase")).await.expect("Failed to retry registration");
+    app.authenticate("heidi", "a long and secret phrase").await.expect("Failed to authenticate");
+}
+
+async fn scenario_roles<R: UserRepo>(app: Application<R>) {
+    let system = Caller::system();
+    let added = app.register("dave", "dave@example.com", None).await.expect("Failed to register user");
+    assert!(added.roles.is_empty());
+
+    let err = app.grant_role(&Caller::from(&added), added.id, Role::Admin).await.expect_err("User granted themselves a role");
+    assert!(matches!(err, AppError::Denied(_)));
+
+    for role in [Role::Auditor, Role::Admin, Role::Auditor] {
+        app.grant_role(&system, added.id, role).await.expect("Failed to grant role");
+    }
+    let fetched = app.get_user(&system, added.id).await.expect("Failed to get user");
+    assert_eq!(fetched.roles, [Role::Admin, Role::Auditor]);
+
+    // Roles survive updates and show up wherever users are read.
+    let updated = app.rename(&system, added.id, "david").await.expect("Failed to update user");
+    assert_eq!(updated.roles, [Role::Admin, Role::Auditor]);
+    let found = app.find_user(&system, "david").await.expect("Failed to find user");
+    assert_eq!(found.roles, [Role::Admin, Role::Auditor]);
+    let page = app.list_users(&system, &UserQuery::default()).await.expect("Failed to query users");
+    assert_eq!(page.users[0].roles, [Role::Admin, Role::Auditor]);
+
+    // Roles are read afresh for every caller.
+    let dave = app.caller(added.id).await.expect("Failed to load caller");
+    assert_eq!(dave.roles, [Role::Admin, Role::Auditor]);
+    let err = app.revoke_role(&dave, added.id, Role::Admin).await.expect_err("Admin revoked their own admin role");
+    assert!(matches!(err, AppError::Denied(_)));
+
+    let revoked = app.revoke_role(&system, added.id, Role::Admin).await.expect("Failed to revoke role");
+    assert_eq!(revoked.roles, [Role::Auditor]);
+    let revoked = app.revoke_role(&system, added.id, Role::Admin).await.expect("Failed to revoke role");
+    assert_eq!(revoked.roles, [Role::Auditor]);
+
+    let missing = uuid::Uuid::new_v4();
+    assert!(matches!(app.grant_role(&system, missing, Role::Admin).await, Err(AppError::NotFound)));
+    assert!(matches!(app.revoke_role(&system, missing, Role::Admin).await, Err(AppError::NotFound)));
+}
+
+async fn scenario_deactivation<R: UserRepo>(app: Application<R>) {
+    let system = Caller::system();
+    let heidi = app.register("heidi", "heidi@example.com", Some("a long and secret phrase")).await.expect("Failed to register user");
+    let caller = Caller::from(&heidi);
+
+    let deactivated = app.deactivate(&caller, heidi.id).await.expect("Failed to deactivate user");
+    assert!(!deactivated.active);
+    assert!(!app.get_user(&system, heidi.id).await.expect("Failed to get user").active);
+
+    // The right password is recognized, but no longer lets the user in.
+    let err = app.authenticate("heidi", "a long and secret phrase").await.expect_err("Deactivated user authenticated");
+    assert!(matches!(err, AppError::Unauthenticated("account is deactivated")));
+    let err = app.authenticate("heidi", "the wrong phrase").await.expect_err("Wrong password accepted");
+    assert!(matches!(err, AppError::Unauthenticated(INVALID_CREDENTIALS)));
+    assert!(matches!(app.caller(heidi.id).await, Err(AppError::Unauthenticated(_))));
+
+    let err = app.reactivate(&caller, heidi.id).await.expect_err("User reactivated themselves");
+    assert!(matches!(err, AppError::Denied(_)));
+
+    let reactivated = app.reactivate(&system, heidi.id).await.expect("Failed to reactivate user");
+    assert!(reactivated.active);
+    app.authenticate("heidi", "a long and secret phrase").await.expect("Failed to authenticate after reactivation");
+
+    let missing = uuid::Uuid::new_v4();
+    assert!(matches!(app.deactivate(&system, missing).await, Err(AppError::NotFound)));
 }
 
-async fn scenario_list_users<R: UserRepo>(app: &mut Application<R>) {
-    assert_eq!(app.users.list_users().await.expect("Failed to get users").len(), 0);
-    app.users.add_user("alice".into(), "alice@example.com".into()).await.expect("Failed to add user");
-    app.users.add_user("bob".into(), "bob@example.com".into()).await.expect("Failed to add user");
-    let users = app.users.list_users().await.expect("Failed to get users");
-    assert_eq!(users.len(), 2);
+async fn scenario_events<R: UserRepo>(app: Application<R>) {
+    let system = Caller::system();
+    let mut events = app.subscribe();
+
+    let ivan = app.register("ivan", "ivan@example.com", Some("a long and secret phrase")).await.expect("Failed to register user");
+    app.update_user(&system, ivan.id, "ivan", "ivan@example.org").await.expect("Failed to update user");
+    app.update_user(&system, ivan.id, "ivan", "ivan@example.org").await.expect("Failed to update user");
+    app.rename(&system, ivan.id, "ivan2").await.expect("Failed to rename user");
+    app.change_password(&Caller::from(&ivan), ivan.id, Some("a long and secret phrase"), "another long phrase").await.expect("Failed to change password");
+    app.grant_role(&system, ivan.id, Role::Auditor).await.expect("Failed to grant role");
+    app.grant_role(&system, ivan.id, Role::Auditor).await.expect("Failed to grant role");
+    app.deactivate(&system, ivan.id).await.expect("Failed to deactivate user");
+    app.deactivate(&system, ivan.id).await.expect("Failed to deactivate user");
+    app.reactivate(&system, ivan.id).await.expect("Failed to reactivate user");
+    app.delete_user(&system, ivan.id).await.expect("Failed to delete user");
+
+    // Failed operations and no-op changes record nothing.
+    assert!(app.delete_user(&system, ivan.id).await.is_err());
+    assert!(app.register("x", "x@example.com", None).await.is_err());
+
+    // Subscribers only hear of events once they are relayed.
+    assert!(events.try_recv().is_err());
+    assert_eq!(app.relay_events(100).await.expect("Failed to relay events"), 8);
+    assert_eq!(app.relay_events(100).await.expect("Failed to relay events"), 0);
+
+    let mut received = Vec::new();
+    while let Ok(recorded) = events.try_recv() {
+        assert_eq!(recorded.event.user_id(), ivan.id);
+        received.push(recorded.event);
+    }
+    assert!(matches!(&received[..], [
+        UserEvent::Registered { user },
+        UserEvent::EmailChanged { previous_email, .. },
+        UserEvent::Renamed { previous_username, user: renamed },
+        UserEvent::PasswordChanged { .. },
+        UserEvent::RolesChanged { user: auditor },
+        UserEvent::Deactivated { .. },
+        UserEvent::Reactivated { .. },
+        UserEvent::Deleted { .. },
+    ] if user.username == "ivan"
+        && previous_email == "ivan@example.com"
+        && previous_username == "ivan"
+        && renamed.username == "ivan2"
+        && auditor.roles == [Role::Auditor]));
+}
+
+async fn scenario_replay<R: UserRepo>(app: Application<R>) {
+    let app = app.with_replay_capacity(2);
+    let system = Caller::system();
+
+    let stranger = Caller { id: uuid::Uuid::new_v4(), roles: vec![] };
+    assert!(matches!(app.watch_users(&stranger, None), Err(AppError::Denied(_))));
+
+    let mut changes = app.watch_users(&system, None).expect("Failed to watch users");
+    assert_eq!(changes.missed, Some(vec![]));
+    for username in ["lena", "lars", "liv"] {
+        app.register(username, &format!("{username}@example.com"), None).await.expect("Failed to register user");
+    }
+    app.relay_events(100).await.expect("Failed to relay events");
+    let mut relayed = Vec::new();
+    while let Ok(recorded) = changes.live.try_recv() {
+        relayed.push(recorded);
+    }
+    assert_eq!(relayed.len(), 3);
+
+    // Only the latest two are kept, so resuming from the first may have missed some.
+    let ids: Vec<_> = relayed.iter().map(|recorded| recorded.id).collect();
+    let missed = |after| app.watch_users(&system, Some(after)).expect("Failed to watch users").missed;
+    assert_eq!(missed(ids[0]), None);
+    assert_eq!(missed(ids[1]), Some(relayed[2..].to_vec()));
+    assert_eq!(missed(ids[2]), Some(vec![]));
+    assert_eq!(missed(uuid::Uuid::new_v4()), None);
+
+    // Resuming never repeats a replayed event live.
+    let mut resumed = app.watch_users(&system, Some(ids[1])).expect("Failed to watch users");
+    app.rename(&system, relayed[2].event.user_id(), "olivia").await.expect("Failed to rename user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    let live = resumed.live.try_recv().expect("Rename not received");
+    assert!(matches!(live.event, UserEvent::Renamed { ref user, .. } if user.username == "olivia"));
+    assert!(resumed.live.try_recv().is_err());
+}
+
+async fn scenario_idempotency_keys<R: UserRepo>(app: Application<R>) {
+    let response = StoredResponse {
+        status: 201,
+        headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+        body: r#"{"id":1}"#.to_owned(),
+    };
+    let claim = |key, fingerprint| app.claim_idempotency_key(key, fingerprint);
+
+    assert_eq!(claim("k1", "a").await.unwrap(), Claim::New);
+    assert_eq!(claim("k1", "a").await.unwrap(), Claim::InFlight);
+    assert_eq!(claim("k1", "b").await.unwrap(), Claim::Mismatch);
+    assert_eq!(claim("k2", "a").await.unwrap(), Claim::New);
+
+    app.complete_idempotency_key("k1", &response).await.expect("Failed to complete key");
+    assert_eq!(claim("k1", "a").await.unwrap(), Claim::Replay(response.clone()));
+    assert_eq!(claim("k1", "b").await.unwrap(), Claim::Mismatch);
+
+    // A released key is free for any request again.
+    app.release_idempotency_key("k2").await.expect("Failed to release key");
+    assert_eq!(claim("k2", "b").await.unwrap(), Claim::New);
+    app.release_idempotency_key("unknown").await.expect("Failed to release key");
+
+    // Claims and responses both lapse.
+    let app = app.with_idempotency(IdempotencySettings { ttl_secs: 0, lock_timeout_secs: 0 });
+    assert_eq!(app.claim_idempotency_key("k3", "a").await.unwrap(), Claim::New);
+    assert_eq!(app.claim_idempotency_key("k3", "b").await.unwrap(), Claim::New);
+    app.complete_idempotency_key("k3", &response).await.expect("Failed to complete key");
+    assert_eq!(app.claim_idempotency_key("k3", "a").await.unwrap(), Claim::New);
+    assert_eq!(app.claim_idempotency_key("k1", "a").await.unwrap(), Claim::Replay(response));
+}
+
+/// Collects what it is given, failing on the events it was told to fail on, once each.
+#[derive(Clone, Default)]
+struct FlakySink {
+    fail_on: Arc<Mutex<Vec<&'static str>>>,
+    received: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl FlakySink {
+    fn kinds(&self) -> Vec<&'static str> {
+        self.received.lock().unwrap().iter().map(|recorded| recorded.event.kind()).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for FlakySink {
+    fn name(&self) -> &str {
+        "flaky"
+    }
+
+    async fn deliver(&self, event: &RecordedEvent) -> Result<(), SinkError> {
+        let mut fail_on = self.fail_on.lock().unwrap();
+        if let Some(at) = fail_on.iter().position(|kind| *kind == event.event.kind()) {
+            fail_on.remove(at);
+            return Err("sink is down".into());
+        }
+        self.received.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+async fn scenario_outbox<R: UserRepo>(app: Application<R>) {
+    let sink = FlakySink::default();
+    let app = app.with_sink(sink.clone());
+    let system = Caller::system();
+
+    let judy = app.register("judy", "judy@example.com", None).await.expect("Failed to register user");
+    app.rename(&system, judy.id, "judith").await.expect("Failed to rename user");
+    app.deactivate(&system, judy.id).await.expect("Failed to deactivate user");
+
+    // Delivery stops at the failing event; it and everything after it stay in the outbox.
+    sink.fail_on.lock().unwrap().push("renamed");
+    let err = app.relay_events(10).await.expect_err("Sink failure ignored");
+    assert!(matches!(err, RelayError::Sink { ref sink, .. } if sink == "flaky"));
+    assert_eq!(sink.kinds(), ["registered"]);
+
+    // Batches are taken in order, and delivered events are gone for good.
+    assert_eq!(app.relay_events(1).await.expect("Failed to relay events"), 1);
+    assert_eq!(app.relay_events(10).await.expect("Failed to relay events"), 1);
+    assert_eq!(app.relay_events(10).await.expect("Failed to relay events"), 0);
+    assert_eq!(sink.kinds(), ["registered", "renamed", "deactivated"]);
+
+    let received = sink.received.lock().unwrap().clone();
+    assert!(received.windows(2).all(|pair| pair[0].recorded_at <= pair[1].recorded_at && pair[0].id != pair[1].id));
+    assert!(matches!(&received[1].event, UserEvent::Renamed { user, previous_username } if user.username == "judith" && previous_username == "judy"));
+
+    // A rejected change records nothing.
+    assert!(app.register("judith", "other@example.com", None).await.is_err());
+    assert_eq!(app.relay_events(10).await.expect("Failed to relay events"), 0);
+}
+
+async fn scenario_relay<R: UserRepo>(app: Application<R>) {
+    let sink = FlakySink::default();
+    sink.fail_on.lock().unwrap().extend(["registered", "registered"]);
+    let app = app.with_sink(sink.clone());
+    let mut events = app.subscribe();
+
+    // Long enough that only the wake-up after a change, or a retry, can explain a delivery.
+    let settings = RelaySettings {
+        poll_interval_ms: 60_000,
+        retry_initial_ms: 10,
+        ..RelaySettings::default()
+    };
+
+    let checks = async {
+        // Settle into waiting for changes with an empty outbox.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let kate = app.register("kate", "kate@example.com", None).await.expect("Failed to register user");
+        let recorded = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("Event not relayed in time")
+            .expect("Subscription closed");
+        assert_eq!(recorded.event.user_id(), kate.id);
+        // Retried after the two failures, and delivered once it went through.
+        assert!(sink.fail_on.lock().unwrap().is_empty());
+        assert_eq!(sink.kinds(), ["registered"]);
+    };
+
+    tokio::select! {
+        _ = app.run_relay(&settings) => unreachable!("The relay stopped"),
+        _ = checks => {}
+    }
+}
+
+async fn scenario_shutdown<R: UserRepo>(app: Application<R>) {
+    let sink = FlakySink::default();
+    let app = app.with_sink(sink.clone());
+
+    let mut events = app.subscribe();
+    app.close_subscriptions();
+    assert!(matches!(events.recv().await, Err(RecvError::Closed)));
+    assert!(matches!(app.subscribe().recv().await, Err(RecvError::Closed)));
+
+    let settings = RelaySettings {
+        poll_interval_ms: 60_000,
+        batch_size: 2,
+        ..RelaySettings::default()
+    };
+    let webhook_settings = WebhookSettings {
+        poll_interval_ms: 60_000,
+        ..WebhookSettings::default()
+    };
+    let sender = WebhookSender::new(&webhook_settings).expect("Failed to create webhook sender");
+
+    let stop = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for username in ["lena", "leon", "lior"] {
+            app.register(username, &format!("{username}@example.com"), None).await.expect("Failed to register user");
+        }
+        app.stop();
+    };
+    let run = async {
+        tokio::join!(app.run_relay(&settings), app.run_webhooks(&sender, &webhook_settings));
+    };
+    tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(run, stop) })
+        .await
+        .expect("Relay and dispatcher kept running after stop");
+
+    // Everything recorded before the stop was relayed, over more than one batch.
+    assert_eq!(sink.kinds(), ["registered", "registered", "registered"]);
+    assert_eq!(app.relay_events(10).await.expect("Failed to relay events"), 0);
+
+    app.close().await.expect("Failed to close repository");
 }
 
 backend_tests!(scenario_add_user);
 backend_tests!(scenario_remove_user);
 backend_tests!(scenario_list_users);
+backend_tests!(scenario_update_user);
+backend_tests!(scenario_unique_fields);
+backend_tests!(scenario_query_users);
+backend_tests!(scenario_created_order);
+backend_tests!(scenario_passwords);
+backend_tests!(scenario_roles);
+backend_tests!(scenario_deactivation);
+backend_tests!(scenario_events);
+backend_tests!(scenario_outbox);
+backend_tests!(scenario_relay);
+backend_tests!(scenario_shutdown);
+backend_tests!(scenario_replay);
+backend_tests!(scenario_idempotency_keys);
@@ -1,3 +1,43 @@
+//! Setup shared by the integration tests. Each test crate uses only some of it.
+#![allow(dead_code)]
+
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use rust_webapp::config::JwtSettings;
+use rust_webapp::tokens::Tokens;
+use serde_json::Value;
+
+/// The default JWT settings with a fixed secret.
+pub fn jwt_settings() -> JwtSettings {
+    JwtSettings {
+        secret: Some("an HS256 secret used only by these tests".to_owned()),
+        ..JwtSettings::default()
+    }
+}
+
+/// Signs with a fixed secret, so tests can mint tokens the app accepts.
+pub fn tokens() -> Tokens {
+    Tokens::new(&jwt_settings()).expect("Invalid test JWT settings")
+}
+
+/// Sends `req` to `service`, returning the status, headers and JSON body (`null` if empty).
+pub async fn send<S, B>(service: &S, req: TestRequest) -> (StatusCode, HeaderMap, Value)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let res = test::call_service(service, req.to_request()).await;
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = test::read_body(res).await;
+    let json = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).expect("Body is not JSON") };
+    (status, headers, json)
+}
+
 #[macro_export] macro_rules! backend_tests {
     ($scenario:ident) => {
         mod $scenario {
@@ -5,7 +45,7 @@
 
             use rust_webapp::adapters::mongo::MongoUserRepo;
             use rust_webapp::adapters::sqlite::SqliteUserRepo;
-            use testcontainers::{runners::AsyncRunner, GenericImage};
+            use testcontainers::{runners::AsyncRunner, GenericImage, ImageExt};
             use std::sync::Once;
             use log::error;
             use log4rs;
@@ -27,8 +67,8 @@
             async fn in_memory() {
                 init_log4rs();
                 let users = MemoryUserRepo::new();
-                let mut app = Application::new(users);
-                super::$scenario(&mut app).await;
+                let app = Application::new(users);
+                super::$scenario(app).await;
             }
 
             #[tokio::test]
@@ -36,28 +76,42 @@
                 init_log4rs();
                 let pool = SqlitePool::connect("sqlite::memory:").await.expect("Failed to create SQLite in-memory database");
                 let users = SqliteUserRepo::new(pool).await.expect("Failed to create SQLiteUserRepo");
-                let mut app = Application::new(users);
-                super::$scenario(&mut app).await;
+                let app = Application::new(users);
+                super::$scenario(app).await;
             }
 
             #[tokio::test]
             async fn mongo() {
                 init_log4rs();
+                // A single-member replica set, so changes and their events go through transactions.
                 let mongo_container = GenericImage::new("mongo", "7.0")
                     .with_exposed_port(27017.into())
+                    .with_cmd(["--replSet", "rs0"])
                     .start()
                     .await
                     .expect("Failed to start MongoDB container");
 
                 let mongo_port = mongo_container.get_host_port_ipv4(27017).await.expect("Failed to get MongoDB port");
-                let mongo_url = format!("mongodb://localhost:{}", mongo_port);
-                let db = mongodb::Client::with_uri_str(&mongo_url)
+                let mongo_url = format!("mongodb://localhost:{}/?directConnection=true", mongo_port);
+                let client = mongodb::Client::with_uri_str(&mongo_url).await.expect("Failed to connect to MongoDB");
+                let admin = client.database("admin");
+                admin
+                    .run_command(mongodb::bson::doc! {
+                        "replSetInitiate": { "_id": "rs0", "members": [{ "_id": 0, "host": "localhost:27017" }] }
+                    })
                     .await
-                    .expect("Failed to connect to MongoDB")
-                    .database("test_db");
-                let users = MongoUserRepo::new(db).await;
-                let mut app = Application::new(users);
-                super::$scenario(&mut app).await;
+                    .expect("Failed to initiate the replica set");
+                loop {
+                    let hello = admin.run_command(mongodb::bson::doc! { "hello": 1 }).await.expect("Failed to ask MongoDB for its state");
+                    if hello.get_bool("isWritablePrimary") == Ok(true) {
+                        break;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+
+                let users = MongoUserRepo::new(client.database("test_db"), false).await.expect("Failed to create MongoUserRepo");
+                let app = Application::new(users);
+                super::$scenario(app).await;
             }
         }
     };
@@ -0,0 +1,65 @@
+use clap::Parser;
+use rust_webapp::config::{BackendKind, ConfigArgs, ConfigError, Settings};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+fn write_config(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rust-webapp-{}-{name}.toml", std::process::id()));
+    std::fs::write(&path, contents).expect("Failed to write config file");
+    path
+}
+
+#[test]
+fn defaults_without_file() {
+    let settings = Settings::default();
+    assert_eq!(settings.backend.kind, BackendKind::Sqlite);
+    assert_eq!(settings.backend.sqlite.path, Path::new("data.sqlite"));
+    assert_eq!((settings.server.host.as_str(), settings.server.port), ("127.0.0.1", 8080));
+    // Metrics are served apart from the API.
+    assert_eq!((settings.metrics.host.as_str(), settings.metrics.port), ("127.0.0.1", 9090));
+}
+
+#[test]
+fn file_values_are_overridden_by_flags() {
+    let path = write_config(
+        "layered",
+        r#"
+        [server]
+        port = 9000
+
+        [backend]
+        kind = "mongo"
+
+        [backend.mongo]
+        uri = "mongodb://db:27017"
+        "#,
+    );
+
+    let cli = Cli::parse_from(["test", "--config", path.to_str().unwrap(), "--backend", "memory", "--host", "0.0.0.0"]);
+    let settings = Settings::load(&cli.config).expect("Failed to load settings");
+
+    assert_eq!(settings.backend.kind, BackendKind::Memory);
+    assert_eq!(settings.backend.mongo.uri, "mongodb://db:27017");
+    assert_eq!(settings.backend.mongo.database, "rust_webapp");
+    assert!(!settings.backend.mongo.allow_standalone);
+    assert_eq!((settings.server.host.as_str(), settings.server.port), ("0.0.0.0", 9000));
+}
+
+#[test]
+fn rejects_unknown_keys() {
+    let path = write_config("unknown", "[backend]\nkind = \"sqlite\"\nflavour = \"vanilla\"\n");
+    let err = Settings::from_file(&path).expect_err("Unknown key accepted");
+    assert!(matches!(err, ConfigError::Parse { .. }));
+}
+
+#[test]
+fn missing_explicit_file_is_an_error() {
+    let cli = Cli::parse_from(["test", "--config", "/nonexistent/config.toml"]);
+    let err = Settings::load(&cli.config).expect_err("Missing file accepted");
+    assert!(matches!(err, ConfigError::Read { .. }));
+}
@@ -0,0 +1,256 @@
+mod common;
+
+use common::{jwt_settings, tokens};
+use actix_web::web::Data;
+use actix_web::{App, HttpServer};
+use futures_util::{SinkExt, StreamExt};
+use rust_webapp::api;
+use rust_webapp::app::Application;
+use rust_webapp::events::UserEvent;
+use rust_webapp::live::{Change, LiveView};
+use rust_webapp::policy::Caller;
+use rust_webapp::config::JwtSettings;
+use rust_webapp::tokens::{TokenKind, Tokens};
+use rust_webapp::users::{Role, User, UserQuery, UserRepo};
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Serves the API on a free local port, returning the URL of the WebSocket route.
+fn start_server<R: UserRepo + 'static>(app: Data<Application<R>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind server");
+    let url = format!("ws://{}/api/users/live", listener.local_addr().unwrap());
+
+    let tokens = Data::new(tokens());
+    let server = HttpServer::new(move || App::new().app_data(app.clone()).app_data(tokens.clone()).service(api::scope::<R>("/api")))
+        .workers(1)
+        .listen(listener)
+        .expect("Failed to listen")
+        .run();
+    tokio::spawn(server);
+    url
+}
+
+/// Opens the socket as user `id`, or without a token.
+async fn connect(url: &str, id: Option<Uuid>) -> Result<Socket, tungstenite::Error> {
+    connect_with(url, id.map(|id| tokens().issue(id, TokenKind::Access).token)).await
+}
+
+/// Opens the socket with access token `token`, if any.
+async fn connect_with(url: &str, token: Option<String>) -> Result<Socket, tungstenite::Error> {
+    let mut request = url.into_client_request().expect("Invalid WebSocket URL");
+    if let Some(token) = token {
+        request.headers_mut().insert("Authorization", format!("Bearer {token}").parse().unwrap());
+    }
+    tokio_tungstenite::connect_async(request).await.map(|(socket, _)| socket)
+}
+
+async fn send(socket: &mut Socket, message: Value) {
+    socket.send(Message::text(message.to_string())).await.expect("Failed to send message");
+}
+
+/// The next JSON message from the server.
+async fn receive(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("No message in time")
+            .expect("Socket closed")
+            .expect("Socket failed");
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).expect("Message is not JSON");
+        }
+    }
+}
+
+/// The code and reason the server closes the socket with, failing on any message before.
+async fn closed(socket: &mut Socket) -> (u16, String) {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("Socket not closed in time")
+        .expect("Socket dropped without closing")
+        .expect("Socket failed");
+    match message {
+        Message::Close(Some(frame)) => (frame.code.into(), frame.reason.to_string()),
+        other => panic!("Expected a close frame, got {other:?}"),
+    }
+}
+
+fn usernames(users: &Value) -> Vec<&str> {
+    let mut usernames: Vec<_> = users.as_array().unwrap().iter().map(|user| user["username"].as_str().unwrap()).collect();
+    usernames.sort();
+    usernames
+}
+
+/// The `type`, `subscription` and user's username of a change message.
+fn change(message: &Value) -> (&str, &str, &str) {
+    (
+        message["type"].as_str().unwrap_or_default(),
+        message["subscription"].as_str().unwrap_or_default(),
+        message["user"]["username"].as_str().unwrap_or_default(),
+    )
+}
+
+async fn scenario_live_users<R: UserRepo + 'static>(app: Application<R>) {
+    let system = Caller::system();
+    let root = app.register("root", "root@example.com", None).await.expect("Failed to register user");
+    app.grant_role(&system, root.id, Role::Admin).await.expect("Failed to grant admin role");
+    let ann = app.register("ann", "ann@example.com", None).await.expect("Failed to register user");
+    let bob = app.register("bob", "bob@example.org", None).await.expect("Failed to register user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    let app = Data::new(app);
+    let url = start_server(app.clone());
+
+    // The handshake itself needs a token of someone who may list users.
+    match connect(&url, None).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("Connected without a token: {:?}", other.map(|_| ())),
+    }
+    match connect(&url, Some(ann.id)).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("Connected without a role: {:?}", other.map(|_| ())),
+    }
+
+    let mut socket = connect(&url, Some(root.id)).await.expect("Failed to connect");
+    send(&mut socket, json!({ "type": "subscribe", "subscription": "example", "filter": { "email_domain": "example.com" } })).await;
+    let snapshot = receive(&mut socket).await;
+    assert_eq!((snapshot["type"].as_str(), snapshot["subscription"].as_str()), (Some("snapshot"), Some("example")));
+    assert_eq!(usernames(&snapshot["users"]), ["ann", "root"]);
+    send(&mut socket, json!({ "type": "subscribe", "subscription": "all" })).await;
+    assert_eq!(usernames(&receive(&mut socket).await["users"]), ["ann", "bob", "root"]);
+
+    send(&mut socket, json!({ "type": "subscribe", "subscription": "all" })).await;
+    let error = receive(&mut socket).await;
+    assert_eq!((error["type"].as_str(), error["subscription"].as_str()), (Some("error"), Some("all")));
+    assert_eq!(error["message"], "already subscribed under this name");
+
+    // Users enter, change within and leave each subscription's set.
+    app.change_email(&system, bob.id, "bob@example.com").await.expect("Failed to change email");
+    app.rename(&system, ann.id, "anne").await.expect("Failed to rename user");
+    app.change_email(&system, ann.id, "ann@example.org").await.expect("Failed to change email");
+    app.change_password(&Caller::from(&bob), bob.id, None, "a long and secret phrase").await.expect("Failed to set password");
+    app.delete_user(&system, bob.id).await.expect("Failed to delete user");
+    app.relay_events(100).await.expect("Failed to relay events");
+
+    let mut changes = Vec::new();
+    for _ in 0..8 {
+        changes.push(receive(&mut socket).await);
+    }
+    let changes: Vec<_> = changes.iter().map(change).collect();
+    assert_eq!(changes, [
+        ("updated", "all", "bob"),
+        ("created", "example", "bob"),
+        ("updated", "all", "anne"),
+        ("updated", "example", "anne"),
+        ("updated", "all", "anne"),
+        ("deleted", "example", "anne"),
+        ("deleted", "all", "bob"),
+        ("deleted", "example", "bob"),
+    ]);
+
+    send(&mut socket, json!({ "type": "unsubscribe", "subscription": "example" })).await;
+    assert_eq!(receive(&mut socket).await, json!({ "type": "unsubscribed", "subscription": "example" }));
+    send(&mut socket, json!({ "type": "unsubscribe", "subscription": "example" })).await;
+    assert_eq!(receive(&mut socket).await["message"], "no such subscription");
+    send(&mut socket, json!({ "type": "subscribe", "subscription": "bad", "filter": { "role": "admin" } })).await;
+    let error = receive(&mut socket).await;
+    assert!(error["message"].as_str().unwrap().starts_with("invalid message"), "{error}");
+
+    // Only the remaining subscription hears of later changes.
+    app.rename(&system, root.id, "admin").await.expect("Failed to rename user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    assert_eq!(change(&receive(&mut socket).await), ("updated", "all", "admin"));
+
+    socket.close(None).await.expect("Failed to close");
+}
+
+async fn scenario_live_users_end<R: UserRepo + 'static>(app: Application<R>) {
+    let system = Caller::system();
+    let root = app.register("root", "root@example.com", None).await.expect("Failed to register user");
+    app.grant_role(&system, root.id, Role::Admin).await.expect("Failed to grant admin role");
+    let ida = app.register("ida", "ida@example.com", None).await.expect("Failed to register user");
+    app.grant_role(&system, ida.id, Role::Auditor).await.expect("Failed to grant auditor role");
+    app.relay_events(100).await.expect("Failed to relay events");
+    let app = Data::new(app);
+    let url = start_server(app.clone());
+
+    // Losing the role closes the socket before anyone else's changes go out.
+    let mut socket = connect(&url, Some(ida.id)).await.expect("Failed to connect");
+    send(&mut socket, json!({ "type": "subscribe", "subscription": "all" })).await;
+    assert_eq!(receive(&mut socket).await["type"], "snapshot");
+    app.revoke_role(&system, ida.id, Role::Auditor).await.expect("Failed to revoke auditor role");
+    app.register("joe", "joe@example.com", None).await.expect("Failed to register user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    assert_eq!(closed(&mut socket).await, (1008, "listing users requires the admin or auditor role".to_owned()));
+
+    // So does the access token expiring.
+    let short_lived = Tokens::new(&JwtSettings { access_ttl_secs: 1, ..jwt_settings() }).expect("Invalid JWT settings");
+    let token = short_lived.issue(root.id, TokenKind::Access).token;
+    let mut socket = connect_with(&url, Some(token)).await.expect("Failed to connect");
+    assert_eq!(closed(&mut socket).await, (1008, "access token expired".to_owned()));
+}
+
+backend_tests!(scenario_live_users);
+backend_tests!(scenario_live_users_end);
+
+fn user(username: &str, email: &str) -> User {
+    User {
+        id: Uuid::new_v4(),
+        username: username.to_owned(),
+        email: email.to_owned(),
+        roles: vec![],
+        active: true,
+    }
+}
+
+fn example_com() -> LiveView {
+    LiveView::new(UserQuery {
+        email_domain: Some("example.com".to_owned()),
+        ..UserQuery::default()
+    })
+}
+
+#[test]
+fn views_follow_users_in_and_out_of_the_filter() {
+    let mut view = example_com();
+    let ann = user("ann", "ann@example.com");
+    view.reset(std::slice::from_ref(&ann));
+
+    let renamed = User { username: "anne".to_owned(), ..ann.clone() };
+    let event = UserEvent::Renamed { user: renamed.clone(), previous_username: "ann".to_owned() };
+    assert_eq!(view.apply(&event), Some(Change::Updated(renamed.clone())));
+
+    let moved = User { email: "anne@example.org".to_owned(), ..renamed };
+    let event = UserEvent::EmailChanged { user: moved.clone(), previous_email: "ann@example.com".to_owned() };
+    assert_eq!(view.apply(&event), Some(Change::Deleted(moved.clone())));
+    assert_eq!(view.apply(&UserEvent::Deactivated { user: moved.clone() }), None);
+
+    let back = User { email: "anne@EXAMPLE.com".to_owned(), ..moved };
+    let event = UserEvent::EmailChanged { user: back.clone(), previous_email: "anne@example.org".to_owned() };
+    assert_eq!(view.apply(&event), Some(Change::Created(back.clone())));
+    assert_eq!(view.apply(&UserEvent::Deleted { user: back.clone() }), Some(Change::Deleted(back.clone())));
+    assert_eq!(view.apply(&UserEvent::Deleted { user: back }), None);
+}
+
+#[test]
+fn views_ignore_password_changes_and_other_users() {
+    let mut view = example_com();
+    let bob = user("bob", "bob@example.com");
+    view.reset(std::slice::from_ref(&bob));
+
+    assert_eq!(view.apply(&UserEvent::PasswordChanged { id: bob.id }), None);
+    assert_eq!(view.apply(&UserEvent::Registered { user: user("carl", "carl@example.org") }), None);
+
+    let dana = user("dana", "dana@example.com");
+    let registered = UserEvent::Registered { user: dana.clone() };
+    assert_eq!(view.apply(&registered), Some(Change::Created(dana.clone())));
+    // Replaying an event the view already reflects does not create the user twice.
+    assert_eq!(view.apply(&registered), Some(Change::Updated(dana)));
+}
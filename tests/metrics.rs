@@ -0,0 +1,66 @@
+mod common;
+
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use rust_webapp::api;
+use rust_webapp::app::Application;
+use rust_webapp::config::JwtSettings;
+use rust_webapp::metrics::{MeteredUserRepo, Metrics};
+use rust_webapp::policy::Caller;
+use rust_webapp::tokens::{TokenKind, Tokens};
+use rust_webapp::users::{Role, UserRepo};
+use serde_json::json;
+use std::sync::Arc;
+
+async fn scenario_metrics<R: UserRepo + 'static>(app: Application<R>) {
+    let metrics = Arc::new(Metrics::new());
+    let tokens = Tokens::new(&JwtSettings::default()).expect("Invalid JWT settings");
+    // Added before metering starts, so only the requests below are counted.
+    let admin = app.register("root", "root@example.com", None).await.expect("Failed to add admin");
+    app.grant_role(&Caller::system(), admin.id, Role::Admin).await.expect("Failed to grant admin role");
+    let token = tokens.issue(admin.id, TokenKind::Access).token;
+    let app = app.map_users(|users| MeteredUserRepo::new(users, metrics.clone()));
+    let service = test::init_service(
+        App::new()
+            .app_data(Data::new(app))
+            .app_data(Data::from(metrics))
+            .app_data(Data::new(tokens))
+            .service(api::scope::<MeteredUserRepo<R>>("/api"))
+            .service(api::metrics::metrics_route("/metrics")),
+    )
+    .await;
+
+    let body = json!({ "username": "alice", "email": "alice@example.com" });
+    for expected in [StatusCode::CREATED, StatusCode::CONFLICT] {
+        let res = test::call_service(&service, TestRequest::post().uri("/api/users").set_json(&body).to_request()).await;
+        assert_eq!(res.status(), expected);
+    }
+    let missing = "550e8400-e29b-41d4-a716-446655440000";
+    let req = TestRequest::get().uri(&format!("/api/users/{missing}")).insert_header((AUTHORIZATION, format!("Bearer {token}")));
+    let res = test::call_service(&service, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = test::call_service(&service, TestRequest::get().uri("/metrics").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("content-type").unwrap().to_str().unwrap().starts_with("text/plain"));
+    let text = String::from_utf8(test::read_body(res).await.to_vec()).expect("Metrics are not UTF-8");
+
+    for line in [
+        r#"http_requests_total{method="POST",route="create_user",status="201"} 1"#,
+        r#"http_requests_total{method="POST",route="create_user",status="409"} 1"#,
+        r#"http_requests_total{method="GET",route="get_user",status="404"} 1"#,
+        r#"http_request_duration_seconds_count{method="GET",route="get_user",status="404"} 1"#,
+        r#"user_repo_operation_duration_seconds_count{operation="add_user"} 2"#,
+        // One lookup for the caller's roles, one for the user asked for.
+        r#"user_repo_operation_duration_seconds_count{operation="get_user"} 2"#,
+        r#"user_repo_errors_total{error="conflict",operation="add_user"} 1"#,
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing `{line}` in:\n{text}");
+    }
+    assert!(!text.contains(r#"error="conflict",operation="get_user""#));
+}
+
+backend_tests!(scenario_metrics);
@@ -0,0 +1,113 @@
+use rust_webapp::adapters::sqlite::{self, SchemaError, SqliteUserRepo};
+use rust_webapp::users::{ConflictField, UserRepo, UserRepoError};
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+/// A single connection keeps every query on the same in-memory database.
+async fn memory_pool() -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to create SQLite in-memory database")
+}
+
+/// The schema `SqliteUserRepo::new` used to create inline before migrations existed.
+async fn create_legacy_schema(pool: &SqlitePool, with_unique_indexes: bool) {
+    sqlx::query("CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY, username TEXT NOT NULL, email TEXT NOT NULL)")
+        .execute(pool)
+        .await
+        .expect("Failed to create legacy table");
+
+    if with_unique_indexes {
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS users_username_key ON users (username)")
+            .execute(pool)
+            .await
+            .expect("Failed to create legacy index");
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS users_email_key ON users (email)")
+            .execute(pool)
+            .await
+            .expect("Failed to create legacy index");
+    }
+}
+
+async fn upgrade_legacy_database(with_unique_indexes: bool) {
+    let pool = memory_pool().await;
+    create_legacy_schema(&pool, with_unique_indexes).await;
+
+    let id = Uuid::new_v4();
+    sqlx::query("INSERT INTO users (id, username, email) VALUES (?, 'legacy', 'legacy@example.com')")
+        .bind(id.to_string())
+        .execute(&pool)
+        .await
+        .expect("Failed to insert legacy user");
+    assert_eq!(sqlite::schema_version(&pool).await.expect("Failed to read version"), None);
+
+    let repo = SqliteUserRepo::new(pool.clone()).await.expect("Failed to migrate legacy database");
+
+    assert_eq!(sqlite::schema_version(&pool).await.expect("Failed to read version"), Some(sqlite::latest_schema_version()));
+    let user = repo.get_user(id).await.expect("Failed to get user").expect("Legacy user lost");
+    assert_eq!(user.username, "legacy");
+
+    let err = repo.add_user("legacy", "other@example.com", None, &|_, _| Vec::new()).await.expect_err("Unique index missing after upgrade");
+    assert!(matches!(err, UserRepoError::Conflict { field: ConflictField::Username, .. }));
+}
+
+#[tokio::test]
+async fn upgrades_database_from_original_inline_schema() {
+    upgrade_legacy_database(false).await;
+}
+
+#[tokio::test]
+async fn upgrades_database_from_inline_schema_with_unique_indexes() {
+    upgrade_legacy_database(true).await;
+}
+
+#[tokio::test]
+async fn migrate_is_idempotent() {
+    let pool = memory_pool().await;
+    let latest = sqlite::latest_schema_version();
+
+    let first = sqlite::migrate(&pool).await.expect("Failed to migrate");
+    assert_eq!((first.from, first.to), (None, latest));
+
+    let second = sqlite::migrate(&pool).await.expect("Failed to migrate again");
+    assert_eq!((second.from, second.to), (Some(latest), latest));
+}
+
+#[tokio::test]
+async fn refuses_newer_schema() {
+    let pool = memory_pool().await;
+    sqlite::migrate(&pool).await.expect("Failed to migrate");
+
+    let future = sqlite::latest_schema_version() + 1;
+    sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (?, 'from the future', 1, x'00', 0)")
+        .bind(future)
+        .execute(&pool)
+        .await
+        .expect("Failed to record future migration");
+
+    let err = SqliteUserRepo::new(pool).await.err().expect("Newer schema accepted");
+    let source = std::error::Error::source(&err).and_then(|e| e.downcast_ref::<SchemaError>());
+    assert!(matches!(source, Some(SchemaError::TooNew { found, .. }) if *found == future));
+}
+
+#[tokio::test]
+async fn close_leaves_a_complete_database_file() {
+    let path = std::env::temp_dir().join(format!("rust-webapp-{}-close.sqlite", std::process::id()));
+    let wal = path.with_extension("sqlite-wal");
+    let _ = std::fs::remove_file(&path);
+
+    let repo = SqliteUserRepo::open(&path).await.expect("Failed to open database");
+    let added = repo.add_user("wally", "wally@example.com", None, &|_, _| Vec::new()).await.expect("Failed to add user");
+    assert!(wal.exists(), "Database not in WAL mode");
+    repo.close().await.expect("Failed to close repository");
+
+    assert!(matches!(repo.health_check().await, Err(UserRepoError::Unavailable)));
+    assert!(std::fs::metadata(&wal).map_or(true, |wal| wal.len() == 0), "WAL not checkpointed");
+    let reopened = SqliteUserRepo::open(&path).await.expect("Failed to reopen database");
+    assert_eq!(reopened.get_user(added.id).await.expect("Failed to get user").map(|user| user.username), Some("wally".to_owned()));
+    reopened.close().await.expect("Failed to close repository");
+    let _ = std::fs::remove_file(&path);
+}
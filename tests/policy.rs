@@ -0,0 +1,66 @@
+use rust_webapp::policy::{authorize, Action, Caller};
+use rust_webapp::users::Role;
+use uuid::Uuid;
+
+fn caller(roles: &[Role]) -> Caller {
+    Caller {
+        id: Uuid::new_v4(),
+        roles: roles.to_vec(),
+    }
+}
+
+#[test]
+fn users_act_only_on_themselves() {
+    let user = caller(&[]);
+    let other = Uuid::new_v4();
+
+    for action in [Action::ReadUser(user.id), Action::UpdateUser(user.id), Action::DeleteUser(user.id), Action::ChangePassword(user.id)] {
+        assert_eq!(authorize(&user, action), Ok(()), "{action:?}");
+    }
+    for action in [
+        Action::ListUsers,
+        Action::ReadUser(other),
+        Action::UpdateUser(other),
+        Action::DeleteUser(other),
+        Action::ChangePassword(other),
+        Action::GrantRole(user.id, Role::Admin),
+        Action::ManageWebhooks,
+    ] {
+        assert!(authorize(&user, action).is_err(), "{action:?}");
+    }
+}
+
+#[test]
+fn auditors_read_everyone() {
+    let auditor = caller(&[Role::Auditor]);
+    let other = Uuid::new_v4();
+
+    assert_eq!(authorize(&auditor, Action::ListUsers), Ok(()));
+    assert_eq!(authorize(&auditor, Action::ReadUser(other)), Ok(()));
+    assert!(authorize(&auditor, Action::UpdateUser(other)).is_err());
+    assert!(authorize(&auditor, Action::DeleteUser(other)).is_err());
+    assert!(authorize(&auditor, Action::RevokeRole(other, Role::Auditor)).is_err());
+    assert!(authorize(&auditor, Action::ManageWebhooks).is_err());
+}
+
+#[test]
+fn admins_do_everything_but_set_passwords_or_demote_themselves() {
+    let admin = caller(&[Role::Admin]);
+    let other = Uuid::new_v4();
+
+    for action in [
+        Action::ListUsers,
+        Action::ReadUser(other),
+        Action::UpdateUser(other),
+        Action::DeleteUser(other),
+        Action::GrantRole(other, Role::Admin),
+        Action::RevokeRole(other, Role::Admin),
+        Action::RevokeRole(admin.id, Role::Auditor),
+        Action::ManageWebhooks,
+    ] {
+        assert_eq!(authorize(&admin, action), Ok(()), "{action:?}");
+    }
+    assert!(authorize(&admin, Action::ChangePassword(other)).is_err());
+    let denied = authorize(&admin, Action::RevokeRole(admin.id, Role::Admin)).unwrap_err();
+    assert_eq!(denied.to_string(), "admins cannot revoke their own admin role");
+}
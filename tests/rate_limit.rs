@@ -0,0 +1,195 @@
+mod common;
+
+use common::{send, tokens};
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use rust_webapp::adapters::memory::MemoryUserRepo;
+use rust_webapp::api;
+use rust_webapp::app::Application;
+use rust_webapp::config::{RateLimitQuota, RateLimitSettings};
+use rust_webapp::rate_limit::{Bucket, Decision, MemoryRateLimitStore, RateLimitStore, RateLimiter, StoreError};
+use rust_webapp::tokens::TokenKind;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const QUOTA: RateLimitQuota = RateLimitQuota { burst: 2, per_minute: 60 };
+
+#[test]
+fn buckets_allow_bursts_then_refill_over_time() {
+    let mut bucket = Bucket::full(QUOTA, 0);
+
+    let first = bucket.take(QUOTA, 0);
+    assert!(first.allowed);
+    assert_eq!((first.limit, first.remaining, first.reset_after), (2, 1, Duration::from_secs(1)));
+    let second = bucket.take(QUOTA, 0);
+    assert!(second.allowed);
+    assert_eq!((second.remaining, second.reset_after, second.retry_after), (0, Duration::from_secs(2), Duration::ZERO));
+
+    let denied = bucket.take(QUOTA, 0);
+    assert!(!denied.allowed);
+    assert_eq!(denied.retry_after, Duration::from_secs(1));
+    assert_eq!(bucket.take(QUOTA, 500).retry_after, Duration::from_millis(500));
+
+    assert!(bucket.take(QUOTA, 1000).allowed);
+    // Idle time never fills the bucket beyond the burst.
+    let rested = bucket.take(QUOTA, 60_000);
+    assert_eq!((rested.allowed, rested.remaining), (true, 1));
+}
+
+#[test]
+fn clocks_going_backwards_do_not_refill() {
+    let mut bucket = Bucket::full(QUOTA, 10_000);
+    bucket.take(QUOTA, 10_000);
+    bucket.take(QUOTA, 10_000);
+    assert!(!bucket.take(QUOTA, 5_000).allowed);
+    assert!(!bucket.take(QUOTA, 10_500).allowed);
+}
+
+#[tokio::test]
+async fn memory_store_keeps_a_bucket_per_key() {
+    let store = MemoryRateLimitStore::new();
+    for _ in 0..2 {
+        assert!(store.acquire("create_user ip:10.0.0.1", QUOTA, 0).await.unwrap().allowed);
+    }
+    assert!(!store.acquire("create_user ip:10.0.0.1", QUOTA, 0).await.unwrap().allowed);
+    assert!(store.acquire("create_user ip:10.0.0.2", QUOTA, 0).await.unwrap().allowed);
+    assert!(store.acquire("get_users ip:10.0.0.1", QUOTA, 0).await.unwrap().allowed);
+}
+
+#[tokio::test]
+async fn memory_store_forgets_the_oldest_buckets_when_full() {
+    // Refills so slowly that no bucket is full again within the test.
+    const SLOW: RateLimitQuota = RateLimitQuota { burst: 1, per_minute: 1 };
+    let store = MemoryRateLimitStore::new();
+    for i in 0..10_000 {
+        assert!(store.acquire(&format!("create_user ip:{i}"), SLOW, i).await.unwrap().allowed);
+    }
+
+    // A new client pushes out the least recently used half, whose clients start over.
+    assert!(store.acquire("create_user ip:new", SLOW, 10_000).await.unwrap().allowed);
+    assert!(store.acquire("create_user ip:0", SLOW, 10_000).await.unwrap().allowed);
+    assert!(store.acquire("create_user ip:4999", SLOW, 10_000).await.unwrap().allowed);
+    assert!(!store.acquire("create_user ip:5000", SLOW, 10_000).await.unwrap().allowed);
+    assert!(!store.acquire("create_user ip:9999", SLOW, 10_000).await.unwrap().allowed);
+}
+
+fn settings(trust_forwarded: bool) -> RateLimitSettings {
+    RateLimitSettings {
+        enabled: true,
+        trust_forwarded,
+        default: RateLimitQuota { burst: 1, per_minute: 60 },
+        routes: HashMap::from([("create_user".to_owned(), RateLimitQuota { burst: 2, per_minute: 1 })]),
+    }
+}
+
+async fn init_app(
+    app: Data<Application<MemoryUserRepo>>,
+    limiter: RateLimiter,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .app_data(app)
+            .app_data(Data::new(tokens()))
+            .app_data(Data::new(limiter))
+            .service(api::scope::<MemoryUserRepo>("/api")),
+    )
+    .await
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+    headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default()
+}
+
+fn create(username: &str, from: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/api/users")
+        .peer_addr(format!("{from}:40000").parse().unwrap())
+        .set_json(json!({ "username": username, "email": format!("{username}@example.com") }))
+}
+
+#[tokio::test]
+async fn clients_are_limited_per_route_by_address() {
+    let app = Data::new(Application::new(MemoryUserRepo::new()));
+    let service = init_app(app, RateLimiter::new(settings(false))).await;
+
+    let (status, headers, _) = send(&service, create("amy", "10.0.0.1")).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!((header(&headers, "RateLimit-Limit"), header(&headers, "RateLimit-Remaining")), ("2", "1"));
+    let (status, headers, _) = send(&service, create("ann", "10.0.0.1")).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!((header(&headers, "RateLimit-Remaining"), header(&headers, "RateLimit-Reset")), ("0", "120"));
+
+    let (status, headers, problem) = send(&service, create("ava", "10.0.0.1")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(problem["type"], "/problems/too-many-requests");
+    assert_eq!(problem["instance"], "/api/users");
+    assert_eq!(header(&headers, RETRY_AFTER.as_str()), "60");
+    assert_eq!(header(&headers, "RateLimit-Remaining"), "0");
+
+    // Other clients have their own buckets, and forwarding headers are not trusted.
+    let spoofed = create("bea", "10.0.0.1").insert_header(("X-Forwarded-For", "192.0.2.7"));
+    assert_eq!(send(&service, spoofed).await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send(&service, create("bea", "10.0.0.2")).await.0, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_used_when_trusted() {
+    let app = Data::new(Application::new(MemoryUserRepo::new()));
+    let service = init_app(app, RateLimiter::new(settings(true))).await;
+
+    for username in ["cat", "cid"] {
+        assert_eq!(send(&service, create(username, "10.0.0.1")).await.0, StatusCode::CREATED);
+    }
+    let forwarded = create("cyd", "10.0.0.1").insert_header(("X-Forwarded-For", "192.0.2.7"));
+    assert_eq!(send(&service, forwarded).await.0, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn authenticated_clients_are_limited_by_user() {
+    let app = Data::new(Application::new(MemoryUserRepo::new()));
+    let dee = app.register("dee", "dee@example.com", None).await.expect("Failed to register user");
+    let service = init_app(app, RateLimiter::new(settings(false))).await;
+
+    let token = tokens().issue(dee.id, TokenKind::Access).token;
+    let get = |from: &str| {
+        TestRequest::get()
+            .uri(&format!("/api/users/{}", dee.id))
+            .peer_addr(format!("{from}:40000").parse().unwrap())
+            .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+    };
+
+    assert_eq!(send(&service, get("10.0.0.1")).await.0, StatusCode::OK);
+    assert_eq!(send(&service, get("10.0.0.2")).await.0, StatusCode::TOO_MANY_REQUESTS);
+    // Anonymous requests from the same address are counted separately.
+    let anonymous = TestRequest::get().uri(&format!("/api/users/{}", dee.id)).peer_addr("10.0.0.1:40000".parse().unwrap());
+    assert_eq!(send(&service, anonymous).await.0, StatusCode::UNAUTHORIZED);
+}
+
+/// A store that is always down.
+struct Unreachable;
+
+#[async_trait::async_trait]
+impl RateLimitStore for Unreachable {
+    async fn acquire(&self, _key: &str, _quota: RateLimitQuota, _now: u64) -> Result<Decision, StoreError> {
+        Err("connection refused".into())
+    }
+}
+
+#[tokio::test]
+async fn requests_go_through_while_the_store_is_down() {
+    let app = Data::new(Application::new(MemoryUserRepo::new()));
+    let service = init_app(app, RateLimiter::new(settings(false)).with_store(Unreachable)).await;
+
+    for username in ["eve", "eli", "ema"] {
+        let (status, headers, _) = send(&service, create(username, "10.0.0.1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(!headers.contains_key("RateLimit-Limit"));
+    }
+}
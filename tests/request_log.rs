@@ -0,0 +1,161 @@
+mod common;
+
+use common::tokens;
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::USER_AGENT;
+use actix_web::http::StatusCode;
+use actix_web::middleware::from_fn;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rust_webapp::adapters::memory::MemoryUserRepo;
+use rust_webapp::api;
+use rust_webapp::api::request_log::{request_log, MDC_KEY};
+use rust_webapp::app::Application;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Mutex, Once};
+
+/// A log record as the tests see it.
+#[derive(Debug, Clone)]
+struct Captured {
+    target: String,
+    message: String,
+    request_id: Option<String>,
+    attributes: HashMap<&'static str, String>,
+}
+
+/// Keeps every record, since log4rs cannot be asked what it wrote.
+struct CapturingLogger {
+    records: Mutex<Vec<Captured>>,
+}
+
+static LOGGER: CapturingLogger = CapturingLogger { records: Mutex::new(Vec::new()) };
+static INIT: Once = Once::new();
+
+const ACCESS_KEYS: [&str; 5] = ["method", "path", "status", "latency_ms", "user_agent"];
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        let attributes = ACCESS_KEYS
+            .into_iter()
+            .filter_map(|key| Some((key, record.key_values().get(key.into())?.to_string())))
+            .collect();
+        self.records.lock().unwrap().push(Captured {
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+            request_id: log_mdc::get(MDC_KEY, |id| id.map(str::to_owned)),
+            attributes,
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Records logged on behalf of request `id`.
+fn logged_for(id: &str) -> Vec<Captured> {
+    LOGGER.records.lock().unwrap().iter().filter(|record| record.request_id.as_deref() == Some(id)).cloned().collect()
+}
+
+fn logged_with(message: &str) -> Vec<Captured> {
+    LOGGER.records.lock().unwrap().iter().filter(|record| record.message == message).cloned().collect()
+}
+
+async fn init_app() -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).expect("Another logger is installed");
+        log::set_max_level(LevelFilter::Debug);
+    });
+
+    test::init_service(
+        App::new()
+            .wrap(from_fn(request_log))
+            .app_data(Data::new(Application::new(MemoryUserRepo::new())))
+            .app_data(Data::new(tokens()))
+            .service(api::scope::<MemoryUserRepo>("/api")),
+    )
+    .await
+}
+
+fn create(username: &str) -> TestRequest {
+    TestRequest::post().uri("/api/users").set_json(json!({ "username": username, "email": format!("{username}@example.com") }))
+}
+
+fn request_id<B>(res: &ServiceResponse<B>) -> String {
+    res.headers().get("X-Request-Id").expect("No request id").to_str().unwrap().to_owned()
+}
+
+#[actix_web::test]
+async fn request_ids_are_echoed_or_generated() {
+    let service = init_app().await;
+
+    let res = test::call_service(&service, create("ida").insert_header(("X-Request-Id", "client-id-1")).to_request()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(request_id(&res), "client-id-1");
+
+    let res = test::call_service(&service, create("ivo").to_request()).await;
+    let generated = request_id(&res);
+    assert!(generated.parse::<uuid::Uuid>().is_ok(), "{generated}");
+
+    for invalid in ["has spaces", &"x".repeat(201)] {
+        let res = test::call_service(&service, TestRequest::get().uri("/api/nowhere").insert_header(("X-Request-Id", invalid)).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(request_id(&res).parse::<uuid::Uuid>().is_ok());
+    }
+}
+
+#[actix_web::test]
+async fn everything_logged_for_a_request_carries_its_id() {
+    let service = init_app().await;
+
+    let req = create("jan").insert_header(("X-Request-Id", "trace-jan")).insert_header((USER_AGENT, "tests/1.0"));
+    assert_eq!(test::call_service(&service, req.to_request()).await.status(), StatusCode::CREATED);
+
+    let logged = logged_for("trace-jan");
+    let targets: Vec<_> = logged.iter().map(|record| record.target.as_str()).collect();
+    assert!(targets.contains(&"rust_webapp::api::users"), "{targets:?}");
+    // The repository adapter logs from inside the handler, and gets the id too.
+    assert!(targets.contains(&"Users"), "{targets:?}");
+    assert_eq!(targets.last(), Some(&"Access"));
+
+    let access = &logged.last().unwrap().attributes;
+    assert_eq!(access["method"], "POST");
+    assert_eq!(access["path"], "/api/users");
+    assert_eq!(access["status"], "201");
+    assert_eq!(access["user_agent"], "tests/1.0");
+    assert!(access["latency_ms"].parse::<f64>().is_ok());
+    assert_eq!(logged.iter().filter(|record| record.target == "Access").count(), 1);
+}
+
+#[actix_web::test]
+async fn interleaved_requests_keep_their_own_ids() {
+    let service = init_app().await;
+
+    // Password hashing makes the first request wait while the second one runs.
+    let with_password = |username: &str, id: &str| {
+        TestRequest::post()
+            .uri("/api/users")
+            .insert_header(("X-Request-Id", id))
+            .set_json(json!({ "username": username, "email": format!("{username}@example.com"), "password": "correct horse battery staple" }))
+            .to_request()
+    };
+    let (kim, kit) = futures_util::join!(
+        test::call_service(&service, with_password("kimberly", "trace-kim")),
+        test::call_service(&service, with_password("kittredge", "trace-kit")),
+    );
+    assert_eq!((kim.status(), kit.status()), (StatusCode::CREATED, StatusCode::CREATED));
+
+    for (username, id) in [("kimberly", "trace-kim"), ("kittredge", "trace-kit")] {
+        let logged = logged_with(&format!("Adding user: {username}"));
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].request_id.as_deref(), Some(id));
+    }
+    assert!(log_mdc::get(MDC_KEY, |id| id.is_none()));
+}
@@ -0,0 +1,87 @@
+use rust_webapp::config::{JwtAlgorithm, JwtSettings};
+use rust_webapp::tokens::{Claims, KeyError, TokenError, TokenKind, Tokens};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const SECRET: &str = "an HS256 secret used only by these tests";
+
+fn hs256() -> JwtSettings {
+    JwtSettings {
+        secret: Some(SECRET.to_owned()),
+        ..JwtSettings::default()
+    }
+}
+
+#[test]
+fn round_trips_both_kinds() {
+    let tokens = Tokens::new(&hs256()).expect("Valid settings rejected");
+    let id = Uuid::new_v4();
+
+    for kind in [TokenKind::Access, TokenKind::Refresh] {
+        let issued = tokens.issue(id, kind);
+        let claims = tokens.validate(&issued.token, kind).expect("Fresh token rejected");
+        assert_eq!(claims.sub, id);
+        assert_eq!(claims.kind, kind);
+        assert_eq!(claims.exp - claims.iat, issued.expires_in);
+    }
+
+    let refresh = tokens.issue(id, TokenKind::Refresh).token;
+    assert!(matches!(
+        tokens.validate(&refresh, TokenKind::Access),
+        Err(TokenError::WrongKind { expected: TokenKind::Access })
+    ));
+}
+
+#[test]
+fn rejects_expired_and_foreign_tokens() {
+    let tokens = Tokens::new(&hs256()).expect("Valid settings rejected");
+    let sign = |claims: &Claims, secret: &str| {
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), claims, &key).unwrap()
+    };
+    let claims = Claims {
+        sub: Uuid::new_v4(),
+        iss: JwtSettings::default().issuer,
+        iat: 1_000_000_000,
+        exp: 1_000_000_900,
+        kind: TokenKind::Access,
+    };
+    assert!(matches!(tokens.validate(&sign(&claims, SECRET), TokenKind::Access), Err(TokenError::Expired)));
+
+    let current = tokens.validate(&tokens.issue(claims.sub, TokenKind::Access).token, TokenKind::Access).unwrap();
+    let other_secret = "another secret that is long enough";
+    assert!(matches!(tokens.validate(&sign(&current, other_secret), TokenKind::Access), Err(TokenError::Invalid)));
+    let other_issuer = Claims { iss: "someone-else".into(), ..current };
+    assert!(matches!(tokens.validate(&sign(&other_issuer, SECRET), TokenKind::Access), Err(TokenError::Invalid)));
+}
+
+#[test]
+fn rejects_weak_or_missing_keys() {
+    let short = JwtSettings {
+        secret: Some("too short".into()),
+        ..JwtSettings::default()
+    };
+    assert!(matches!(Tokens::new(&short), Err(KeyError::SecretTooShort)));
+
+    let eddsa = JwtSettings {
+        algorithm: JwtAlgorithm::EdDSA,
+        ..JwtSettings::default()
+    };
+    assert!(matches!(Tokens::new(&eddsa), Err(KeyError::MissingKeyFile)));
+}
+
+#[test]
+fn signs_with_ed25519_keys() {
+    let fixture = |name: &str| Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name));
+    let settings = JwtSettings {
+        algorithm: JwtAlgorithm::EdDSA,
+        private_key_file: fixture("ed25519_private.pem"),
+        public_key_file: fixture("ed25519_public.pem"),
+        ..JwtSettings::default()
+    };
+    let tokens = Tokens::new(&settings).expect("Valid key files rejected");
+
+    let id = Uuid::new_v4();
+    let claims = tokens.validate(&tokens.issue(id, TokenKind::Access).token, TokenKind::Access).unwrap();
+    assert_eq!(claims.sub, id);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use common::tokens;
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::middleware::from_fn;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use opentelemetry::trace::{Span, SpanId, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+use rust_webapp::adapters::sqlite::SqliteUserRepo;
+use rust_webapp::api;
+use rust_webapp::app::Application;
+use rust_webapp::config::{BackendKind, TraceExporterKind, TracingSettings};
+use rust_webapp::telemetry::{TracedUserRepo, Tracing};
+use rust_webapp::tokens::TokenKind;
+use serde_json::json;
+use sqlx::SqlitePool;
+
+type Repo = TracedUserRepo<SqliteUserRepo>;
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+async fn traced_app(tracing: &Tracing) -> (Data<Application<Repo>>, SqlitePool) {
+    let pool = SqlitePool::connect("sqlite::memory:").await.expect("Failed to create SQLite in-memory database");
+    let users = SqliteUserRepo::new(pool.clone()).await.expect("Failed to create SqliteUserRepo");
+    let app = Application::new(TracedUserRepo::new(users, tracing, BackendKind::Sqlite));
+    (Data::new(app), pool)
+}
+
+async fn init_service(
+    app: Data<Application<Repo>>,
+    tracing: Data<Tracing>,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .wrap(from_fn(api::tracing::trace_request))
+            .app_data(app)
+            .app_data(tracing)
+            .app_data(Data::new(tokens()))
+            .service(api::scope::<Repo>("/api")),
+    )
+    .await
+}
+
+fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+    span.attributes.iter().find(|attribute| attribute.key.as_str() == key).map(|attribute| &attribute.value)
+}
+
+fn server_span(spans: &[SpanData]) -> &SpanData {
+    let mut servers = spans.iter().filter(|span| span.span_kind == SpanKind::Server);
+    let server = servers.next().expect("No server span");
+    assert!(servers.next().is_none(), "More than one server span");
+    server
+}
+
+#[actix_web::test]
+async fn requests_continue_the_callers_trace() {
+    let exporter = InMemorySpanExporter::default();
+    let tracing = Data::new(Tracing::with_exporter(exporter.clone()));
+    let (app, _pool) = traced_app(&tracing).await;
+    let service = init_service(app, tracing).await;
+
+    let req = TestRequest::post()
+        .uri("/api/users")
+        .insert_header(("traceparent", TRACEPARENT))
+        .set_json(json!({ "username": "tracy", "email": "tracy@example.com" }));
+    assert_eq!(test::call_service(&service, req.to_request()).await.status(), StatusCode::CREATED);
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let server = server_span(&spans);
+    assert_eq!(server.name, "POST /api/users");
+    assert_eq!(server.span_context.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(server.parent_span_id.to_string(), "00f067aa0ba902b7");
+    assert!(server.parent_span_is_remote);
+    assert_eq!(attribute(server, "http.route"), Some(&Value::from("/api/users")));
+    assert_eq!(attribute(server, "http.response.status_code"), Some(&Value::I64(201)));
+    assert_eq!(server.status, Status::Unset);
+
+    let queries: Vec<_> = spans.iter().filter(|span| span.span_kind == SpanKind::Client).collect();
+    assert!(queries.iter().any(|span| span.name == "add_user"), "{queries:?}");
+    for query in queries {
+        assert_eq!(query.span_context.trace_id(), server.span_context.trace_id());
+        assert_eq!(query.parent_span_id, server.span_context.span_id());
+        assert_eq!(attribute(query, "db.system"), Some(&Value::from("sqlite")));
+        assert_eq!(attribute(query, "db.operation"), Some(&Value::from(query.name.to_string())));
+    }
+}
+
+#[actix_web::test]
+async fn requests_without_traceparent_start_a_trace() {
+    let exporter = InMemorySpanExporter::default();
+    let tracing = Data::new(Tracing::with_exporter(exporter.clone()));
+    let (app, _pool) = traced_app(&tracing).await;
+    let service = init_service(app, tracing).await;
+
+    for traceparent in [None, Some("not a traceparent")] {
+        exporter.reset();
+        let mut req = TestRequest::get().uri(&format!("/api/users/{}", uuid::Uuid::new_v4()));
+        if let Some(traceparent) = traceparent {
+            req = req.insert_header(("traceparent", traceparent));
+        }
+        assert_eq!(test::call_service(&service, req.to_request()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let server = server_span(&spans);
+        assert_eq!(server.name, "GET /api/users/{id}");
+        assert_eq!(server.parent_span_id, SpanId::INVALID);
+        // Client errors do not fail the span.
+        assert_eq!(server.status, Status::Unset);
+    }
+}
+
+#[actix_web::test]
+async fn failed_repository_calls_fail_their_spans() {
+    let exporter = InMemorySpanExporter::default();
+    let tracing = Data::new(Tracing::with_exporter(exporter.clone()));
+    let (app, pool) = traced_app(&tracing).await;
+    let user = app.register("ursula", "ursula@example.com", None).await.expect("Failed to register user");
+    let service = init_service(app, tracing).await;
+    pool.close().await;
+
+    let token = tokens().issue(user.id, TokenKind::Access).token;
+    let req = TestRequest::get().uri("/api/users").insert_header((AUTHORIZATION, format!("Bearer {token}")));
+    assert_eq!(test::call_service(&service, req.to_request()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let spans = exporter.get_finished_spans().unwrap();
+    assert!(matches!(server_span(&spans).status, Status::Error { .. }));
+    let query = spans.iter().find(|span| span.span_kind == SpanKind::Client).expect("No repository span");
+    assert!(matches!(query.status, Status::Error { .. }));
+}
+
+#[actix_web::test]
+async fn repository_calls_outside_a_trace_are_not_recorded() {
+    let exporter = InMemorySpanExporter::default();
+    let tracing = Tracing::with_exporter(exporter.clone());
+    let (app, _pool) = traced_app(&tracing).await;
+
+    app.register("otto", "otto@example.com", None).await.expect("Failed to register user");
+    assert!(exporter.get_finished_spans().unwrap().is_empty());
+}
+
+#[test]
+fn file_exporter_writes_a_json_object_per_span() {
+    let path = std::env::temp_dir().join(format!("rust-webapp-{}-traces.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let settings = TracingSettings {
+        exporter: TraceExporterKind::File,
+        service_name: "tracing-tests".to_owned(),
+        file: path.clone(),
+        ..TracingSettings::default()
+    };
+    let tracing = Tracing::new(&settings).unwrap().expect("No tracing for the file exporter");
+
+    let tracer = tracing.tracer();
+    let parent = tracer.start("parent");
+    let cx = Context::current_with_span(parent);
+    tracer.build_with_context(tracer.span_builder("child").with_attributes([KeyValue::new("db.system", "sqlite")]), &cx).end();
+    cx.span().end();
+    tracing.shutdown().unwrap();
+
+    let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Line is not JSON"))
+        .collect();
+    let [child, parent] = lines.as_slice() else { panic!("Expected two spans: {lines:?}") };
+    assert_eq!(child["name"], "child");
+    assert_eq!(child["service_name"], "tracing-tests");
+    assert_eq!(child["attributes"]["db.system"], "sqlite");
+    assert_eq!(child["trace_id"], parent["trace_id"]);
+    assert_eq!(child["parent_span_id"], parent["span_id"]);
+    assert_eq!(parent["parent_span_id"], serde_json::Value::Null);
+    assert_eq!(parent["status"]["code"], "unset");
+    assert!(child["end_time_unix_nano"].as_u64() >= child["start_time_unix_nano"].as_u64());
+
+    assert!(Tracing::new(&TracingSettings::default()).unwrap().is_none());
+}
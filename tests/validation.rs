@@ -0,0 +1,92 @@
+use rust_webapp::validation::{
+    normalize_login, validate_new_user, validate_password, validate_user_fields, validate_webhook, PasswordPolicy, UserFields,
+};
+
+const POLICY: PasswordPolicy = PasswordPolicy { min_length: 12, max_length: 128 };
+
+#[test]
+fn normalizes_valid_fields() {
+    let fields = validate_user_fields("  john.doe-1 ", " John.Doe@Example.COM\n").expect("Valid fields rejected");
+    assert_eq!(fields.username, "john.doe-1");
+    assert_eq!(fields.email, "john.doe@example.com");
+}
+
+#[test]
+fn reports_every_failing_field() {
+    let errors = validate_user_fields("   ", "not-an-email").expect_err("Invalid fields accepted");
+    let failures: Vec<_> = errors.errors().iter().map(|e| (e.field, e.code)).collect();
+    assert_eq!(failures, [("username", "required"), ("email", "invalid_format")]);
+}
+
+#[test]
+fn rejects_bad_usernames() {
+    let codes = |username: &str| {
+        validate_user_fields(username, "a@example.com")
+            .map(|_| Vec::new())
+            .unwrap_or_else(|e| e.errors().iter().map(|e| e.code).collect())
+    };
+
+    assert_eq!(codes("ab"), ["too_short"]);
+    assert_eq!(codes(&"a".repeat(33)), ["too_long"]);
+    assert_eq!(codes("john doe"), ["invalid_chars"]);
+    assert!(codes("abc").is_empty());
+}
+
+#[test]
+fn rejects_bad_emails() {
+    for email in ["@example.com", "john@", "john@localhost", "john..doe@example.com", "john@-example.com", "a@b@example.com", "john doe@example.com"] {
+        let errors = validate_user_fields("johndoe", email).expect_err(email);
+        assert_eq!(errors.errors()[0].code, "invalid_format", "{email}");
+    }
+}
+
+#[test]
+fn enforces_password_policy() {
+    let fields = UserFields { username: "johndoe".into(), email: "john.smith@example.com".into() };
+    let codes = |password: &str| {
+        validate_password("new_password", password, &POLICY, &fields)
+            .map(|_| Vec::new())
+            .unwrap_or_else(|e| e.errors().iter().map(|e| e.code).collect())
+    };
+
+    assert!(codes("correct horse battery").is_empty());
+    assert_eq!(codes(""), ["required"]);
+    assert_eq!(codes("short"), ["too_short"]);
+    assert_eq!(codes(&"x".repeat(129)), ["too_long"]);
+    assert_eq!(codes("tab\there is bad"), ["invalid_chars"]);
+    assert_eq!(codes("my name is JohnDoe!"), ["contains_identity"]);
+    assert_eq!(codes("john.smith rules"), ["contains_identity"]);
+}
+
+#[test]
+fn reports_password_with_user_fields() {
+    let errors = validate_new_user("ab", "ab@example.com", Some("short"), &POLICY).expect_err("Invalid user accepted");
+    let failures: Vec<_> = errors.errors().iter().map(|e| (e.field, e.code)).collect();
+    assert_eq!(failures, [("username", "too_short"), ("password", "too_short")]);
+
+    validate_new_user("johndoe", "john@example.com", None, &POLICY).expect("Password should be optional");
+}
+
+#[test]
+fn normalizes_logins_by_kind() {
+    assert_eq!(normalize_login(" JohnDoe "), "JohnDoe");
+    assert_eq!(normalize_login(" JohnDoe@Example.COM "), "johndoe@example.com");
+}
+
+#[test]
+fn validates_webhooks() {
+    let types = |kinds: &[&str]| kinds.iter().map(|kind| kind.to_string()).collect::<Vec<_>>();
+
+    let fields = validate_webhook(" https://example.com/hook ", "0123456789abcdef", &types(&["renamed", "deleted", "renamed"]))
+        .expect("Valid webhook rejected");
+    assert_eq!(fields.url, "https://example.com/hook");
+    assert_eq!(fields.event_types, ["deleted", "renamed"]);
+
+    let errors = validate_webhook("ftp://example.com", "short", &types(&["exploded"])).expect_err("Invalid webhook accepted");
+    let failures: Vec<_> = errors.errors().iter().map(|e| (e.field, e.code)).collect();
+    assert_eq!(failures, [("url", "invalid_format"), ("secret", "too_short"), ("event_types", "invalid_value")]);
+
+    let errors = validate_webhook("/relative", "0123456789abcdef", &[]).expect_err("Invalid webhook accepted");
+    let failures: Vec<_> = errors.errors().iter().map(|e| (e.field, e.code)).collect();
+    assert_eq!(failures, [("url", "invalid_format"), ("event_types", "required")]);
+}
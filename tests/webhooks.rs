@@ -0,0 +1,245 @@
+mod common;
+
+use actix_web::web::{self, Bytes, Data};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+use rust_webapp::app::{AppError, Application};
+use rust_webapp::config::WebhookSettings;
+use rust_webapp::events::{now_millis, RecordedEvent, UserEvent};
+use rust_webapp::policy::Caller;
+use rust_webapp::users::UserRepo;
+use rust_webapp::webhooks::{
+    retry_delay, sign, verify, Attempt, Delivery, DeliveryStatus, Webhook, WebhookSender, EVENT_HEADER, ID_HEADER, SIGNATURE_HEADER,
+};
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+const SECRET: &str = "a webhook secret for tests";
+
+/// One request the receiver got.
+#[derive(Debug, Clone)]
+struct Received {
+    id: String,
+    event: String,
+    signature: String,
+    body: Bytes,
+}
+
+/// What the receiver has seen, and the statuses it answers with next (then 204).
+#[derive(Default)]
+struct Receiver {
+    received: Mutex<Vec<Received>>,
+    statuses: Mutex<VecDeque<u16>>,
+}
+
+impl Receiver {
+    fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+
+    fn answer(&self, statuses: &[u16]) {
+        self.statuses.lock().unwrap().extend(statuses);
+    }
+}
+
+async fn receive(req: HttpRequest, body: Bytes, receiver: Data<Receiver>) -> HttpResponse {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default().to_owned();
+    receiver.received.lock().unwrap().push(Received {
+        id: header(ID_HEADER),
+        event: header(EVENT_HEADER),
+        signature: header(SIGNATURE_HEADER),
+        body,
+    });
+
+    let status = receiver.statuses.lock().unwrap().pop_front().unwrap_or(204);
+    HttpResponse::build(status.try_into().unwrap()).finish()
+}
+
+/// Starts a webhook receiver on a free local port, returning its state and URL.
+fn start_receiver() -> (Data<Receiver>, String) {
+    let receiver = Data::new(Receiver::default());
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind receiver");
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+    let state = receiver.clone();
+    let server = HttpServer::new(move || App::new().app_data(state.clone()).route("/hook", web::post().to(receive)))
+        .workers(1)
+        .listen(listener)
+        .expect("Failed to listen")
+        .run();
+    tokio::spawn(server);
+
+    (receiver, url)
+}
+
+/// An address nothing listens on.
+fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    format!("http://{}/hook", listener.local_addr().unwrap())
+}
+
+/// Retries right away, so tests need not wait for backoff.
+fn settings() -> WebhookSettings {
+    WebhookSettings {
+        timeout_ms: 2000,
+        max_attempts: 3,
+        retry_initial_ms: 0,
+        ..WebhookSettings::default()
+    }
+}
+
+fn kinds(kinds: &[&str]) -> Vec<String> {
+    kinds.iter().map(|kind| kind.to_string()).collect()
+}
+
+async fn scenario_webhooks<R: UserRepo>(app: Application<R>) {
+    let (receiver, url) = start_receiver();
+    let settings = settings();
+    let sender = WebhookSender::new(&settings).expect("Failed to build sender");
+    let admin = Caller::system();
+
+    let stranger = Caller { id: Uuid::new_v4(), roles: vec![] };
+    let err = app.create_webhook(&stranger, &url, SECRET, &kinds(&["registered"])).await.expect_err("Stranger added a webhook");
+    assert!(matches!(err, AppError::Denied(_)));
+    let err = app.create_webhook(&admin, "not a url", SECRET, &kinds(&["registered"])).await.expect_err("Invalid webhook accepted");
+    assert!(matches!(err, AppError::Validation(_)));
+
+    let webhook = app
+        .create_webhook(&admin, &url, SECRET, &kinds(&["registered", "deleted"]))
+        .await
+        .expect("Failed to add webhook");
+    assert_eq!(app.list_webhooks(&admin).await.expect("Failed to list webhooks"), std::slice::from_ref(&webhook));
+
+    // Only subscribed events are queued.
+    let user = app.register("hooked", "hooked@example.com", None).await.expect("Failed to register user");
+    app.rename(&Caller::from(&user), user.id, "renamed").await.expect("Failed to rename user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    let deliveries = app.webhook_deliveries(&admin, webhook.id, 50).await.expect("Failed to list deliveries");
+    assert_eq!(deliveries.len(), 1);
+    let delivery = &deliveries[0];
+    assert_eq!((delivery.event_type.as_str(), delivery.status, delivery.attempts), ("registered", DeliveryStatus::Pending, 0));
+
+    // The receiver fails once, then takes it.
+    receiver.answer(&[500]);
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 1);
+    let failed = app.webhook_deliveries(&admin, webhook.id, 50).await.unwrap().remove(0);
+    assert_eq!((failed.status, failed.attempts, failed.last_status_code), (DeliveryStatus::Pending, 1, Some(500)));
+    assert!(failed.last_error.is_some());
+
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 1);
+    let succeeded = app.webhook_deliveries(&admin, webhook.id, 50).await.unwrap().remove(0);
+    assert_eq!((succeeded.status, succeeded.attempts, succeeded.last_status_code), (DeliveryStatus::Succeeded, 2, Some(204)));
+    assert_eq!((succeeded.next_attempt_at, succeeded.last_error.as_deref()), (None, None));
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 0);
+
+    // Both attempts carried the same delivery id and a valid signature over the event.
+    let received = receiver.received();
+    assert_eq!(received.len(), 2);
+    for request in &received {
+        assert_eq!((request.id.as_str(), request.event.as_str()), (delivery.id.to_string().as_str(), "registered"));
+        assert!(verify(SECRET, &request.signature, &request.body, now_millis() / 1000, Duration::from_secs(300)));
+        assert!(!verify("another secret entirely", &request.signature, &request.body, now_millis() / 1000, Duration::from_secs(300)));
+    }
+    let event: RecordedEvent = serde_json::from_slice(&received[0].body).expect("Body is not an event");
+    assert_eq!(event.id, delivery.event_id);
+    assert_eq!(event.event, UserEvent::Registered { user: user.clone() });
+
+    // A manual redelivery sends it once more, with a fresh set of attempts.
+    let err = app.redeliver(&admin, webhook.id, Uuid::new_v4()).await.expect_err("Redelivered a missing delivery");
+    assert!(matches!(err, AppError::DeliveryNotFound));
+    let queued = app.redeliver(&admin, webhook.id, delivery.id).await.expect("Failed to redeliver");
+    assert_eq!((queued.status, queued.attempts), (DeliveryStatus::Pending, 0));
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 1);
+    assert_eq!(receiver.received().len(), 3);
+
+    // Deleting the webhook takes its log along.
+    app.delete_webhook(&admin, webhook.id).await.expect("Failed to delete webhook");
+    let err = app.webhook_deliveries(&admin, webhook.id, 50).await.expect_err("Listed deliveries of a deleted webhook");
+    assert!(matches!(err, AppError::WebhookNotFound));
+    app.delete_user(&admin, user.id).await.expect("Failed to delete user");
+    app.relay_events(100).await.expect("Failed to relay events");
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 0);
+}
+
+async fn scenario_unreachable_receiver<R: UserRepo>(app: Application<R>) {
+    let settings = settings();
+    let sender = WebhookSender::new(&settings).expect("Failed to build sender");
+    let admin = Caller::system();
+
+    let webhook = app
+        .create_webhook(&admin, &unreachable_url(), SECRET, &kinds(&["registered"]))
+        .await
+        .expect("Failed to add webhook");
+    app.register("nobody", "nobody@example.com", None).await.expect("Failed to register user");
+    app.relay_events(100).await.expect("Failed to relay events");
+
+    // Gives up after max_attempts.
+    for _ in 0..settings.max_attempts {
+        assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 1);
+    }
+    assert_eq!(app.dispatch_webhooks(&sender, &settings).await.expect("Failed to dispatch"), 0);
+
+    let delivery = app.webhook_deliveries(&admin, webhook.id, 50).await.unwrap().remove(0);
+    assert_eq!((delivery.status, delivery.attempts, delivery.last_status_code), (DeliveryStatus::Failed, 3, None));
+    assert_eq!(delivery.next_attempt_at, None);
+    assert!(delivery.last_error.is_some());
+}
+
+backend_tests!(scenario_webhooks);
+backend_tests!(scenario_unreachable_receiver);
+
+#[test]
+fn signatures_cover_timestamp_and_body() {
+    let header = sign(SECRET, 1_700_000_000, b"{}");
+    assert!(header.starts_with("t=1700000000,v1="));
+
+    let tolerance = Duration::from_secs(300);
+    assert!(verify(SECRET, &header, b"{}", 1_700_000_100, tolerance));
+    assert!(!verify(SECRET, &header, b"{ }", 1_700_000_100, tolerance));
+    assert!(!verify(SECRET, &header, b"{}", 1_700_001_000, tolerance));
+    assert!(!verify(SECRET, &header.replace("t=1700000000", "t=1700000001"), b"{}", 1_700_000_100, tolerance));
+    assert!(!verify(SECRET, "v1=00", b"{}", 1_700_000_100, tolerance));
+}
+
+#[test]
+fn retries_back_off_exponentially() {
+    let settings = WebhookSettings {
+        retry_initial_ms: 1000,
+        retry_max_ms: 5000,
+        ..WebhookSettings::default()
+    };
+    let delays: Vec<_> = (1..=5).map(|attempts| retry_delay(attempts, &settings).as_millis()).collect();
+    assert_eq!(delays, [1000, 2000, 4000, 5000, 5000]);
+    assert_eq!(retry_delay(u32::MAX, &settings).as_millis(), 5000);
+}
+
+#[test]
+fn attempts_settle_the_delivery() {
+    let settings = WebhookSettings {
+        max_attempts: 2,
+        retry_initial_ms: 1000,
+        ..WebhookSettings::default()
+    };
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: "https://example.com/hook".to_owned(),
+        secret: SECRET.to_owned(),
+        event_types: kinds(&["password_changed"]),
+        created_at: 0,
+    };
+    let event = RecordedEvent::new(UserEvent::PasswordChanged { id: Uuid::new_v4() });
+    assert!(webhook.wants(&event));
+
+    let failure = Attempt { status_code: Some(503), error: Some("unavailable".to_owned()) };
+    let mut delivery = Delivery::new(&webhook, &event);
+    delivery.record_attempt(10_000, failure.clone(), &settings);
+    assert_eq!((delivery.status, delivery.next_attempt_at), (DeliveryStatus::Pending, Some(11_000)));
+    delivery.record_attempt(11_000, failure, &settings);
+    assert_eq!((delivery.status, delivery.next_attempt_at), (DeliveryStatus::Failed, None));
+
+    delivery.redeliver();
+    delivery.record_attempt(12_000, Attempt { status_code: Some(200), error: None }, &settings);
+    assert_eq!((delivery.status, delivery.attempts, delivery.last_error), (DeliveryStatus::Succeeded, 1, None));
+}